@@ -0,0 +1,343 @@
+use validator::Validate;
+
+use crate::{
+    authorize_admin, cancel_credit_order, certify_order, ensure_verified, has_open_dispute,
+    idempotent, is_suspended, notify, order_involves, validate_max_bytes, AccountRef, Client,
+    Contract, CreditOrder, Error, OrderStatus, OrderType, Producer, CLIENT_STORAGE,
+    CONTRACT_STORAGE, CREDIT_ORDER_STORAGE, PRODUCER_STORAGE,
+};
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct RotatePasswordPayload {
+    producer_id: u64,
+    password: String,
+    #[validate(length(min = 4), custom = "validate_max_bytes::<128>")]
+    new_password: String,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct RotateClientPasswordPayload {
+    client_id: u64,
+    // the client password, or the contract password to reset it
+    password: String,
+    #[validate(length(min = 4), custom = "validate_max_bytes::<128>")]
+    new_password: String,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct RotateContractPasswordPayload {
+    contract_password: String,
+    #[validate(length(min = 4), custom = "validate_max_bytes::<128>")]
+    new_password: String,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct DeactivationPayload {
+    account: AccountRef,
+    // the account password, or the contract password for any account
+    password: String,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ReactivationPayload {
+    contract_password: String,
+    account: AccountRef,
+    idempotency_key: Option<String>,
+}
+
+// get when an account was deactivated, None while it is active
+fn deactivated_at(account: AccountRef) -> Result<Option<u64>, Error> {
+    match account {
+        AccountRef::Client { id } => match CLIENT_STORAGE.with(|s| s.borrow().get(&id)) {
+            Some(client) => Ok(client.deactivated_at),
+            None => Err(Error::NotFound {
+                msg: format!("client with id: {} not found", id),
+            }),
+        },
+        AccountRef::Producer { id } => match PRODUCER_STORAGE.with(|s| s.borrow().get(&id)) {
+            Some(producer) => Ok(producer.deactivated_at),
+            None => Err(Error::NotFound {
+                msg: format!("producer with id: {} not found", id),
+            }),
+        },
+    }
+}
+
+// refuse accounts that are deactivated or not verified for trading
+pub(crate) fn ensure_can_trade(account: AccountRef) -> Result<(), Error> {
+    if deactivated_at(account)?.is_some() {
+        return Err(Error::Unauthorized {
+            msg: "Account is deactivated".to_string(),
+        });
+    }
+    ensure_verified(account)
+}
+
+fn set_deactivated_at(account: AccountRef, deactivated_at: Option<u64>) {
+    match account {
+        AccountRef::Client { id } => CLIENT_STORAGE.with(|s| {
+            let client = s.borrow().get(&id).unwrap();
+            s.borrow_mut().insert(
+                id,
+                Client {
+                    deactivated_at,
+                    ..client
+                },
+            );
+        }),
+        AccountRef::Producer { id } => PRODUCER_STORAGE.with(|s| {
+            let producer = s.borrow().get(&id).unwrap();
+            s.borrow_mut().insert(
+                id,
+                Producer {
+                    deactivated_at,
+                    ..producer
+                },
+            );
+        }),
+    }
+}
+
+// rotate the password of a producer
+#[ic_cdk::update]
+fn rotate_producer_password(payload: RotatePasswordPayload) -> Result<String, Error> {
+    idempotent(
+        "rotate_producer_password",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            match PRODUCER_STORAGE.with(|s| s.borrow().get(&payload.producer_id)) {
+                Some(producer) => {
+                    if producer.password != payload.password {
+                        return Err(Error::Unauthorized {
+                            msg: "Unauthorized, method only available to producers".to_string(),
+                        });
+                    }
+                    PRODUCER_STORAGE.with(|s| {
+                        s.borrow_mut().insert(
+                            payload.producer_id,
+                            Producer {
+                                password: payload.new_password,
+                                ..producer
+                            },
+                        )
+                    });
+                    Ok(format!(
+                        "Producer id: {} password rotated successfully",
+                        payload.producer_id
+                    ))
+                }
+                None => Err(Error::NotFound {
+                    msg: "Producer not found".to_string(),
+                }),
+            }
+        },
+    )
+}
+
+// rotate the password of a client, admins can also reset it for clients without one
+#[ic_cdk::update]
+fn rotate_client_password(payload: RotateClientPasswordPayload) -> Result<String, Error> {
+    idempotent(
+        "rotate_client_password",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            match CLIENT_STORAGE.with(|s| s.borrow().get(&payload.client_id)) {
+                Some(client) => {
+                    let is_owner = client.password.as_ref() == Some(&payload.password);
+                    if !is_owner && authorize_admin(&payload.password).is_err() {
+                        return Err(Error::Unauthorized {
+                            msg: "Unauthorized, method only available to the client and \
+                                  contract Admins"
+                                .to_string(),
+                        });
+                    }
+                    CLIENT_STORAGE.with(|s| {
+                        s.borrow_mut().insert(
+                            payload.client_id,
+                            Client {
+                                password: Some(payload.new_password),
+                                ..client
+                            },
+                        )
+                    });
+                    Ok(format!(
+                        "Client id: {} password rotated successfully",
+                        payload.client_id
+                    ))
+                }
+                None => Err(Error::NotFound {
+                    msg: "Client not found".to_string(),
+                }),
+            }
+        },
+    )
+}
+
+// rotate the contract password used by admins
+#[ic_cdk::update]
+fn rotate_contract_password(payload: RotateContractPasswordPayload) -> Result<String, Error> {
+    idempotent(
+        "rotate_contract_password",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            let contract = authorize_admin(&payload.contract_password)?;
+            CONTRACT_STORAGE.with(|s| {
+                s.borrow_mut().insert(
+                    0,
+                    Contract {
+                        password: payload.new_password,
+                        ..contract
+                    },
+                )
+            });
+            Ok("Contract password rotated successfully".to_string())
+        },
+    )
+}
+
+// clear the bid of a deactivated client from an English auction, reopening it for other bidders
+// unless the producer is suspended, bids are paid off-chain so no credits are held for them
+fn clear_client_bid(credit_order: CreditOrder) {
+    let order_id = credit_order.id;
+    let status = match is_suspended(AccountRef::Producer {
+        id: credit_order.producer_id,
+    }) {
+        true => OrderStatus::Frozen,
+        false => OrderStatus::Open,
+    };
+    if let Some(client_id) = credit_order.client_id {
+        notify(
+            AccountRef::Client { id: client_id },
+            "outbid",
+            order_id,
+            format!(
+                "Bid on credit order {} withdrawn, the account was deactivated",
+                order_id
+            ),
+        );
+    }
+    CREDIT_ORDER_STORAGE.with(|s| {
+        s.borrow_mut().insert(
+            order_id,
+            CreditOrder {
+                client_id: None,
+                high_bid: None,
+                status,
+                ..credit_order
+            },
+        )
+    });
+    certify_order(order_id);
+}
+
+// deactivate an account, cancelling its open orders and refunding their escrow
+#[ic_cdk::update]
+fn deactivate_account(payload: DeactivationPayload) -> Result<String, Error> {
+    idempotent(
+        "deactivate_account",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            let is_admin = CONTRACT_STORAGE
+                .with(|s| s.borrow().get(&0))
+                .is_some_and(|contract| contract.password == payload.password);
+            let is_owner = match payload.account {
+                AccountRef::Producer { id } => PRODUCER_STORAGE
+                    .with(|s| s.borrow().get(&id))
+                    .is_some_and(|producer| producer.password == payload.password),
+                AccountRef::Client { id } => CLIENT_STORAGE
+                    .with(|s| s.borrow().get(&id))
+                    .is_some_and(|client| client.password.as_ref() == Some(&payload.password)),
+            };
+            if !is_admin && !is_owner {
+                return Err(Error::Unauthorized {
+                    msg:
+                        "Unauthorized, method only available to account owners and contract Admins"
+                            .to_string(),
+                });
+            }
+            if deactivated_at(payload.account)?.is_some() {
+                return Err(Error::InvalidPayload {
+                    msg: "Account is already deactivated".to_string(),
+                });
+            }
+
+            // disputed orders keep their escrow until the dispute is ruled
+            let orders: Vec<CreditOrder> = CREDIT_ORDER_STORAGE.with(|s| {
+                s.borrow()
+                    .iter()
+                    .map(|(_, credit_order)| credit_order)
+                    .filter(|credit_order| {
+                        order_involves(credit_order, payload.account)
+                            && !credit_order.paid
+                            && (credit_order.status == OrderStatus::Open
+                                || (credit_order.status == OrderStatus::Frozen
+                                    && !has_open_dispute(credit_order)))
+                    })
+                    .collect()
+            });
+            // a client that only holds the high bid of an auction leaves it open for others
+            let (bids, orders): (Vec<CreditOrder>, Vec<CreditOrder>) =
+                orders.into_iter().partition(|credit_order| {
+                    matches!(payload.account, AccountRef::Client { .. })
+                        && credit_order.order_type == OrderType::EnglishAuction
+                });
+            let (withdrawn, cancelled) = (bids.len(), orders.len());
+            for credit_order in bids {
+                clear_client_bid(credit_order);
+            }
+            for credit_order in orders {
+                let order_id = credit_order.id;
+                // trapping discards the orders already cancelled, the account stays active
+                if let Err(e) = cancel_credit_order(credit_order) {
+                    ic_cdk::trap(&format!(
+                        "Could not cancel credit order id: {}: {:?}",
+                        order_id, e
+                    ));
+                }
+            }
+
+            set_deactivated_at(payload.account, Some(ic_cdk::api::time()));
+            Ok(format!(
+                "Account deactivated successfully, {} open orders cancelled and {} bids withdrawn",
+                cancelled, withdrawn
+            ))
+        },
+    )
+}
+
+// reactivate a deactivated account
+#[ic_cdk::update]
+fn reactivate_account(payload: ReactivationPayload) -> Result<String, Error> {
+    idempotent(
+        "reactivate_account",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_admin(&payload.contract_password)?;
+            if deactivated_at(payload.account)?.is_none() {
+                return Err(Error::InvalidPayload {
+                    msg: "Account is already active".to_string(),
+                });
+            }
+            set_deactivated_at(payload.account, None);
+            Ok("Account reactivated successfully".to_string())
+        },
+    )
+}
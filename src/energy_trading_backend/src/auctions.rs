@@ -0,0 +1,249 @@
+use std::time::Duration;
+
+use crate::{
+    certify_order, check_bid_risk, ensure_can_trade, idempotent, record_activity,
+    settle_credit_order, AccountRef, CreditOrder, Error, OrderStatus, SealedBidPricing,
+    CLIENT_STORAGE, CREDIT_ORDER_STORAGE,
+};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// longest interval between Dutch auction price drops
+const MAX_DUTCH_INTERVAL_SECONDS: u64 = 30 * 24 * 60 * 60;
+// longest commit or reveal phase of a sealed-bid auction
+const MAX_SEALED_PHASE_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum OrderType {
+    // clients outbid each other until the producer settles with the high bidder
+    #[default]
+    EnglishAuction,
+    // sold to the first client at the fixed price
+    BuyItNow,
+    // the price drops by price_decrement every interval_seconds, down to the floor price,
+    // and the order is sold to the first client accepting it
+    DutchAuction {
+        start_price: u64,
+        price_decrement: u64,
+        interval_seconds: u64,
+    },
+    // clients commit to hidden offers, reveal them once bidding closes and the highest
+    // revealed offer wins at the first or second price
+    SealedBid {
+        commit_seconds: u64,
+        reveal_seconds: u64,
+        pricing: SealedBidPricing,
+    },
+}
+
+impl OrderType {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            OrderType::EnglishAuction => "english_auction",
+            OrderType::BuyItNow => "buy_it_now",
+            OrderType::DutchAuction { .. } => "dutch_auction",
+            OrderType::SealedBid { .. } => "sealed_bid",
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct BuyNowPayload {
+    client_id: u64,
+    credit_order_id: u64,
+    idempotency_key: Option<String>,
+}
+
+pub(crate) fn validate_order_type(order_type: &OrderType, floor_price: u64) -> Result<(), Error> {
+    match *order_type {
+        OrderType::DutchAuction {
+            start_price,
+            price_decrement,
+            interval_seconds,
+        } if start_price <= floor_price || price_decrement == 0 || interval_seconds == 0 => {
+            Err(Error::InvalidPayload {
+                msg: "Dutch auctions need a start price above the floor price, \
+                      a price decrement and an interval"
+                    .to_string(),
+            })
+        }
+        OrderType::DutchAuction {
+            interval_seconds, ..
+        } if interval_seconds > MAX_DUTCH_INTERVAL_SECONDS => Err(Error::InvalidPayload {
+            msg: format!(
+                "Dutch auction interval cannot exceed {} seconds",
+                MAX_DUTCH_INTERVAL_SECONDS
+            ),
+        }),
+        OrderType::SealedBid {
+            commit_seconds,
+            reveal_seconds,
+            ..
+        } if commit_seconds == 0 || reveal_seconds == 0 => Err(Error::InvalidPayload {
+            msg: "Sealed-bid auctions need a commit and a reveal phase".to_string(),
+        }),
+        OrderType::SealedBid {
+            commit_seconds,
+            reveal_seconds,
+            ..
+        } if commit_seconds > MAX_SEALED_PHASE_SECONDS
+            || reveal_seconds > MAX_SEALED_PHASE_SECONDS =>
+        {
+            Err(Error::InvalidPayload {
+                msg: format!(
+                    "Sealed-bid auction phases cannot exceed {} seconds",
+                    MAX_SEALED_PHASE_SECONDS
+                ),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn initial_ask_price(order_type: &OrderType, min_offer_per_credit: u64) -> Option<u64> {
+    match *order_type {
+        OrderType::EnglishAuction | OrderType::SealedBid { .. } => None,
+        OrderType::BuyItNow => Some(min_offer_per_credit),
+        OrderType::DutchAuction { start_price, .. } => Some(start_price),
+    }
+}
+
+// the scheduled price of a Dutch auction at a time, never below the floor price
+fn dutch_price(credit_order: &CreditOrder, now: u64) -> Option<u64> {
+    let OrderType::DutchAuction {
+        start_price,
+        price_decrement,
+        interval_seconds,
+    } = credit_order.order_type
+    else {
+        return None;
+    };
+    let intervals = now.saturating_sub(credit_order.created_at)
+        / interval_seconds.saturating_mul(NANOS_PER_SECOND);
+    let price = start_price.saturating_sub(intervals.saturating_mul(price_decrement));
+    Some(price.max(credit_order.min_offer_per_credit))
+}
+
+// wait until the next price drop of a Dutch auction that is still above its floor
+pub(crate) fn schedule_price_decay(credit_order: &CreditOrder) {
+    let OrderType::DutchAuction {
+        interval_seconds, ..
+    } = credit_order.order_type
+    else {
+        return;
+    };
+    if credit_order
+        .ask_price
+        .is_none_or(|price| price <= credit_order.min_offer_per_credit)
+    {
+        return;
+    }
+    let interval = interval_seconds.saturating_mul(NANOS_PER_SECOND);
+    let elapsed = ic_cdk::api::time().saturating_sub(credit_order.created_at);
+    let delay = Duration::from_nanos(interval - elapsed % interval);
+    let order_id = credit_order.id;
+    ic_cdk_timers::set_timer(delay, move || decay_price(order_id));
+}
+
+// lower the ask price of a Dutch auction to its scheduled price
+fn decay_price(order_id: u64) {
+    let Some(credit_order) = CREDIT_ORDER_STORAGE.with(|s| s.borrow().get(&order_id)) else {
+        return;
+    };
+    // frozen orders keep decaying so the schedule holds once they are released
+    if !matches!(credit_order.status, OrderStatus::Open | OrderStatus::Frozen) {
+        return;
+    }
+    let credit_order = CreditOrder {
+        ask_price: dutch_price(&credit_order, ic_cdk::api::time()),
+        ..credit_order
+    };
+    CREDIT_ORDER_STORAGE.with(|s| s.borrow_mut().insert(order_id, credit_order.clone()));
+    certify_order(order_id);
+    schedule_price_decay(&credit_order);
+}
+
+// re-arm the price drops of Dutch auctions, catching up on drops missed during the upgrade
+pub(crate) fn restore_price_decay_timers() {
+    let orders: Vec<u64> = CREDIT_ORDER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, credit_order)| {
+                matches!(credit_order.order_type, OrderType::DutchAuction { .. })
+                    && matches!(credit_order.status, OrderStatus::Open | OrderStatus::Frozen)
+            })
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for order_id in orders {
+        decay_price(order_id);
+    }
+}
+
+// get the order to buy, checking the client can trade, also used to check batched purchases
+pub(crate) fn check_buy_now(payload: &BuyNowPayload) -> Result<CreditOrder, Error> {
+    let credit_order = match CREDIT_ORDER_STORAGE.with(|s| s.borrow().get(&payload.credit_order_id))
+    {
+        Some(credit_order) => credit_order,
+        None => {
+            return Err(Error::NotFound {
+                msg: "Credit order not found".to_string(),
+            })
+        }
+    };
+    if !CLIENT_STORAGE.with(|s| s.borrow().contains_key(&payload.client_id)) {
+        return Err(Error::NotFound {
+            msg: "Client not found".to_string(),
+        });
+    }
+    ensure_can_trade(AccountRef::Client {
+        id: payload.client_id,
+    })?;
+    Ok(credit_order)
+}
+
+// buy a buy-it-now order or accept the current price of a Dutch auction, settling it at once
+#[ic_cdk::update]
+pub(crate) fn buy_now(payload: BuyNowPayload) -> Result<CreditOrder, Error> {
+    idempotent(
+        "buy_now",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            let credit_order = check_buy_now(&payload)?;
+            if credit_order.paid {
+                return Err(Error::AlreadyPaid {
+                    msg: "Credit order has already been paid".to_string(),
+                });
+            }
+            if credit_order.status != OrderStatus::Open {
+                return Err(Error::InvalidPayload {
+                    msg: "Credit order is not open".to_string(),
+                });
+            }
+            // the timer may lag behind the schedule, Dutch auctions sell at the scheduled price
+            let price = match dutch_price(&credit_order, ic_cdk::api::time()) {
+                Some(price) => price,
+                None => match credit_order.ask_price {
+                    Some(price) => price,
+                    None => {
+                        return Err(Error::InvalidPayload {
+                            msg: "Credit order is an auction, place a bid instead".to_string(),
+                        })
+                    }
+                },
+            };
+            check_bid_risk(payload.client_id, credit_order.producer_id)?;
+
+            let credit_order = settle_credit_order(CreditOrder {
+                client_id: Some(payload.client_id),
+                high_bid: Some(price),
+                ask_price: None,
+                ..credit_order
+            })?;
+            record_activity(AccountRef::Client {
+                id: payload.client_id,
+            });
+            Ok(credit_order)
+        },
+    )
+}
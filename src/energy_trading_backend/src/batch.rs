@@ -0,0 +1,203 @@
+use validator::Validate;
+
+use crate::{
+    add_client, add_credit_order, add_producer, authorize_admin, authorize_producer,
+    award_facility_energy, award_producer_energy, bid, buy_now, check_buy_now,
+    check_facility_award, check_retirement, check_transfer, ensure_can_trade, idempotent,
+    mark_order_paid, producer_facility, retire_credits, transfer_credits, validate_order_type,
+    AccountRef, BidPayload, BuyNowPayload, Client, ClientPayload, CreditOrder, CreditOrderPayload,
+    Error, FacilityAward, FacilityEnergyPayload, PaidPayload, Producer, ProducerEnergyPayload,
+    ProducerPayload, RetirePayload, Retirement, Transfer, TransferPayload, CREDIT_ORDER_STORAGE,
+};
+
+// operations accepted in one batch
+const MAX_BATCH_OPERATIONS: usize = 100;
+
+// an update applied as part of a batch
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum BatchOperation {
+    AddClient(ClientPayload),
+    AddProducer(ProducerPayload),
+    AwardProducerEnergy(ProducerEnergyPayload),
+    AwardFacilityEnergy(FacilityEnergyPayload),
+    AddCreditOrder(CreditOrderPayload),
+    Bid(BidPayload),
+    BuyNow(BuyNowPayload),
+    MarkOrderPaid(PaidPayload),
+    TransferCredits(TransferPayload),
+    RetireCredits(RetirePayload),
+}
+
+// the result of each operation, in the order of the batch
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum BatchResult {
+    Message(String),
+    Client(Client),
+    Producer(Producer),
+    CreditOrder(CreditOrder),
+    FacilityAward(FacilityAward),
+    Transfer(Transfer),
+    Retirement(Retirement),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct BatchPayload {
+    operations: Vec<BatchOperation>,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct BatchOperationResult {
+    // 0-based position of the operation in the batch
+    index: u64,
+    result: Option<BatchResult>,
+    error: Option<Error>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct BatchReport {
+    // false when an operation failed its checks and none of the batch was applied
+    applied: bool,
+    operations: Vec<BatchOperationResult>,
+}
+
+fn validate_payload(payload: &impl Validate) -> Result<(), Error> {
+    payload
+        .validate()
+        .map_err(|e| Error::InvalidPayload { msg: e.to_string() })
+}
+
+// check an operation against the current state before any of the batch is applied, balances and
+// order states are only checked when the operation is applied after the ones before it
+fn check(operation: &BatchOperation) -> Result<(), Error> {
+    match operation {
+        BatchOperation::AddClient(payload) => validate_payload(payload),
+        BatchOperation::AddProducer(payload) => validate_payload(payload),
+        BatchOperation::AwardProducerEnergy(payload) => {
+            authorize_admin(&payload.contract_password)?;
+            producer_facility(payload.producer_id, payload.facility_id).map(|_| ())
+        }
+        BatchOperation::AwardFacilityEnergy(payload) => check_facility_award(payload).map(|_| ()),
+        BatchOperation::AddCreditOrder(payload) => {
+            ensure_can_trade(AccountRef::Producer {
+                id: payload.producer_id,
+            })?;
+            validate_order_type(
+                &payload.order_type.unwrap_or_default(),
+                payload.min_offer_per_credit,
+            )
+        }
+        BatchOperation::Bid(payload) => {
+            if !CREDIT_ORDER_STORAGE.with(|s| s.borrow().contains_key(&payload.credit_order_id)) {
+                return Err(Error::NotFound {
+                    msg: "Credit order not found".to_string(),
+                });
+            }
+            ensure_can_trade(AccountRef::Client {
+                id: payload.client_id,
+            })
+        }
+        BatchOperation::BuyNow(payload) => check_buy_now(payload).map(|_| ()),
+        BatchOperation::MarkOrderPaid(payload) => {
+            match CREDIT_ORDER_STORAGE.with(|s| s.borrow().get(&payload.order_id)) {
+                Some(credit_order) => {
+                    authorize_producer(credit_order.producer_id, &payload.password).map(|_| ())
+                }
+                None => Err(Error::NotFound {
+                    msg: "Credit order not found".to_string(),
+                }),
+            }
+        }
+        BatchOperation::TransferCredits(payload) => check_transfer(payload),
+        BatchOperation::RetireCredits(payload) => check_retirement(payload),
+    }
+}
+
+fn apply(operation: BatchOperation) -> Result<BatchResult, Error> {
+    match operation {
+        BatchOperation::AddClient(payload) => add_client(payload).map(BatchResult::Client),
+        BatchOperation::AddProducer(payload) => add_producer(payload).map(BatchResult::Producer),
+        BatchOperation::AwardProducerEnergy(payload) => {
+            award_producer_energy(payload).map(BatchResult::Message)
+        }
+        BatchOperation::AwardFacilityEnergy(payload) => {
+            award_facility_energy(payload).map(BatchResult::FacilityAward)
+        }
+        BatchOperation::AddCreditOrder(payload) => {
+            add_credit_order(payload).map(BatchResult::CreditOrder)
+        }
+        BatchOperation::Bid(payload) => bid(payload).map(BatchResult::Message),
+        BatchOperation::BuyNow(payload) => buy_now(payload).map(BatchResult::CreditOrder),
+        BatchOperation::MarkOrderPaid(payload) => {
+            mark_order_paid(payload).map(BatchResult::Message)
+        }
+        BatchOperation::TransferCredits(payload) => {
+            transfer_credits(payload).map(BatchResult::Transfer)
+        }
+        BatchOperation::RetireCredits(payload) => {
+            retire_credits(payload).map(BatchResult::Retirement)
+        }
+    }
+}
+
+// apply several updates atomically, each operation sees the effects of the ones before it; every
+// operation is checked first and if any check fails none of the batch is applied and the report
+// holds the error of each failing operation, an operation that still fails when it is applied
+// traps so none of the batch is applied
+#[ic_cdk::update]
+fn batch(payload: BatchPayload) -> Result<BatchReport, Error> {
+    idempotent(
+        "batch",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if payload.operations.is_empty() || payload.operations.len() > MAX_BATCH_OPERATIONS {
+                return Err(Error::InvalidPayload {
+                    msg: format!(
+                        "Batch must have between 1 and {} operations",
+                        MAX_BATCH_OPERATIONS
+                    ),
+                });
+            }
+            let errors: Vec<Option<Error>> = payload
+                .operations
+                .iter()
+                .map(|operation| check(operation).err())
+                .collect();
+            if errors.iter().any(Option::is_some) {
+                return Ok(BatchReport {
+                    applied: false,
+                    operations: errors
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, error)| BatchOperationResult {
+                            index: index as u64,
+                            result: None,
+                            error,
+                        })
+                        .collect(),
+                });
+            }
+            let total = payload.operations.len();
+            let mut operations = Vec::with_capacity(total);
+            for (index, operation) in payload.operations.into_iter().enumerate() {
+                match apply(operation) {
+                    Ok(result) => operations.push(BatchOperationResult {
+                        index: index as u64,
+                        result: Some(result),
+                        error: None,
+                    }),
+                    // trapping discards every state change made by the batch
+                    Err(e) => ic_cdk::trap(&format!(
+                        "Batch rolled back, operation {} of {} failed: {:?}",
+                        index, total, e
+                    )),
+                }
+            }
+            Ok(BatchReport {
+                applied: true,
+                operations,
+            })
+        },
+    )
+}
@@ -0,0 +1,370 @@
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::ops::RangeFrom;
+
+use crate::{
+    authorize_admin, award_producer_facility, create_client, create_producer, idempotent,
+    ClientPayload, Error, OrderStatus, ProducerPayload, CLIENT_STORAGE, CREDIT_ORDER_STORAGE,
+    PRODUCER_STORAGE, TRADE_STORAGE,
+};
+
+// rows accepted in one import batch
+const MAX_IMPORT_ROWS: usize = 500;
+// rows and encoded bytes returned in one export chunk, well below the reply size limit
+const MAX_EXPORT_ROWS: u64 = 1_000;
+const MAX_EXPORT_BYTES: usize = 1_500_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum DataFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+// a batch of rows, CSV with a header line or a JSON array of objects
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ImportPayload {
+    contract_password: String,
+    format: DataFormat,
+    data: String,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ImportRowResult {
+    // 1-based position of the row in the batch, not counting the CSV header
+    row: u64,
+    // id of the created or awarded record
+    id: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ImportReport {
+    imported: u64,
+    failed: u64,
+    rows: Vec<ImportRowResult>,
+}
+
+// a historical energy supply to award to a producer
+#[derive(Clone, Serialize, Deserialize, Default)]
+struct EnergyAwardRow {
+    producer_id: u64,
+    facility_id: u64,
+    energy_supply: u64,
+    period_start: u64,
+    period_end: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ExportPayload {
+    contract_password: String,
+    format: DataFormat,
+    // id to resume from, 0 for the first chunk
+    cursor: u64,
+    limit: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ExportChunk {
+    data: String,
+    rows: u64,
+    // cursor of the next chunk, None once the export is complete
+    next_cursor: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct OrderRow {
+    id: u64,
+    producer_id: u64,
+    client_id: Option<u64>,
+    credits: u64,
+    order_type: &'static str,
+    min_offer_per_credit: u64,
+    high_bid: Option<u64>,
+    ask_price: Option<u64>,
+    escrow: u64,
+    status: OrderStatus,
+    paid: bool,
+}
+
+#[derive(Serialize)]
+struct BalanceRow {
+    account: &'static str,
+    id: u64,
+    name: String,
+    credits: u64,
+    // credits of the producer held in escrow by unsettled orders
+    escrow: u64,
+}
+
+// decode the rows of a batch, keeping the decoding error of each malformed row
+fn decode_rows<T: DeserializeOwned>(
+    format: DataFormat,
+    data: &str,
+) -> Result<Vec<Result<T, String>>, Error> {
+    let rows: Vec<Result<T, String>> = match format {
+        DataFormat::Csv => csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize()
+            .map(|row| row.map_err(|e| e.to_string()))
+            .collect(),
+        DataFormat::Json => serde_json::from_str::<Vec<serde_json::Value>>(data)
+            .map_err(|e| Error::InvalidPayload {
+                msg: format!("Batch is not a JSON array: {}", e),
+            })?
+            .into_iter()
+            .map(|row| serde_json::from_value(row).map_err(|e| e.to_string()))
+            .collect(),
+    };
+    if rows.len() > MAX_IMPORT_ROWS {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Batch has {} rows, split it into chunks of at most {}",
+                rows.len(),
+                MAX_IMPORT_ROWS
+            ),
+        });
+    }
+    Ok(rows)
+}
+
+fn import_rows<T: DeserializeOwned>(
+    payload: ImportPayload,
+    mut import: impl FnMut(T) -> Result<u64, Error>,
+) -> Result<ImportReport, Error> {
+    authorize_admin(&payload.contract_password)?;
+    let mut report = ImportReport::default();
+    for (index, row) in decode_rows(payload.format, &payload.data)?
+        .into_iter()
+        .enumerate()
+    {
+        let result = row.and_then(|row| {
+            import(row).map_err(|e| match e {
+                Error::NotFound { msg }
+                | Error::AlreadyPaid { msg }
+                | Error::InvalidPayload { msg }
+                | Error::Unauthorized { msg } => msg,
+            })
+        });
+        let (id, error) = match result {
+            Ok(id) => {
+                report.imported += 1;
+                (Some(id), None)
+            }
+            Err(msg) => {
+                report.failed += 1;
+                (None, Some(msg))
+            }
+        };
+        report.rows.push(ImportRowResult {
+            row: index as u64 + 1,
+            id,
+            error,
+        });
+    }
+    Ok(report)
+}
+
+// import a batch of producers, rows are validated like add_producer
+#[ic_cdk::update]
+fn import_producers(payload: ImportPayload) -> Result<ImportReport, Error> {
+    idempotent(
+        "import_producers",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            import_rows(payload, |row: ProducerPayload| {
+                create_producer(row).map(|producer| producer.id)
+            })
+        },
+    )
+}
+
+// import a batch of clients, rows are validated like add_client
+#[ic_cdk::update]
+fn import_clients(payload: ImportPayload) -> Result<ImportReport, Error> {
+    idempotent(
+        "import_clients",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            import_rows(payload, |row: ClientPayload| {
+                create_client(row).map(|client| client.id)
+            })
+        },
+    )
+}
+
+// import a batch of historical energy awards and mint their credits
+#[ic_cdk::update]
+fn import_energy_awards(payload: ImportPayload) -> Result<ImportReport, Error> {
+    idempotent(
+        "import_energy_awards",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            let contract = authorize_admin(&payload.contract_password)?;
+            import_rows(payload, |row: EnergyAwardRow| {
+                award_producer_facility(
+                    &contract,
+                    row.producer_id,
+                    row.facility_id,
+                    row.energy_supply,
+                    row.period_start,
+                    row.period_end,
+                )
+                .map(|award| award.id)
+            })
+        },
+    )
+}
+
+fn encode_row<T: serde::Serialize>(
+    format: DataFormat,
+    row: &T,
+    header: bool,
+) -> Result<String, Error> {
+    let encoded = match format {
+        DataFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(header)
+                .from_writer(vec![]);
+            writer
+                .serialize(row)
+                .map_err(|e| e.to_string())
+                .and_then(|_| writer.into_inner().map_err(|e| e.to_string()))
+                .and_then(|bytes| String::from_utf8(bytes).map_err(|e| e.to_string()))
+        }
+        DataFormat::Json => serde_json::to_string(row).map_err(|e| e.to_string()),
+    };
+    encoded.map_err(|msg| Error::InvalidPayload {
+        msg: format!("Could not encode export row: {}", msg),
+    })
+}
+
+// encode the rows from the cursor on until the row or byte limit of a chunk is reached
+fn export_rows<T: serde::Serialize>(
+    payload: &ExportPayload,
+    rows: impl FnOnce(RangeFrom<u64>) -> Vec<(u64, T)>,
+) -> Result<ExportChunk, Error> {
+    authorize_admin(&payload.contract_password)?;
+    if payload.limit == 0 || payload.limit > MAX_EXPORT_ROWS {
+        return Err(Error::InvalidPayload {
+            msg: format!("Limit must be between 1 and {}", MAX_EXPORT_ROWS),
+        });
+    }
+    let mut encoded: Vec<String> = Vec::new();
+    let mut size = 0;
+    let mut next_cursor = None;
+    for (id, row) in rows(payload.cursor..) {
+        if encoded.len() as u64 == payload.limit || size >= MAX_EXPORT_BYTES {
+            next_cursor = Some(id);
+            break;
+        }
+        let row = encode_row(payload.format, &row, encoded.is_empty())?;
+        size += row.len();
+        encoded.push(row);
+    }
+    let data = match payload.format {
+        DataFormat::Csv => encoded.concat(),
+        DataFormat::Json => format!("[{}]", encoded.join(",")),
+    };
+    Ok(ExportChunk {
+        data,
+        rows: encoded.len() as u64,
+        next_cursor,
+    })
+}
+
+// export a chunk of credit orders
+#[ic_cdk::query]
+fn export_orders(payload: ExportPayload) -> Result<ExportChunk, Error> {
+    let limit = payload.limit as usize;
+    export_rows(&payload, |range| {
+        CREDIT_ORDER_STORAGE.with(|s| {
+            s.borrow()
+                .range(range)
+                .take(limit + 1)
+                .map(|(id, credit_order)| {
+                    (
+                        id,
+                        OrderRow {
+                            id,
+                            producer_id: credit_order.producer_id,
+                            client_id: credit_order.client_id,
+                            credits: credit_order.credits,
+                            order_type: credit_order.order_type.name(),
+                            min_offer_per_credit: credit_order.min_offer_per_credit,
+                            high_bid: credit_order.high_bid,
+                            ask_price: credit_order.ask_price,
+                            escrow: credit_order.escrow,
+                            status: credit_order.status,
+                            paid: credit_order.paid,
+                        },
+                    )
+                })
+                .collect()
+        })
+    })
+}
+
+// export a chunk of trades
+#[ic_cdk::query]
+fn export_trades(payload: ExportPayload) -> Result<ExportChunk, Error> {
+    let limit = payload.limit as usize;
+    export_rows(&payload, |range| {
+        TRADE_STORAGE.with(|s| s.borrow().range(range).take(limit + 1).collect())
+    })
+}
+
+// export a chunk of client and producer balances, ordered by account id
+#[ic_cdk::query]
+fn export_balances(payload: ExportPayload) -> Result<ExportChunk, Error> {
+    let limit = payload.limit as usize;
+    export_rows(&payload, |range| {
+        let mut rows: Vec<(u64, BalanceRow)> = CLIENT_STORAGE.with(|s| {
+            s.borrow()
+                .range(range.clone())
+                .take(limit + 1)
+                .map(|(id, client)| {
+                    (
+                        id,
+                        BalanceRow {
+                            account: "client",
+                            id,
+                            name: client.name,
+                            credits: client.credits,
+                            escrow: 0,
+                        },
+                    )
+                })
+                .collect()
+        });
+        let mut escrow: BTreeMap<u64, u64> = BTreeMap::new();
+        CREDIT_ORDER_STORAGE.with(|s| {
+            for (_, credit_order) in s.borrow().iter() {
+                *escrow.entry(credit_order.producer_id).or_default() += credit_order.escrow;
+            }
+        });
+        PRODUCER_STORAGE.with(|s| {
+            for (id, producer) in s.borrow().range(range).take(limit + 1) {
+                rows.push((
+                    id,
+                    BalanceRow {
+                        account: "producer",
+                        id,
+                        name: producer.name,
+                        credits: producer.credits,
+                        escrow: escrow.get(&id).copied().unwrap_or(0),
+                    },
+                ));
+            }
+        });
+        // ids come from the shared counter so clients and producers never collide
+        rows.sort_by_key(|(id, _)| *id);
+        rows.truncate(limit + 1);
+        rows
+    })
+}
@@ -0,0 +1,196 @@
+use candid::Encode;
+use ic_certification::{AsHashTree, Hash, HashTree, RbTree};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+use crate::{
+    AccountRef, CreditOrder, Error, CLIENT_STORAGE, CREDIT_ORDER_STORAGE, PRODUCER_STORAGE,
+};
+
+// labels of the subtrees under the certified root, entries are keyed by big endian id
+const CLIENTS_LABEL: &str = "clients";
+const PRODUCERS_LABEL: &str = "producers";
+const ORDERS_LABEL: &str = "orders";
+
+// the certified part of an account, its credit balance
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct CertifiedBalance {
+    account: AccountRef,
+    credits: u64,
+    energy_supply: u64,
+}
+
+// a query response a client can check against the subnet signature
+// the certificate carries the canister certified data, the root hash of the witness, and the
+// witness proves that SHA-256 of the encoded record is the leaf at /<label>/<id>
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct CertifiedResponse<T> {
+    record: T,
+    // the record as candid, the bytes the leaf hash is computed over
+    encoded: Vec<u8>,
+    certificate: Vec<u8>,
+    // CBOR encoded hash tree pruned to the record path
+    witness: Vec<u8>,
+}
+
+thread_local! {
+    // label -> id -> record hash, rebuilt from stable memory after an upgrade
+    static CERTIFIED_TREE: RefCell<RbTree<&'static str, RbTree<Vec<u8>, Hash>>> =
+        const { RefCell::new(RbTree::new()) };
+}
+
+fn client_balance(id: u64) -> Option<CertifiedBalance> {
+    CLIENT_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .map(|client| CertifiedBalance {
+            account: AccountRef::Client { id },
+            credits: client.credits,
+            energy_supply: 0,
+        })
+}
+
+fn producer_balance(id: u64) -> Option<CertifiedBalance> {
+    PRODUCER_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .map(|producer| CertifiedBalance {
+            account: AccountRef::Producer { id },
+            credits: producer.credits,
+            energy_supply: producer.energy_supply,
+        })
+}
+
+fn encode<T: candid::CandidType>(record: &T) -> Vec<u8> {
+    Encode!(record).unwrap()
+}
+
+// put the hash of a record in the tree without publishing the new root
+fn put_leaf(label: &'static str, id: u64, encoded: Option<Vec<u8>>) {
+    CERTIFIED_TREE.with(|t| {
+        let mut tree = t.borrow_mut();
+        if tree.get(label.as_bytes()).is_none() {
+            tree.insert(label, RbTree::new());
+        }
+        tree.modify(label.as_bytes(), |records| match encoded {
+            Some(encoded) => {
+                records.insert(id.to_be_bytes().to_vec(), Sha256::digest(encoded).into())
+            }
+            None => records.delete(&id.to_be_bytes()),
+        });
+    });
+}
+
+fn publish_root() {
+    let root = CERTIFIED_TREE.with(|t| t.borrow().root_hash());
+    ic_cdk::api::set_certified_data(&root);
+}
+
+// refresh the certified balance of a client after it changed
+pub(crate) fn certify_client(id: u64) {
+    put_leaf(CLIENTS_LABEL, id, client_balance(id).as_ref().map(encode));
+    publish_root();
+}
+
+// refresh the certified balance of a producer after it changed
+pub(crate) fn certify_producer(id: u64) {
+    put_leaf(
+        PRODUCERS_LABEL,
+        id,
+        producer_balance(id).as_ref().map(encode),
+    );
+    publish_root();
+}
+
+// refresh the certified state of a credit order after it changed
+pub(crate) fn certify_order(id: u64) {
+    let credit_order = CREDIT_ORDER_STORAGE.with(|s| s.borrow().get(&id));
+    put_leaf(ORDERS_LABEL, id, credit_order.as_ref().map(encode));
+    publish_root();
+}
+
+// rebuild the certified tree from stable memory, the heap copy does not survive an upgrade
+pub(crate) fn restore_certified_data() {
+    let client_ids: Vec<u64> =
+        CLIENT_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect());
+    for id in client_ids {
+        put_leaf(CLIENTS_LABEL, id, client_balance(id).as_ref().map(encode));
+    }
+    let producer_ids: Vec<u64> =
+        PRODUCER_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect());
+    for id in producer_ids {
+        put_leaf(
+            PRODUCERS_LABEL,
+            id,
+            producer_balance(id).as_ref().map(encode),
+        );
+    }
+    CREDIT_ORDER_STORAGE.with(|s| {
+        for (id, credit_order) in s.borrow().iter() {
+            put_leaf(ORDERS_LABEL, id, Some(encode(&credit_order)));
+        }
+    });
+    publish_root();
+}
+
+// the tree with everything pruned except the path to one record
+fn witness(label: &'static str, id: u64) -> Result<Vec<u8>, Error> {
+    let tree: HashTree = CERTIFIED_TREE.with(|t| {
+        t.borrow().nested_witness(label.as_bytes(), |records| {
+            records.witness(&id.to_be_bytes())
+        })
+    });
+    let mut serializer = serde_cbor::Serializer::new(Vec::new());
+    serializer
+        .self_describe()
+        .map_err(|e| Error::InvalidPayload { msg: e.to_string() })?;
+    tree.serialize(&mut serializer)
+        .map_err(|e| Error::InvalidPayload { msg: e.to_string() })?;
+    Ok(serializer.into_inner())
+}
+
+fn certified_response<T: candid::CandidType>(
+    label: &'static str,
+    id: u64,
+    record: T,
+) -> Result<CertifiedResponse<T>, Error> {
+    // the certificate is only available to non-replicated queries
+    let certificate = ic_cdk::api::data_certificate().ok_or(Error::InvalidPayload {
+        msg: "Certificate only available in query calls".to_string(),
+    })?;
+    Ok(CertifiedResponse {
+        encoded: encode(&record),
+        record,
+        certificate,
+        witness: witness(label, id)?,
+    })
+}
+
+// get the balance of a client with a certificate and witness
+#[ic_cdk::query]
+fn get_client_certified(id: u64) -> Result<CertifiedResponse<CertifiedBalance>, Error> {
+    let balance = client_balance(id).ok_or(Error::NotFound {
+        msg: format!("client with id: {} not found", id),
+    })?;
+    certified_response(CLIENTS_LABEL, id, balance)
+}
+
+// get the balance of a producer with a certificate and witness
+#[ic_cdk::query]
+fn get_producer_certified(id: u64) -> Result<CertifiedResponse<CertifiedBalance>, Error> {
+    let balance = producer_balance(id).ok_or(Error::NotFound {
+        msg: format!("producer with id: {} not found", id),
+    })?;
+    certified_response(PRODUCERS_LABEL, id, balance)
+}
+
+// get a credit order with a certificate and witness
+#[ic_cdk::query]
+fn get_credit_order_by_id_certified(id: u64) -> Result<CertifiedResponse<CreditOrder>, Error> {
+    let credit_order =
+        CREDIT_ORDER_STORAGE
+            .with(|s| s.borrow().get(&id))
+            .ok_or(Error::NotFound {
+                msg: format!("credit order with id: {} not found", id),
+            })?;
+    certified_response(ORDERS_LABEL, id, credit_order)
+}
@@ -0,0 +1,694 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+use validator::Validate;
+
+use crate::{
+    add_credit_to_client, add_credit_to_producer, authorize_admin, authorize_client,
+    authorize_producer, certify_order, deduct_credit_from_client, idempotent, next_id, notify,
+    release_vintage_credits, reverse_trades, settle_credit_order, settle_escrow,
+    validate_max_bytes, AccountRef, CreditOrder, Error, Memory, OrderStatus, CREDIT_ORDER_STORAGE,
+    MEMORY_MANAGER, TRADE_STORAGE,
+};
+
+// time the counterparty has to answer a dispute before it is escalated to the arbiters
+const DISPUTE_RESPONSE_WINDOW: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+// time after a settlement during which the producer can contest it
+const SETTLEMENT_DISPUTE_WINDOW: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+// disputes a credit order can go through, keeps the order within its storage bound
+const MAX_DISPUTES_PER_ORDER: usize = 8;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum DisputeParty {
+    #[default]
+    Client,
+    Producer,
+}
+
+// credentials of the party acting on a dispute
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum DisputeAuth {
+    Client { client_id: u64, password: String },
+    Producer { password: String },
+}
+
+impl Default for DisputeAuth {
+    fn default() -> Self {
+        DisputeAuth::Client {
+            client_id: 0,
+            password: String::new(),
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum DisputeRuling {
+    // the escrowed credits go to the client
+    ReleaseToBuyer,
+    // the escrowed credits go back to the producer and the order is cancelled
+    RefundToSeller,
+    // the client receives buyer_credits and the producer the rest of the escrow
+    Split { buyer_credits: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum DisputeStatus {
+    #[default]
+    Open,
+    Responded,
+    // the counterparty did not respond in time, the dispute waits for an arbiter ruling
+    Escalated,
+    Resolved,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Dispute {
+    id: u64,
+    order_id: u64,
+    opened_by: DisputeParty,
+    reason: String,
+    response: Option<String>,
+    status: DisputeStatus,
+    ruling: Option<DisputeRuling>,
+    // None when the dispute was ruled by default after the response deadline
+    arbiter_id: Option<u64>,
+    opened_at: u64,
+    respond_by: u64,
+    resolved_at: Option<u64>,
+    // credits taken back from the client when the producer contested a settlement
+    clawback: Option<u64>,
+}
+
+// credits a contested settlement took back from a client, and what the ruling gave back
+pub(crate) struct SettlementClawback {
+    pub(crate) order_id: u64,
+    pub(crate) taken_at: u64,
+    pub(crate) credits: u64,
+    // (resolved at, credits) once the dispute is ruled
+    pub(crate) returned: Option<(u64, u64)>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Arbiter {
+    id: u64,
+    name: String,
+    password: String,
+}
+
+impl Storable for Dispute {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Arbiter {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// reason and response are up to 500 bytes each
+impl BoundedStorable for Dispute {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Arbiter {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ARBITER_STORAGE: RefCell<StableBTreeMap<u64, Arbiter, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    static DISPUTE_STORAGE: RefCell<StableBTreeMap<u64, Dispute, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct ArbiterPayload {
+    contract_password: String,
+    #[validate(length(min = 3), custom = "validate_max_bytes::<128>")]
+    name: String,
+    #[validate(length(min = 4), custom = "validate_max_bytes::<128>")]
+    password: String,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ArbiterReturn {
+    id: u64,
+    name: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct OpenDisputePayload {
+    order_id: u64,
+    auth: DisputeAuth,
+    #[validate(length(min = 5), custom = "validate_max_bytes::<500>")]
+    reason: String,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct RespondDisputePayload {
+    dispute_id: u64,
+    auth: DisputeAuth,
+    #[validate(length(min = 5), custom = "validate_max_bytes::<500>")]
+    response: String,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RuleDisputePayload {
+    dispute_id: u64,
+    arbiter_id: u64,
+    password: String,
+    ruling: DisputeRuling,
+    idempotency_key: Option<String>,
+}
+
+// check the credentials against the credit order and return the party they belong to
+fn authorize_party(credit_order: &CreditOrder, auth: &DisputeAuth) -> Result<DisputeParty, Error> {
+    match auth {
+        DisputeAuth::Client {
+            client_id,
+            password,
+        } => {
+            if credit_order.client_id != Some(*client_id) {
+                return Err(Error::Unauthorized {
+                    msg: "Client is not the buyer of this credit order".to_string(),
+                });
+            }
+            authorize_client(*client_id, password)?;
+            Ok(DisputeParty::Client)
+        }
+        DisputeAuth::Producer { password } => {
+            authorize_producer(credit_order.producer_id, password)?;
+            Ok(DisputeParty::Producer)
+        }
+    }
+}
+
+// record a dispute notification on the buyer and the seller of the order
+fn notify_dispute_parties(credit_order: &CreditOrder, dispute_id: u64, message: String) {
+    if let Some(client_id) = credit_order.client_id {
+        notify(
+            AccountRef::Client { id: client_id },
+            "dispute",
+            dispute_id,
+            message.clone(),
+        );
+    }
+    notify(
+        AccountRef::Producer {
+            id: credit_order.producer_id,
+        },
+        "dispute",
+        dispute_id,
+        message,
+    );
+}
+
+fn get_order(order_id: u64) -> Result<CreditOrder, Error> {
+    CREDIT_ORDER_STORAGE
+        .with(|s| s.borrow().get(&order_id))
+        .ok_or(Error::NotFound {
+            msg: "Credit order not found".to_string(),
+        })
+}
+
+// apply a ruling to the escrow of the disputed order and close the dispute
+fn resolve_dispute(
+    dispute: Dispute,
+    ruling: DisputeRuling,
+    arbiter_id: Option<u64>,
+) -> Result<Dispute, Error> {
+    let credit_order = get_order(dispute.order_id)?;
+    let client_id = credit_order.client_id;
+
+    let credit_order = match &ruling {
+        DisputeRuling::ReleaseToBuyer if !credit_order.paid => settle_credit_order(credit_order)?,
+        DisputeRuling::ReleaseToBuyer => {
+            add_credit_to_client(client_id, credit_order.escrow)?;
+            CreditOrder {
+                escrow: 0,
+                status: OrderStatus::Paid,
+                ..credit_order
+            }
+        }
+        DisputeRuling::RefundToSeller => {
+            add_credit_to_producer(credit_order.producer_id, credit_order.escrow)?;
+            release_vintage_credits(&credit_order, credit_order.escrow);
+            // a contested settlement is undone, its trades no longer count as traded
+            if credit_order.paid {
+                reverse_trades(credit_order.id);
+            }
+            CreditOrder {
+                escrow: 0,
+                paid: false,
+                status: OrderStatus::Cancelled,
+                ..credit_order
+            }
+        }
+        DisputeRuling::Split { buyer_credits } if *buyer_credits == 0 => {
+            return Err(Error::InvalidPayload {
+                msg: "A split must give the buyer some credits, refund the seller instead"
+                    .to_string(),
+            })
+        }
+        DisputeRuling::Split { buyer_credits } if *buyer_credits > credit_order.escrow => {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "Cannot split more than the {} escrowed credits",
+                    credit_order.escrow
+                ),
+            })
+        }
+        // an unsettled order is settled for the buyer share, with its fees and trade
+        DisputeRuling::Split { buyer_credits } if !credit_order.paid => {
+            settle_escrow(credit_order, *buyer_credits)?
+        }
+        DisputeRuling::Split { buyer_credits } => {
+            add_credit_to_client(client_id, *buyer_credits)?;
+            add_credit_to_producer(
+                credit_order.producer_id,
+                credit_order.escrow - buyer_credits,
+            )?;
+            release_vintage_credits(&credit_order, credit_order.escrow - buyer_credits);
+            CreditOrder {
+                escrow: 0,
+                paid: true,
+                status: OrderStatus::Paid,
+                ..credit_order
+            }
+        }
+    };
+    let order_id = credit_order.id;
+    notify_dispute_parties(
+        &credit_order,
+        dispute.id,
+        format!(
+            "Dispute {} on credit order {} resolved, see the dispute for the ruling",
+            dispute.id, order_id
+        ),
+    );
+    CREDIT_ORDER_STORAGE.with(|s| s.borrow_mut().insert(order_id, credit_order));
+    certify_order(order_id);
+
+    let dispute = Dispute {
+        status: DisputeStatus::Resolved,
+        ruling: Some(ruling),
+        arbiter_id,
+        resolved_at: Some(ic_cdk::api::time()),
+        ..dispute
+    };
+    DISPUTE_STORAGE.with(|s| s.borrow_mut().insert(dispute.id, dispute.clone()));
+    Ok(dispute)
+}
+
+// the settlements of a client contested by producers, for replaying its balance
+pub(crate) fn client_clawbacks(client_id: u64) -> Vec<SettlementClawback> {
+    DISPUTE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter_map(|(_, dispute)| {
+                let credits = dispute.clawback?;
+                let credit_order =
+                    CREDIT_ORDER_STORAGE.with(|s| s.borrow().get(&dispute.order_id))?;
+                if credit_order.client_id != Some(client_id) {
+                    return None;
+                }
+                let returned = match (&dispute.ruling, dispute.resolved_at) {
+                    (Some(DisputeRuling::ReleaseToBuyer), Some(resolved_at)) => {
+                        Some((resolved_at, credits))
+                    }
+                    (Some(DisputeRuling::Split { buyer_credits }), Some(resolved_at)) => {
+                        Some((resolved_at, *buyer_credits))
+                    }
+                    _ => None,
+                };
+                Some(SettlementClawback {
+                    order_id: dispute.order_id,
+                    taken_at: dispute.opened_at,
+                    credits,
+                    returned,
+                })
+            })
+            .collect()
+    })
+}
+
+// check if a credit order has a dispute awaiting a ruling
+pub(crate) fn has_open_dispute(credit_order: &CreditOrder) -> bool {
+    DISPUTE_STORAGE.with(|s| {
+        let storage = s.borrow();
+        credit_order.dispute_ids.iter().any(|id| {
+            storage
+                .get(id)
+                .is_some_and(|dispute| dispute.status != DisputeStatus::Resolved)
+        })
+    })
+}
+
+// escalate an unanswered dispute to the arbiters, the escrow stays frozen until they rule
+fn expire_dispute(dispute_id: u64) {
+    let Some(dispute) = DISPUTE_STORAGE.with(|s| s.borrow().get(&dispute_id)) else {
+        return;
+    };
+    if dispute.status != DisputeStatus::Open {
+        return;
+    }
+    let dispute = Dispute {
+        status: DisputeStatus::Escalated,
+        ..dispute
+    };
+    DISPUTE_STORAGE.with(|s| s.borrow_mut().insert(dispute_id, dispute.clone()));
+    if let Some(credit_order) = CREDIT_ORDER_STORAGE.with(|s| s.borrow().get(&dispute.order_id)) {
+        notify_dispute_parties(
+            &credit_order,
+            dispute_id,
+            format!(
+                "Dispute {} on credit order {} was not answered in time and awaits an arbiter",
+                dispute_id, dispute.order_id
+            ),
+        );
+    }
+}
+
+// credits the client received from the settlement of an order, as recorded by its trades, and
+// when the order was settled, None while it is unsettled
+fn settled_delivery(credit_order: &CreditOrder) -> Option<(u64, u64)> {
+    let (credits, settled_at) = TRADE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, trade)| trade.order_id == credit_order.id)
+            .fold((0u64, None), |(credits, settled_at), (_, trade)| {
+                (
+                    credits + trade.received(),
+                    settled_at.max(Some(trade.settled_at)),
+                )
+            })
+    });
+    let settled_at = settled_at?;
+    Some((credits, settled_at))
+}
+
+// check whether a settlement can still be contested by the producer, once and within the window
+fn ensure_settlement_contestable(credit_order: &CreditOrder, settled_at: u64) -> Result<(), Error> {
+    let now = ic_cdk::api::time();
+    if now > settled_at.saturating_add(SETTLEMENT_DISPUTE_WINDOW.as_nanos() as u64) {
+        return Err(Error::InvalidPayload {
+            msg: "The settlement can no longer be disputed".to_string(),
+        });
+    }
+    let contested = DISPUTE_STORAGE.with(|s| {
+        let storage = s.borrow();
+        credit_order.dispute_ids.iter().any(|id| {
+            storage
+                .get(id)
+                .is_some_and(|dispute| dispute.opened_at >= settled_at)
+        })
+    });
+    if contested {
+        return Err(Error::InvalidPayload {
+            msg: "The settlement has already been disputed".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn schedule_dispute_deadline(dispute_id: u64, respond_by: u64) {
+    let delay = Duration::from_nanos(respond_by.saturating_sub(ic_cdk::api::time()));
+    ic_cdk_timers::set_timer(delay, move || expire_dispute(dispute_id));
+}
+
+// re-arm the response deadlines of unanswered disputes
+pub(crate) fn restore_dispute_timers() {
+    DISPUTE_STORAGE.with(|s| {
+        for (id, dispute) in s.borrow().iter() {
+            if dispute.status == DisputeStatus::Open {
+                schedule_dispute_deadline(id, dispute.respond_by);
+            }
+        }
+    });
+}
+
+// add an arbiter allowed to rule on disputes
+#[ic_cdk::update]
+fn add_arbiter(payload: ArbiterPayload) -> Result<ArbiterReturn, Error> {
+    idempotent(
+        "add_arbiter",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            authorize_admin(&payload.contract_password)?;
+
+            let id = next_id();
+            let arbiter = Arbiter {
+                id,
+                name: payload.name,
+                password: payload.password,
+            };
+            ARBITER_STORAGE.with(|s| s.borrow_mut().insert(id, arbiter.clone()));
+            Ok(ArbiterReturn {
+                id,
+                name: arbiter.name,
+            })
+        },
+    )
+}
+
+// open a dispute on a credit order, freezing its escrow
+#[ic_cdk::update]
+fn open_dispute(payload: OpenDisputePayload) -> Result<Dispute, Error> {
+    idempotent(
+        "open_dispute",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            let credit_order = get_order(payload.order_id)?;
+            let Some(client_id) = credit_order.client_id else {
+                return Err(Error::InvalidPayload {
+                    msg: "Client has not bid for credit order".to_string(),
+                });
+            };
+            let opened_by = authorize_party(&credit_order, &payload.auth)?;
+            if credit_order.dispute_ids.len() >= MAX_DISPUTES_PER_ORDER {
+                return Err(Error::InvalidPayload {
+                    msg: format!(
+                        "Credit order has reached the limit of {} disputes",
+                        MAX_DISPUTES_PER_ORDER
+                    ),
+                });
+            }
+
+            let escrow = match credit_order.status {
+                OrderStatus::Open => credit_order.escrow,
+                // a settlement can only be contested by the producer, the delivered credits
+                // are taken back from the client into escrow
+                OrderStatus::Paid if opened_by == DisputeParty::Producer => {
+                    let (delivered, settled_at) =
+                        settled_delivery(&credit_order).ok_or(Error::InvalidPayload {
+                            msg: "Credit order has no recorded settlement".to_string(),
+                        })?;
+                    ensure_settlement_contestable(&credit_order, settled_at)?;
+                    deduct_credit_from_client(client_id, delivered)?;
+                    delivered
+                }
+                OrderStatus::Paid => {
+                    return Err(Error::AlreadyPaid {
+                        msg: "Credit order has already been paid".to_string(),
+                    })
+                }
+                OrderStatus::Frozen | OrderStatus::Cancelled => {
+                    return Err(Error::InvalidPayload {
+                        msg: "Credit order is frozen or cancelled".to_string(),
+                    })
+                }
+            };
+
+            let id = next_id();
+            let now = ic_cdk::api::time();
+            let dispute = Dispute {
+                id,
+                order_id: credit_order.id,
+                opened_by,
+                reason: payload.reason,
+                response: None,
+                status: DisputeStatus::Open,
+                ruling: None,
+                arbiter_id: None,
+                opened_at: now,
+                respond_by: now + DISPUTE_RESPONSE_WINDOW.as_nanos() as u64,
+                resolved_at: None,
+                clawback: (credit_order.status == OrderStatus::Paid).then_some(escrow),
+            };
+            DISPUTE_STORAGE.with(|s| s.borrow_mut().insert(id, dispute.clone()));
+
+            let mut dispute_ids = credit_order.dispute_ids.clone();
+            dispute_ids.push(id);
+            CREDIT_ORDER_STORAGE.with(|s| {
+                s.borrow_mut().insert(
+                    credit_order.id,
+                    CreditOrder {
+                        escrow,
+                        status: OrderStatus::Frozen,
+                        dispute_ids,
+                        ..credit_order
+                    },
+                )
+            });
+            certify_order(credit_order.id);
+            notify_dispute_parties(
+                &credit_order,
+                id,
+                format!(
+                    "Dispute {} opened on credit order {}, its escrow is frozen until resolved",
+                    id, credit_order.id
+                ),
+            );
+
+            schedule_dispute_deadline(id, dispute.respond_by);
+            Ok(dispute)
+        },
+    )
+}
+
+// counterparty response to an open dispute
+#[ic_cdk::update]
+fn respond_to_dispute(payload: RespondDisputePayload) -> Result<Dispute, Error> {
+    idempotent(
+        "respond_to_dispute",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            let dispute = DISPUTE_STORAGE
+                .with(|s| s.borrow().get(&payload.dispute_id))
+                .ok_or(Error::NotFound {
+                    msg: format!("dispute with id: {} not found", payload.dispute_id),
+                })?;
+            if dispute.status != DisputeStatus::Open {
+                return Err(Error::InvalidPayload {
+                    msg: "Dispute has already been answered or resolved".to_string(),
+                });
+            }
+            let credit_order = get_order(dispute.order_id)?;
+            if authorize_party(&credit_order, &payload.auth)? == dispute.opened_by {
+                return Err(Error::Unauthorized {
+                    msg: "Only the counterparty can respond to a dispute".to_string(),
+                });
+            }
+
+            let dispute = Dispute {
+                response: Some(payload.response),
+                status: DisputeStatus::Responded,
+                ..dispute
+            };
+            DISPUTE_STORAGE.with(|s| s.borrow_mut().insert(dispute.id, dispute.clone()));
+            Ok(dispute)
+        },
+    )
+}
+
+// arbiter ruling on a dispute
+#[ic_cdk::update]
+fn rule_dispute(payload: RuleDisputePayload) -> Result<Dispute, Error> {
+    idempotent(
+        "rule_dispute",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            match ARBITER_STORAGE.with(|s| s.borrow().get(&payload.arbiter_id)) {
+                Some(arbiter) if arbiter.password == payload.password => {}
+                Some(_) => {
+                    return Err(Error::Unauthorized {
+                        msg: "Unauthorized, method only available to arbiters".to_string(),
+                    })
+                }
+                None => {
+                    return Err(Error::NotFound {
+                        msg: "Arbiter not found".to_string(),
+                    })
+                }
+            }
+            let dispute = DISPUTE_STORAGE
+                .with(|s| s.borrow().get(&payload.dispute_id))
+                .ok_or(Error::NotFound {
+                    msg: format!("dispute with id: {} not found", payload.dispute_id),
+                })?;
+            if dispute.status == DisputeStatus::Resolved {
+                return Err(Error::InvalidPayload {
+                    msg: "Dispute has already been resolved".to_string(),
+                });
+            }
+
+            resolve_dispute(dispute, payload.ruling, Some(payload.arbiter_id))
+        },
+    )
+}
+
+// get dispute by id
+#[ic_cdk::query]
+fn get_dispute(id: u64) -> Result<Dispute, Error> {
+    match DISPUTE_STORAGE.with(|s| s.borrow().get(&id)) {
+        Some(dispute) => Ok(dispute),
+        None => Err(Error::NotFound {
+            msg: format!("dispute with id: {} not found", id),
+        }),
+    }
+}
+
+// get the dispute history of a credit order
+#[ic_cdk::query]
+fn get_order_disputes(order_id: u64) -> Result<Vec<Dispute>, Error> {
+    let credit_order = get_order(order_id)?;
+    Ok(DISPUTE_STORAGE.with(|s| {
+        let storage = s.borrow();
+        credit_order
+            .dispute_ids
+            .iter()
+            .filter_map(|id| storage.get(id))
+            .collect()
+    }))
+}
+
+// get all disputes awaiting a ruling
+#[ic_cdk::query]
+fn get_open_disputes() -> Vec<Dispute> {
+    DISPUTE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, dispute)| dispute)
+            .filter(|dispute| dispute.status != DisputeStatus::Resolved)
+            .collect()
+    })
+}
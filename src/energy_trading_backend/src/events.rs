@@ -0,0 +1,546 @@
+use candid::{Decode, Encode, Principal};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::BTreeSet;
+use std::time::Duration;
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+};
+use validator::Validate;
+
+use crate::{
+    authorize_admin, idempotent, next_id, truncate_bytes, AccountRef, Error, Memory,
+    CONTRACT_STORAGE, MEMORY_MANAGER,
+};
+
+const MAX_SUBSCRIPTIONS: usize = 100;
+// events pushed to a subscriber per call
+const MAX_EVENTS_PER_DELIVERY: usize = 50;
+const MAX_EVENTS_PER_PAGE: u32 = 200;
+// events read past per batch or page, so sparse kind filters stay within the instruction limit
+const MAX_EVENTS_SCANNED: usize = 5000;
+// a failed delivery is retried after 5 seconds, doubling up to an hour
+const RETRY_BASE_SECONDS: u64 = 5;
+const RETRY_MAX_SECONDS: u64 = 3600;
+// delivery errors are cut to this many bytes, so the subscription stays within its bound
+const MAX_ERROR_BYTES: usize = 256;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum EventKind {
+    #[default]
+    OrderCreated,
+    BidPlaced,
+    // the account is the client whose high bid was beaten
+    Outbid,
+    Settled,
+    CreditsMinted,
+}
+
+// something that happened on the market, numbered in the order it happened
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct MarketEvent {
+    seq: u64,
+    kind: EventKind,
+    order_id: Option<u64>,
+    account: Option<AccountRef>,
+    credits: u64,
+    price_per_credit: Option<u64>,
+    created_at: u64,
+}
+
+// a canister receiving market events through calls to one of its methods
+// the method is called with a vec of events and must return unit
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Subscription {
+    id: u64,
+    canister: Principal,
+    method: String,
+    // empty for every kind
+    kinds: Vec<EventKind>,
+    // sequence number of the last event delivered or skipped
+    cursor: u64,
+    // consecutive failed deliveries
+    failures: u32,
+    next_attempt_at: u64,
+    last_error: Option<String>,
+    // events are only delivered once an admin approves the subscriber
+    approved_at: Option<u64>,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct EventPage {
+    events: Vec<MarketEvent>,
+    // pass back as the cursor of the next call
+    next_cursor: u64,
+}
+
+impl Storable for MarketEvent {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Subscription {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for MarketEvent {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Subscription {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // sequence number -> event, numbered from 1 without gaps
+    static EVENT_STORAGE: RefCell<StableBTreeMap<u64, MarketEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+
+    static SUBSCRIPTION_STORAGE: RefCell<StableBTreeMap<u64, Subscription, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+    ));
+
+    // when the pending delivery run is due, an earlier run replaces it
+    static DELIVERY_TIMER: Cell<Option<(u64, TimerId)>> = const { Cell::new(None) };
+
+    // subscriptions with a call in flight, so a slow subscriber is not called twice
+    static DELIVERIES_IN_FLIGHT: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct SubscribePayload {
+    #[validate(length(min = 1, max = 64))]
+    method: String,
+    #[validate(length(max = 5))]
+    kinds: Vec<EventKind>,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ApproveSubscriptionPayload {
+    subscription_id: u64,
+    contract_password: String,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct UnsubscribePayload {
+    subscription_id: u64,
+    // lets admins remove the subscription of another canister
+    contract_password: Option<String>,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct EventQuery {
+    // sequence number of the last event seen, 0 to start from the beginning
+    cursor: u64,
+    // empty for every kind
+    kinds: Vec<EventKind>,
+    limit: u32,
+}
+
+fn last_seq() -> u64 {
+    EVENT_STORAGE.with(|s| s.borrow().last_key_value().map_or(0, |(seq, _)| seq))
+}
+
+fn matches_kind(kinds: &[EventKind], event: &MarketEvent) -> bool {
+    kinds.is_empty() || kinds.contains(&event.kind)
+}
+
+// record a market event and push it to the subscribers
+pub(crate) fn emit_event(
+    kind: EventKind,
+    order_id: Option<u64>,
+    account: Option<AccountRef>,
+    credits: u64,
+    price_per_credit: Option<u64>,
+) {
+    let seq = last_seq() + 1;
+    let event = MarketEvent {
+        seq,
+        kind,
+        order_id,
+        account,
+        credits,
+        price_per_credit,
+        created_at: ic_cdk::api::time(),
+    };
+    EVENT_STORAGE.with(|s| s.borrow_mut().insert(seq, event));
+    if SUBSCRIPTION_STORAGE.with(|s| !s.borrow().is_empty()) {
+        schedule_delivery(Duration::ZERO);
+    }
+}
+
+fn schedule_delivery(delay: Duration) {
+    let at = ic_cdk::api::time().saturating_add(delay.as_nanos() as u64);
+    if let Some((scheduled_at, timer)) = DELIVERY_TIMER.with(|t| t.get()) {
+        if scheduled_at <= at {
+            return;
+        }
+        ic_cdk_timers::clear_timer(timer);
+    }
+    let timer = ic_cdk_timers::set_timer(delay, deliver_pending_events);
+    DELIVERY_TIMER.with(|t| t.set(Some((at, timer))));
+}
+
+// start a delivery to every subscriber that is due, each in its own call so a slow or
+// failing subscriber does not hold back the others
+fn deliver_pending_events() {
+    DELIVERY_TIMER.with(|t| t.set(None));
+    let now = ic_cdk::api::time();
+    let due: Vec<Subscription> = SUBSCRIPTION_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| {
+                subscription.approved_at.is_some() && subscription.next_attempt_at <= now
+            })
+            .collect()
+    });
+    for subscription in due {
+        if DELIVERIES_IN_FLIGHT.with(|f| f.borrow().contains(&subscription.id)) {
+            continue;
+        }
+        let (events, cursor) = pending_events(&subscription);
+        if cursor == subscription.cursor {
+            continue;
+        }
+        if events.is_empty() {
+            // only filtered out events, move past them without a call
+            SUBSCRIPTION_STORAGE.with(|s| {
+                s.borrow_mut().insert(
+                    subscription.id,
+                    Subscription {
+                        cursor,
+                        ..subscription
+                    },
+                )
+            });
+            continue;
+        }
+        let guard = DeliveryGuard::new(subscription.id);
+        ic_cdk::spawn(deliver_to_subscriber(guard, subscription, events, cursor));
+    }
+    schedule_next_delivery();
+}
+
+// run again at once for subscribers with events left, or when the next retry is due,
+// subscribers with a call in flight schedule their own next run when it returns
+fn schedule_next_delivery() {
+    let last_seq = last_seq();
+    let now = ic_cdk::api::time();
+    let next_run = SUBSCRIPTION_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| {
+                subscription.approved_at.is_some()
+                    && subscription.cursor < last_seq
+                    && !DELIVERIES_IN_FLIGHT.with(|f| f.borrow().contains(&subscription.id))
+            })
+            .map(|subscription| {
+                Duration::from_nanos(subscription.next_attempt_at.saturating_sub(now))
+            })
+            .min()
+    });
+    if let Some(delay) = next_run {
+        schedule_delivery(delay);
+    }
+}
+
+// marks a subscription as in flight until dropped, also when the callback traps
+struct DeliveryGuard(u64);
+
+impl DeliveryGuard {
+    fn new(subscription_id: u64) -> Self {
+        DELIVERIES_IN_FLIGHT.with(|f| f.borrow_mut().insert(subscription_id));
+        DeliveryGuard(subscription_id)
+    }
+}
+
+impl Drop for DeliveryGuard {
+    fn drop(&mut self) {
+        DELIVERIES_IN_FLIGHT.with(|f| f.borrow_mut().remove(&self.0));
+    }
+}
+
+// the subscriber events after its cursor, and the cursor once they are delivered
+fn pending_events(subscription: &Subscription) -> (Vec<MarketEvent>, u64) {
+    EVENT_STORAGE.with(|s| {
+        let mut events = Vec::new();
+        let mut cursor = subscription.cursor;
+        let log = s.borrow();
+        let range = log
+            .range(subscription.cursor + 1..)
+            .take(MAX_EVENTS_SCANNED);
+        for (seq, event) in range {
+            if events.len() == MAX_EVENTS_PER_DELIVERY {
+                break;
+            }
+            cursor = seq;
+            if matches_kind(&subscription.kinds, &event) {
+                events.push(event);
+            }
+        }
+        (events, cursor)
+    })
+}
+
+fn retry_delay(failures: u32) -> u64 {
+    RETRY_BASE_SECONDS
+        .saturating_mul(1 << failures.saturating_sub(1).min(20))
+        .min(RETRY_MAX_SECONDS)
+}
+
+// the error of a failed call, cut to MAX_ERROR_BYTES
+fn delivery_error(error: String) -> String {
+    truncate_bytes(error, MAX_ERROR_BYTES)
+}
+
+// push one batch to a subscriber and advance its cursor, or back off when the call fails
+async fn deliver_to_subscriber(
+    guard: DeliveryGuard,
+    subscription: Subscription,
+    events: Vec<MarketEvent>,
+    cursor: u64,
+) {
+    let result = ic_cdk::call::<_, ()>(subscription.canister, &subscription.method, (events,))
+        .await
+        .map_err(|(code, msg)| delivery_error(format!("{:?}: {}", code, msg)));
+    drop(guard);
+    // the subscription may have been removed while the call was in flight
+    let Some(current) = SUBSCRIPTION_STORAGE.with(|s| s.borrow().get(&subscription.id)) else {
+        return;
+    };
+    let updated = match result {
+        Ok(()) => Subscription {
+            cursor,
+            failures: 0,
+            last_error: None,
+            ..current
+        },
+        Err(error) => {
+            let failures = current.failures.saturating_add(1);
+            Subscription {
+                failures,
+                next_attempt_at: ic_cdk::api::time()
+                    .saturating_add(Duration::from_secs(retry_delay(failures)).as_nanos() as u64),
+                last_error: Some(error),
+                ..current
+            }
+        }
+    };
+    SUBSCRIPTION_STORAGE.with(|s| s.borrow_mut().insert(updated.id, updated));
+    schedule_next_delivery();
+}
+
+// resume delivering events after an upgrade
+pub(crate) fn restore_event_delivery() {
+    schedule_delivery(Duration::ZERO);
+}
+
+// ask for the calling canister to receive market events, once an admin approves it
+#[ic_cdk::update]
+fn subscribe_events(payload: SubscribePayload) -> Result<Subscription, Error> {
+    idempotent(
+        "subscribe_events",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            let canister = ic_cdk::caller();
+            if canister == Principal::anonymous() {
+                return Err(Error::Unauthorized {
+                    msg: "Anonymous callers cannot subscribe".to_string(),
+                });
+            }
+            if SUBSCRIPTION_STORAGE.with(|s| s.borrow().len()) as usize >= MAX_SUBSCRIPTIONS {
+                return Err(Error::InvalidPayload {
+                    msg: format!("At most {} subscriptions are allowed", MAX_SUBSCRIPTIONS),
+                });
+            }
+            let subscription = Subscription {
+                id: next_id(),
+                canister,
+                method: payload.method,
+                kinds: payload.kinds,
+                cursor: last_seq(),
+                failures: 0,
+                next_attempt_at: 0,
+                last_error: None,
+                approved_at: None,
+                created_at: ic_cdk::api::time(),
+            };
+            SUBSCRIPTION_STORAGE
+                .with(|s| s.borrow_mut().insert(subscription.id, subscription.clone()));
+            Ok(subscription)
+        },
+    )
+}
+
+// let a subscriber receive events, delivered from the next event on (admin only)
+#[ic_cdk::update]
+fn approve_event_subscription(payload: ApproveSubscriptionPayload) -> Result<Subscription, Error> {
+    idempotent(
+        "approve_event_subscription",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_admin(&payload.contract_password)?;
+            let subscription = SUBSCRIPTION_STORAGE
+                .with(|s| s.borrow().get(&payload.subscription_id))
+                .ok_or(Error::NotFound {
+                    msg: format!(
+                        "subscription with id: {} not found",
+                        payload.subscription_id
+                    ),
+                })?;
+            if subscription.approved_at.is_some() {
+                return Err(Error::InvalidPayload {
+                    msg: format!("Subscription id: {} is already approved", subscription.id),
+                });
+            }
+            let approved = Subscription {
+                cursor: last_seq(),
+                approved_at: Some(ic_cdk::api::time()),
+                ..subscription
+            };
+            SUBSCRIPTION_STORAGE.with(|s| s.borrow_mut().insert(approved.id, approved.clone()));
+            Ok(approved)
+        },
+    )
+}
+
+// remove a subscription, by the subscribed canister or an admin
+#[ic_cdk::update]
+fn unsubscribe_events(payload: UnsubscribePayload) -> Result<String, Error> {
+    idempotent(
+        "unsubscribe_events",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            let subscription = SUBSCRIPTION_STORAGE
+                .with(|s| s.borrow().get(&payload.subscription_id))
+                .ok_or(Error::NotFound {
+                    msg: format!(
+                        "subscription with id: {} not found",
+                        payload.subscription_id
+                    ),
+                })?;
+            let is_admin = CONTRACT_STORAGE
+                .with(|s| s.borrow().get(&0))
+                .is_some_and(|contract| {
+                    Some(&contract.password) == payload.contract_password.as_ref()
+                });
+            if !is_admin && subscription.canister != ic_cdk::caller() {
+                return Err(Error::Unauthorized {
+                    msg:
+                        "Unauthorized, method only available to the subscriber and contract Admins"
+                            .to_string(),
+                });
+            }
+            SUBSCRIPTION_STORAGE.with(|s| s.borrow_mut().remove(&subscription.id));
+            Ok(format!(
+                "Subscription id: {} removed successfully",
+                subscription.id
+            ))
+        },
+    )
+}
+
+// get the subscriptions of the calling canister
+#[ic_cdk::query]
+fn get_event_subscriptions() -> Vec<Subscription> {
+    let caller = ic_cdk::caller();
+    SUBSCRIPTION_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| subscription.canister == caller)
+            .collect()
+    })
+}
+
+// read the events after a cursor, for consumers that poll instead of subscribing
+#[ic_cdk::query]
+fn get_events(query: EventQuery) -> Result<EventPage, Error> {
+    if query.limit == 0 || query.limit > MAX_EVENTS_PER_PAGE {
+        return Err(Error::InvalidPayload {
+            msg: format!("Limit must be between 1 and {}", MAX_EVENTS_PER_PAGE),
+        });
+    }
+    EVENT_STORAGE.with(|s| {
+        let mut events = Vec::new();
+        let mut next_cursor = query.cursor;
+        let log = s.borrow();
+        for (seq, event) in log.range(query.cursor + 1..).take(MAX_EVENTS_SCANNED) {
+            if events.len() == query.limit as usize {
+                break;
+            }
+            next_cursor = seq;
+            if matches_kind(&query.kinds, &event) {
+                events.push(event);
+            }
+        }
+        Ok(EventPage {
+            events,
+            next_cursor,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delivery_errors_are_cut_at_a_character_boundary() {
+        assert_eq!(delivery_error("rejected".to_string()), "rejected");
+        let error = delivery_error("é".repeat(200));
+        assert!(error.len() <= MAX_ERROR_BYTES);
+        assert_eq!(error, "é".repeat(MAX_ERROR_BYTES / 2));
+        let error = delivery_error(format!("x{}", "é".repeat(200)));
+        assert_eq!(error.len(), MAX_ERROR_BYTES - 1);
+    }
+
+    #[test]
+    fn largest_subscription_fits_its_bound() {
+        let subscription = Subscription {
+            id: u64::MAX,
+            canister: Principal::from_slice(&[0xff; 29]),
+            method: "\u{10ffff}".repeat(64),
+            kinds: vec![EventKind::CreditsMinted; 5],
+            cursor: u64::MAX,
+            failures: u32::MAX,
+            next_attempt_at: u64::MAX,
+            last_error: Some(delivery_error("\u{10ffff}".repeat(1000))),
+            approved_at: Some(u64::MAX),
+            created_at: u64::MAX,
+        };
+        assert!(subscription.to_bytes().len() <= Subscription::MAX_SIZE as usize);
+    }
+}
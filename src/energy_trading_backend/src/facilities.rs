@@ -0,0 +1,525 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, cmp::Ordering};
+use validator::Validate;
+
+use crate::{
+    authorize_admin, authorize_producer, award_energy, check_capacity_factor, idempotent, next_id,
+    validate_country_code, Contract, CreditOrder, Error, Memory, OrderStatus, CREDIT_ORDER_STORAGE,
+    MEMORY_MANAGER, PRODUCER_STORAGE,
+};
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub(crate) enum FacilityTechnology {
+    #[default]
+    Solar,
+    Wind,
+    Hydro,
+    Geothermal,
+    Biomass,
+    Other,
+}
+
+// only certified facilities are awarded credits
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum CertificationStatus {
+    #[default]
+    Pending,
+    Certified,
+    Revoked,
+}
+
+// a generation plant operated by a producer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Facility {
+    pub(crate) id: u64,
+    pub(crate) producer_id: u64,
+    pub(crate) name: String,
+    pub(crate) technology: FacilityTechnology,
+    // nameplate capacity in MW
+    pub(crate) capacity_mw: f64,
+    pub(crate) location: String,
+    pub(crate) country_code: String,
+    pub(crate) commissioned_at: u64,
+    pub(crate) certification: CertificationStatus,
+    // total energy awarded to the facility
+    pub(crate) energy_supply: u64,
+}
+
+// energy generated by a facility over a period and the credits minted for it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct FacilityAward {
+    pub(crate) id: u64,
+    pub(crate) facility_id: u64,
+    pub(crate) producer_id: u64,
+    // energy supply in MWh
+    pub(crate) energy_supply: u64,
+    pub(crate) credits: u64,
+    pub(crate) period_start: u64,
+    pub(crate) period_end: u64,
+    pub(crate) awarded_at: u64,
+}
+
+// credits awarded to a facility for a vintage year and how many of them are committed to orders
+// and sales, shared by orders, quotes and forward deliveries declaring the vintage
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct VintageLedger {
+    awarded: u64,
+    committed: u64,
+}
+
+impl Storable for Facility {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for FacilityAward {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for VintageLedger {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Facility {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for FacilityAward {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for VintageLedger {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    pub(crate) static FACILITY_STORAGE: RefCell<StableBTreeMap<u64, Facility, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    pub(crate) static FACILITY_AWARD_STORAGE: RefCell<StableBTreeMap<u64, FacilityAward, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    // (facility id, vintage) -> credits awarded and committed
+    static VINTAGE_LEDGER_STORAGE: RefCell<StableBTreeMap<(u64, u32), VintageLedger, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct FacilityPayload {
+    producer_id: u64,
+    password: String,
+    #[validate(length(min = 3, max = 128))]
+    name: String,
+    technology: FacilityTechnology,
+    #[validate(range(min = 0.001, max = 100000.0))]
+    capacity_mw: f64,
+    #[validate(length(min = 2, max = 256))]
+    location: String,
+    #[validate(custom = "validate_country_code")]
+    country_code: String,
+    commissioned_at: u64,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct CertificationPayload {
+    contract_password: String,
+    facility_id: u64,
+    certification: CertificationStatus,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct FacilityEnergyPayload {
+    contract_password: String,
+    facility_id: u64,
+    energy_supply: u64,
+    // generation period in nanoseconds since the epoch
+    period_start: u64,
+    period_end: u64,
+    idempotency_key: Option<String>,
+}
+
+// the calendar year (UTC) of a timestamp, credits take the vintage of the year they were generated
+pub(crate) fn vintage_year(timestamp: u64) -> u32 {
+    // days since 1970-01-01 to a civil date, shifted to years starting in March
+    let days = (timestamp / (24 * NANOS_PER_HOUR)) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let year = year_of_era + era * 400;
+    // January and February belong to the next year
+    (if month_index >= 10 { year + 1 } else { year }) as u32
+}
+
+fn update_vintage_ledger(facility_id: u64, vintage: u32, update: impl FnOnce(&mut VintageLedger)) {
+    VINTAGE_LEDGER_STORAGE.with(|s| {
+        let mut ledger = s.borrow().get(&(facility_id, vintage)).unwrap_or_default();
+        update(&mut ledger);
+        s.borrow_mut().insert((facility_id, vintage), ledger)
+    });
+}
+
+// add credits minted for energy a facility generated in a vintage year
+pub(crate) fn record_vintage_award(facility_id: u64, vintage: u32, credits: u64) {
+    update_vintage_ledger(facility_id, vintage, |ledger| ledger.awarded += credits);
+}
+
+// credits of a facility vintage not committed to an order or sale yet
+pub(crate) fn remaining_vintage_credits(facility_id: u64, vintage: u32) -> u64 {
+    VINTAGE_LEDGER_STORAGE
+        .with(|s| s.borrow().get(&(facility_id, vintage)))
+        .map_or(0, |ledger| ledger.awarded.saturating_sub(ledger.committed))
+}
+
+// check that a facility vintage has the credits left, sources without a facility are not tracked
+pub(crate) fn ensure_vintage_credits(
+    facility_id: Option<u64>,
+    vintage: Option<u32>,
+    credits: u64,
+) -> Result<(), Error> {
+    let (Some(facility_id), Some(vintage)) = (facility_id, vintage) else {
+        return Ok(());
+    };
+    let remaining = remaining_vintage_credits(facility_id, vintage);
+    if remaining < credits {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Facility has {} uncommitted credits for the {} vintage, {} requested",
+                remaining, vintage, credits
+            ),
+        });
+    }
+    Ok(())
+}
+
+// commit credits of a facility vintage to an order or sale, after ensure_vintage_credits
+pub(crate) fn commit_vintage_credits(facility_id: Option<u64>, vintage: Option<u32>, credits: u64) {
+    if let (Some(facility_id), Some(vintage)) = (facility_id, vintage) {
+        update_vintage_ledger(facility_id, vintage, |ledger| ledger.committed += credits);
+    }
+}
+
+// release the credits of an order that go back to its producer unsold
+pub(crate) fn release_vintage_credits(credit_order: &CreditOrder, credits: u64) {
+    if let (Some(facility_id), Some(vintage)) = (credit_order.facility_id, credit_order.vintage) {
+        update_vintage_ledger(facility_id, vintage, |ledger| {
+            ledger.committed = ledger.committed.saturating_sub(credits)
+        });
+    }
+}
+
+// build the ledger from the awards and orders stored before it was kept
+pub(crate) fn seed_vintage_ledger() {
+    if !VINTAGE_LEDGER_STORAGE.with(|s| s.borrow().is_empty()) {
+        return;
+    }
+    let awards: Vec<FacilityAward> =
+        FACILITY_AWARD_STORAGE.with(|s| s.borrow().iter().map(|(_, award)| award).collect());
+    for award in awards {
+        record_vintage_award(
+            award.facility_id,
+            vintage_year(award.period_start),
+            award.credits,
+        );
+    }
+    let orders: Vec<CreditOrder> = CREDIT_ORDER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, credit_order)| credit_order)
+            .filter(|credit_order| credit_order.status != OrderStatus::Cancelled)
+            .collect()
+    });
+    for credit_order in orders {
+        commit_vintage_credits(
+            credit_order.facility_id,
+            credit_order.vintage,
+            credit_order.credits,
+        );
+    }
+}
+
+// check the facility and vintage a producer declares as the source of an order
+pub(crate) fn validate_credit_source(
+    producer_id: u64,
+    facility_id: Option<u64>,
+    vintage: Option<u32>,
+    credits: u64,
+) -> Result<(), Error> {
+    if vintage.is_some_and(|vintage| vintage > vintage_year(ic_cdk::api::time())) {
+        return Err(Error::InvalidPayload {
+            msg: "Vintage cannot be in the future".to_string(),
+        });
+    }
+    let Some(facility_id) = facility_id else {
+        return Ok(());
+    };
+    let Some(vintage) = vintage else {
+        return Err(Error::InvalidPayload {
+            msg: "Orders from a facility need a vintage".to_string(),
+        });
+    };
+    let facility = get_facility_record(facility_id)?;
+    if facility.producer_id != producer_id {
+        return Err(Error::Unauthorized {
+            msg: "Facility does not belong to producer".to_string(),
+        });
+    }
+    ensure_vintage_credits(Some(facility_id), Some(vintage), credits)
+}
+
+pub(crate) fn get_facility_record(facility_id: u64) -> Result<Facility, Error> {
+    FACILITY_STORAGE
+        .with(|s| s.borrow().get(&facility_id))
+        .ok_or(Error::NotFound {
+            msg: format!("facility with id: {} not found", facility_id),
+        })
+}
+
+// the most energy in MWh a facility can generate at full nameplate capacity over a period
+pub(crate) fn max_generation(facility: &Facility, period_start: u64, period_end: u64) -> f64 {
+    facility.capacity_mw * (period_end - period_start) as f64 / NANOS_PER_HOUR as f64
+}
+
+// register a generation facility for a producer, pending certification
+#[ic_cdk::update]
+fn add_facility(payload: FacilityPayload) -> Result<Facility, Error> {
+    idempotent(
+        "add_facility",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            // the range check lets NaN through
+            if !payload.capacity_mw.is_finite() {
+                return Err(Error::InvalidPayload {
+                    msg: "Capacity must be a finite number of MW".to_string(),
+                });
+            }
+            authorize_producer(payload.producer_id, &payload.password)?;
+            let id = next_id();
+            let facility = Facility {
+                id,
+                producer_id: payload.producer_id,
+                name: payload.name,
+                technology: payload.technology,
+                capacity_mw: payload.capacity_mw,
+                location: payload.location,
+                country_code: payload.country_code.to_ascii_uppercase(),
+                commissioned_at: payload.commissioned_at,
+                certification: CertificationStatus::Pending,
+                energy_supply: 0,
+            };
+            FACILITY_STORAGE.with(|s| s.borrow_mut().insert(id, facility.clone()));
+            Ok(facility)
+        },
+    )
+}
+
+// set the certification status of a facility
+#[ic_cdk::update]
+fn set_facility_certification(payload: CertificationPayload) -> Result<Facility, Error> {
+    idempotent(
+        "set_facility_certification",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_admin(&payload.contract_password)?;
+            let facility = Facility {
+                certification: payload.certification,
+                ..get_facility_record(payload.facility_id)?
+            };
+            FACILITY_STORAGE.with(|s| s.borrow_mut().insert(facility.id, facility.clone()));
+            Ok(facility)
+        },
+    )
+}
+
+// award credits for the energy a certified facility generated over a period
+#[ic_cdk::update]
+pub(crate) fn award_facility_energy(
+    payload: FacilityEnergyPayload,
+) -> Result<FacilityAward, Error> {
+    idempotent(
+        "award_facility_energy",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            let (contract, facility) = check_facility_award(&payload)?;
+            award_facility(
+                &contract,
+                facility,
+                payload.energy_supply,
+                payload.period_start,
+                payload.period_end,
+            )
+        },
+    )
+}
+
+// authenticate a facility award and get the facility, also used to check batched awards
+pub(crate) fn check_facility_award(
+    payload: &FacilityEnergyPayload,
+) -> Result<(Contract, Facility), Error> {
+    let contract = authorize_admin(&payload.contract_password)?;
+    let facility = get_facility_record(payload.facility_id)?;
+    Ok((contract, facility))
+}
+
+// check an energy supply against the facility and its generation period, then mint its credits
+pub(crate) fn award_facility(
+    contract: &Contract,
+    facility: Facility,
+    energy_supply: u64,
+    period_start: u64,
+    period_end: u64,
+) -> Result<FacilityAward, Error> {
+    if facility.certification != CertificationStatus::Certified {
+        return Err(Error::InvalidPayload {
+            msg: "Only certified facilities can be awarded energy".to_string(),
+        });
+    }
+    if period_end <= period_start || period_end > ic_cdk::api::time() {
+        return Err(Error::InvalidPayload {
+            msg: "Award period must end after it starts and not in the future".to_string(),
+        });
+    }
+    if period_start < facility.commissioned_at {
+        return Err(Error::InvalidPayload {
+            msg: "Award period starts before the facility was commissioned".to_string(),
+        });
+    }
+    // capacity factor check, a facility cannot generate more than its nameplate capacity,
+    // a capacity that is not a number is refused too
+    let max_energy = max_generation(&facility, period_start, period_end);
+    if !matches!(
+        (energy_supply as f64).partial_cmp(&max_energy),
+        Some(Ordering::Less | Ordering::Equal)
+    ) {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Energy supply of {} MWh exceeds the {:.3} MWh the facility can generate in the period",
+                energy_supply, max_energy
+            ),
+        });
+    }
+    let overlapping = FACILITY_AWARD_STORAGE.with(|s| {
+        s.borrow().iter().any(|(_, award)| {
+            award.facility_id == facility.id
+                && award.period_start < period_end
+                && period_start < award.period_end
+        })
+    });
+    if overlapping {
+        return Err(Error::InvalidPayload {
+            msg: "Facility was already awarded energy for part of this period".to_string(),
+        });
+    }
+    check_capacity_factor(&facility, energy_supply, period_start, period_end)?;
+
+    let credits = award_energy(
+        contract,
+        facility.producer_id,
+        Some(facility.id),
+        Some(vintage_year(period_start)),
+        energy_supply,
+    )?;
+    FACILITY_STORAGE.with(|s| {
+        s.borrow_mut().insert(
+            facility.id,
+            Facility {
+                energy_supply: facility.energy_supply + energy_supply,
+                ..facility.clone()
+            },
+        )
+    });
+    let id = next_id();
+    let award = FacilityAward {
+        id,
+        facility_id: facility.id,
+        producer_id: facility.producer_id,
+        energy_supply,
+        credits,
+        period_start,
+        period_end,
+        awarded_at: ic_cdk::api::time(),
+    };
+    FACILITY_AWARD_STORAGE.with(|s| s.borrow_mut().insert(id, award.clone()));
+    Ok(award)
+}
+
+// get a facility by id
+#[ic_cdk::query]
+fn get_facility(id: u64) -> Result<Facility, Error> {
+    get_facility_record(id)
+}
+
+// get the facilities of a producer
+#[ic_cdk::query]
+fn get_producer_facilities(producer_id: u64) -> Result<Vec<Facility>, Error> {
+    if !PRODUCER_STORAGE.with(|s| s.borrow().contains_key(&producer_id)) {
+        return Err(Error::NotFound {
+            msg: format!("producer with id: {} not found", producer_id),
+        });
+    }
+    Ok(FACILITY_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, facility)| facility)
+            .filter(|facility| facility.producer_id == producer_id)
+            .collect()
+    }))
+}
+
+// get the energy awards of a facility
+#[ic_cdk::query]
+fn get_facility_awards(facility_id: u64) -> Result<Vec<FacilityAward>, Error> {
+    get_facility_record(facility_id)?;
+    Ok(FACILITY_AWARD_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, award)| award)
+            .filter(|award| award.facility_id == facility_id)
+            .collect()
+    }))
+}
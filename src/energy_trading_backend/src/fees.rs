@@ -0,0 +1,877 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    authorize_admin, idempotent, CreditOrder, Error, Memory, MEMORY_MANAGER, PRODUCER_STORAGE,
+};
+
+// fees are expressed in basis points, 10_000 bps = 100%
+const BPS_DENOMINATOR: u64 = 10_000;
+// upper bound on the number of buckets a single fee report may return
+const MAX_REPORT_PERIODS: u64 = 1_000;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// asset the marketplace fees are charged in
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum FeeAsset {
+    // withheld from the traded credits and routed to the treasury balance
+    #[default]
+    Credits,
+    // charged on the off-chain payment and accrued as a treasury receivable
+    PaymentToken,
+}
+
+// how the fee rates are applied to a settlement
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum FeeModel {
+    // a single percentage paid by the producer
+    Flat {
+        fee_bps: u64,
+    },
+    // separate percentages for the producer (maker) and the winning client (taker)
+    MakerTaker {
+        maker_fee_bps: u64,
+        taker_fee_bps: u64,
+    },
+}
+
+impl Default for FeeModel {
+    fn default() -> Self {
+        FeeModel::Flat { fee_bps: 0 }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct FeeSchedule {
+    model: FeeModel,
+    asset: FeeAsset,
+    // charged in credits to the sender of a peer-to-peer transfer
+    transfer_fee_bps: u64,
+    updated_at: u64,
+}
+
+// a fee tier overrides the schedule rates for the producers assigned to it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct FeeTier {
+    id: u64,
+    name: String,
+    maker_fee_bps: u64,
+    taker_fee_bps: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Treasury {
+    credits: u64,
+    payment_token_fees: u64,
+}
+
+// what a fee was charged on
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum FeeSource {
+    Settlement {
+        order_id: u64,
+        producer_id: u64,
+        client_id: u64,
+    },
+    // transfer fees are paid by the sender and recorded as the maker fee
+    Transfer {
+        transfer_id: u64,
+    },
+}
+
+impl Default for FeeSource {
+    fn default() -> Self {
+        FeeSource::Transfer { transfer_id: 0 }
+    }
+}
+
+// fees charged on a single settlement or transfer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct FeeRecord {
+    id: u64,
+    source: FeeSource,
+    asset: FeeAsset,
+    maker_fee: u64,
+    taker_fee: u64,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct FeePeriod {
+    start: u64,
+    end: u64,
+    settlements: u64,
+    transfers: u64,
+    credits_collected: u64,
+    payment_token_collected: u64,
+}
+
+// fees computed for an order that is about to be settled
+#[derive(Clone, Copy, Default)]
+pub(crate) struct SettlementFees {
+    pub(crate) asset: FeeAsset,
+    pub(crate) maker_fee: u64,
+    pub(crate) taker_fee: u64,
+}
+
+impl SettlementFees {
+    // maker fee the producer pays from its own balance when fees are charged in credits
+    pub(crate) fn producer_credit_fee(&self) -> u64 {
+        match self.asset {
+            FeeAsset::Credits => self.maker_fee,
+            FeeAsset::PaymentToken => 0,
+        }
+    }
+
+    // credits the client receives, the order lot minus any taker fee charged in credits
+    pub(crate) fn client_credit(&self, credits: u64) -> u64 {
+        match self.asset {
+            FeeAsset::Credits => credits.saturating_sub(self.taker_fee),
+            FeeAsset::PaymentToken => credits,
+        }
+    }
+}
+
+impl Storable for FeeSchedule {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for FeeTier {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Treasury {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for FeeRecord {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for FeeSchedule {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for FeeTier {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Treasury {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for FeeRecord {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static FEE_SCHEDULE_STORAGE: RefCell<StableBTreeMap<u64, FeeSchedule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    static FEE_TIER_STORAGE: RefCell<StableBTreeMap<u64, FeeTier, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    // producer id -> fee tier id
+    static PRODUCER_FEE_TIER_STORAGE: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    static FEE_RECORD_STORAGE: RefCell<StableBTreeMap<u64, FeeRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    static TREASURY_STORAGE: RefCell<StableBTreeMap<u64, Treasury, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct FeeSchedulePayload {
+    contract_password: String,
+    model: FeeModel,
+    asset: FeeAsset,
+    transfer_fee_bps: u64,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct FeeTierPayload {
+    contract_password: String,
+    name: String,
+    maker_fee_bps: u64,
+    taker_fee_bps: u64,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ProducerFeeTierPayload {
+    contract_password: String,
+    producer_id: u64,
+    // None removes the producer from its tier
+    tier_id: Option<u64>,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct FeeReportPayload {
+    // start and end of the report in nanoseconds since the epoch
+    start: u64,
+    end: u64,
+    // length of each reporting period in seconds
+    period_seconds: u64,
+}
+
+// compute the fee on an amount, rounding down so the fee never exceeds the exact share
+pub(crate) fn fee_amount(amount: u64, fee_bps: u64) -> u64 {
+    (amount as u128 * fee_bps as u128 / BPS_DENOMINATOR as u128) as u64
+}
+
+fn validate_fee_bps(fee_bps: u64) -> Result<(), Error> {
+    if fee_bps > BPS_DENOMINATOR {
+        return Err(Error::InvalidPayload {
+            msg: format!("Fee of {} bps exceeds {} bps", fee_bps, BPS_DENOMINATOR),
+        });
+    }
+    Ok(())
+}
+
+// maker and taker rates applying to a producer, its tier wins over the schedule
+fn producer_fee_rates(producer_id: u64, schedule: &FeeSchedule) -> (u64, u64) {
+    let tier = PRODUCER_FEE_TIER_STORAGE
+        .with(|s| s.borrow().get(&producer_id))
+        .and_then(|tier_id| FEE_TIER_STORAGE.with(|s| s.borrow().get(&tier_id)));
+    match (tier, &schedule.model) {
+        (Some(tier), _) => (tier.maker_fee_bps, tier.taker_fee_bps),
+        (None, FeeModel::Flat { fee_bps }) => (*fee_bps, 0),
+        (
+            None,
+            FeeModel::MakerTaker {
+                maker_fee_bps,
+                taker_fee_bps,
+            },
+        ) => (*maker_fee_bps, *taker_fee_bps),
+    }
+}
+
+// compute the fees due when settling a credit order at its current offer
+pub(crate) fn settlement_fees(credit_order: &CreditOrder) -> Result<SettlementFees, Error> {
+    let schedule = FEE_SCHEDULE_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .unwrap_or_default();
+    let (maker_fee_bps, taker_fee_bps) = producer_fee_rates(credit_order.producer_id, &schedule);
+
+    let base = match schedule.asset {
+        FeeAsset::Credits => credit_order.credits,
+        FeeAsset::PaymentToken => credit_order
+            .credits
+            .checked_mul(credit_order.price_per_credit())
+            .ok_or(Error::InvalidPayload {
+                msg: "Order value overflow".to_string(),
+            })?,
+    };
+
+    Ok(SettlementFees {
+        asset: schedule.asset,
+        maker_fee: fee_amount(base, maker_fee_bps),
+        taker_fee: fee_amount(base, taker_fee_bps),
+    })
+}
+
+// route settlement fees to the treasury and record them for reporting
+pub(crate) fn collect_settlement_fees(
+    credit_order: &CreditOrder,
+    client_id: u64,
+    fees: SettlementFees,
+) -> Result<(), Error> {
+    record_fee(
+        FeeSource::Settlement {
+            order_id: credit_order.id,
+            producer_id: credit_order.producer_id,
+            client_id,
+        },
+        fees,
+    )
+}
+
+// compute the fee charged in credits on a peer-to-peer transfer
+pub(crate) fn transfer_fee(amount: u64) -> u64 {
+    let schedule = FEE_SCHEDULE_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .unwrap_or_default();
+    fee_amount(amount, schedule.transfer_fee_bps)
+}
+
+fn transfer_fees(fee: u64) -> SettlementFees {
+    SettlementFees {
+        asset: FeeAsset::Credits,
+        maker_fee: fee,
+        taker_fee: 0,
+    }
+}
+
+// route a transfer fee to the treasury and record it for reporting
+pub(crate) fn collect_transfer_fee(transfer_id: u64, fee: u64) -> Result<(), Error> {
+    if fee == 0 {
+        return Ok(());
+    }
+    record_fee(FeeSource::Transfer { transfer_id }, transfer_fees(fee))
+}
+
+// the treasury once the fees are collected, fails when a total overflows
+fn collected_treasury(fees: &SettlementFees) -> Result<Treasury, Error> {
+    let overflow = || Error::InvalidPayload {
+        msg: "Treasury balance overflow".to_string(),
+    };
+    let total = fees
+        .maker_fee
+        .checked_add(fees.taker_fee)
+        .ok_or_else(overflow)?;
+    let mut treasury = TREASURY_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .unwrap_or_default();
+    let balance = match fees.asset {
+        FeeAsset::Credits => &mut treasury.credits,
+        FeeAsset::PaymentToken => &mut treasury.payment_token_fees,
+    };
+    *balance = balance.checked_add(total).ok_or_else(overflow)?;
+    Ok(treasury)
+}
+
+// check the treasury can take the fees before any credits are moved
+pub(crate) fn ensure_fees_collectable(fees: &SettlementFees) -> Result<(), Error> {
+    collected_treasury(fees).map(|_| ())
+}
+
+pub(crate) fn ensure_transfer_fee_collectable(fee: u64) -> Result<(), Error> {
+    ensure_fees_collectable(&transfer_fees(fee))
+}
+
+fn record_fee(source: FeeSource, fees: SettlementFees) -> Result<(), Error> {
+    let treasury = collected_treasury(&fees)?;
+    TREASURY_STORAGE.with(|s| s.borrow_mut().insert(0, treasury));
+
+    let id = crate::next_id();
+    let record = FeeRecord {
+        id,
+        source,
+        asset: fees.asset,
+        maker_fee: fees.maker_fee,
+        taker_fee: fees.taker_fee,
+        created_at: ic_cdk::api::time(),
+    };
+    FEE_RECORD_STORAGE.with(|s| s.borrow_mut().insert(id, record));
+    Ok(())
+}
+
+// taker fees withheld in credits from clients, by the order that was settled
+pub(crate) fn client_credit_fees() -> BTreeMap<u64, u64> {
+    let mut fees = BTreeMap::new();
+    FEE_RECORD_STORAGE.with(|s| {
+        for (_, record) in s.borrow().iter() {
+            if let (FeeSource::Settlement { order_id, .. }, FeeAsset::Credits) =
+                (record.source, record.asset)
+            {
+                fees.entry(order_id).or_insert(record.taker_fee);
+            }
+        }
+    });
+    fees
+}
+
+// set the marketplace fee schedule
+#[ic_cdk::update]
+fn set_fee_schedule(payload: FeeSchedulePayload) -> Result<FeeSchedule, Error> {
+    idempotent(
+        "set_fee_schedule",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_admin(&payload.contract_password)?;
+
+            match &payload.model {
+                FeeModel::Flat { fee_bps } => validate_fee_bps(*fee_bps)?,
+                FeeModel::MakerTaker {
+                    maker_fee_bps,
+                    taker_fee_bps,
+                } => {
+                    validate_fee_bps(*maker_fee_bps)?;
+                    validate_fee_bps(*taker_fee_bps)?;
+                }
+            }
+            validate_fee_bps(payload.transfer_fee_bps)?;
+
+            let schedule = FeeSchedule {
+                model: payload.model,
+                asset: payload.asset,
+                transfer_fee_bps: payload.transfer_fee_bps,
+                updated_at: ic_cdk::api::time(),
+            };
+            FEE_SCHEDULE_STORAGE.with(|s| s.borrow_mut().insert(0, schedule.clone()));
+            Ok(schedule)
+        },
+    )
+}
+
+// get the current fee schedule
+#[ic_cdk::query]
+fn get_fee_schedule() -> FeeSchedule {
+    FEE_SCHEDULE_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .unwrap_or_default()
+}
+
+// add a fee tier that producers can be assigned to
+#[ic_cdk::update]
+fn add_fee_tier(payload: FeeTierPayload) -> Result<FeeTier, Error> {
+    idempotent(
+        "add_fee_tier",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_admin(&payload.contract_password)?;
+            if payload.name.len() < 3 {
+                return Err(Error::InvalidPayload {
+                    msg: "Fee tier name must be at least 3 characters".to_string(),
+                });
+            }
+            validate_fee_bps(payload.maker_fee_bps)?;
+            validate_fee_bps(payload.taker_fee_bps)?;
+
+            let id = crate::next_id();
+            let tier = FeeTier {
+                id,
+                name: payload.name,
+                maker_fee_bps: payload.maker_fee_bps,
+                taker_fee_bps: payload.taker_fee_bps,
+            };
+            FEE_TIER_STORAGE.with(|s| s.borrow_mut().insert(id, tier.clone()));
+            Ok(tier)
+        },
+    )
+}
+
+// get all fee tiers
+#[ic_cdk::query]
+fn get_fee_tiers() -> Vec<FeeTier> {
+    FEE_TIER_STORAGE.with(|s| s.borrow().iter().map(|(_, tier)| tier).collect())
+}
+
+// assign a producer to a fee tier, or back to the default schedule
+#[ic_cdk::update]
+fn set_producer_fee_tier(payload: ProducerFeeTierPayload) -> Result<String, Error> {
+    idempotent(
+        "set_producer_fee_tier",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_admin(&payload.contract_password)?;
+            if !PRODUCER_STORAGE.with(|s| s.borrow().contains_key(&payload.producer_id)) {
+                return Err(Error::NotFound {
+                    msg: "Producer not found".to_string(),
+                });
+            }
+
+            match payload.tier_id {
+                Some(tier_id) => {
+                    if !FEE_TIER_STORAGE.with(|s| s.borrow().contains_key(&tier_id)) {
+                        return Err(Error::NotFound {
+                            msg: format!("fee tier with id: {} not found", tier_id),
+                        });
+                    }
+                    PRODUCER_FEE_TIER_STORAGE
+                        .with(|s| s.borrow_mut().insert(payload.producer_id, tier_id));
+                    Ok(format!(
+                        "Producer id: {} assigned to fee tier id: {}",
+                        payload.producer_id, tier_id
+                    ))
+                }
+                None => {
+                    PRODUCER_FEE_TIER_STORAGE.with(|s| s.borrow_mut().remove(&payload.producer_id));
+                    Ok(format!(
+                        "Producer id: {} uses the default fee schedule",
+                        payload.producer_id
+                    ))
+                }
+            }
+        },
+    )
+}
+
+// get the treasury balances
+#[ic_cdk::query]
+fn get_treasury() -> Treasury {
+    TREASURY_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .unwrap_or_default()
+}
+
+// report the fees collected in each period between start and end
+#[ic_cdk::query]
+fn get_fee_report(payload: FeeReportPayload) -> Result<Vec<FeePeriod>, Error> {
+    if payload.end <= payload.start || payload.period_seconds == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "Report end must be after start and period must not be zero".to_string(),
+        });
+    }
+    let period = payload.period_seconds.saturating_mul(NANOS_PER_SECOND);
+    let periods = (payload.end - payload.start).div_ceil(period);
+    if periods > MAX_REPORT_PERIODS {
+        return Err(Error::InvalidPayload {
+            msg: format!("Report cannot exceed {} periods", MAX_REPORT_PERIODS),
+        });
+    }
+
+    let mut report: Vec<FeePeriod> = (0..periods)
+        .map(|i| {
+            let start = payload.start + i * period;
+            FeePeriod {
+                start,
+                end: start.saturating_add(period).min(payload.end),
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    FEE_RECORD_STORAGE.with(|s| {
+        for (_, record) in s.borrow().iter() {
+            if record.created_at < payload.start || record.created_at >= payload.end {
+                continue;
+            }
+            let bucket = &mut report[((record.created_at - payload.start) / period) as usize];
+            match record.source {
+                FeeSource::Settlement { .. } => bucket.settlements += 1,
+                FeeSource::Transfer { .. } => bucket.transfers += 1,
+            }
+            match record.asset {
+                FeeAsset::Credits => {
+                    bucket.credits_collected += record.maker_fee + record.taker_fee
+                }
+                FeeAsset::PaymentToken => {
+                    bucket.payment_token_collected += record.maker_fee + record.taker_fee
+                }
+            }
+        }
+    });
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = NANOS_PER_SECOND;
+
+    fn set_schedule(model: FeeModel, asset: FeeAsset) {
+        FEE_SCHEDULE_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                0,
+                FeeSchedule {
+                    model,
+                    asset,
+                    ..Default::default()
+                },
+            )
+        });
+    }
+
+    fn assign_tier(producer_id: u64, maker_fee_bps: u64, taker_fee_bps: u64) {
+        let tier = FeeTier {
+            id: 100,
+            name: "gold".to_string(),
+            maker_fee_bps,
+            taker_fee_bps,
+        };
+        FEE_TIER_STORAGE.with(|s| s.borrow_mut().insert(tier.id, tier));
+        PRODUCER_FEE_TIER_STORAGE.with(|s| s.borrow_mut().insert(producer_id, 100));
+    }
+
+    fn order(credits: u64, min_offer_per_credit: u64, high_bid: Option<u64>) -> CreditOrder {
+        CreditOrder {
+            id: 1,
+            client_id: Some(2),
+            producer_id: 3,
+            credits,
+            min_offer_per_credit,
+            high_bid,
+            ..Default::default()
+        }
+    }
+
+    fn insert_record(id: u64, source: FeeSource, asset: FeeAsset, fee: u64, created_at: u64) {
+        let record = FeeRecord {
+            id,
+            source,
+            asset,
+            maker_fee: fee,
+            taker_fee: 0,
+            created_at,
+        };
+        FEE_RECORD_STORAGE.with(|s| s.borrow_mut().insert(id, record));
+    }
+
+    #[test]
+    fn fee_amount_rounds_down() {
+        assert_eq!(fee_amount(999, 100), 9);
+        assert_eq!(fee_amount(1, 9_999), 0);
+        assert_eq!(fee_amount(10_000, 1), 1);
+        assert_eq!(fee_amount(0, 10_000), 0);
+        // the product is computed in u128 so large amounts do not overflow
+        assert_eq!(fee_amount(u64::MAX, 10_000), u64::MAX);
+        assert_eq!(fee_amount(u64::MAX, 5_000), u64::MAX / 2);
+    }
+
+    #[test]
+    fn flat_fee_is_charged_to_the_producer_only() {
+        set_schedule(FeeModel::Flat { fee_bps: 250 }, FeeAsset::Credits);
+        let fees = settlement_fees(&order(1_000, 10, None)).unwrap();
+        assert_eq!(fees.maker_fee, 25);
+        assert_eq!(fees.taker_fee, 0);
+        assert_eq!(fees.producer_credit_fee(), 25);
+        assert_eq!(fees.client_credit(1_000), 1_000);
+    }
+
+    #[test]
+    fn maker_taker_fees_are_charged_to_both_sides() {
+        set_schedule(
+            FeeModel::MakerTaker {
+                maker_fee_bps: 100,
+                taker_fee_bps: 300,
+            },
+            FeeAsset::Credits,
+        );
+        let fees = settlement_fees(&order(1_000, 10, None)).unwrap();
+        assert_eq!(fees.maker_fee, 10);
+        assert_eq!(fees.taker_fee, 30);
+        assert_eq!(fees.producer_credit_fee(), 10);
+        assert_eq!(fees.client_credit(1_000), 970);
+    }
+
+    #[test]
+    fn producer_tier_takes_precedence_over_the_schedule() {
+        set_schedule(
+            FeeModel::MakerTaker {
+                maker_fee_bps: 100,
+                taker_fee_bps: 300,
+            },
+            FeeAsset::Credits,
+        );
+        assign_tier(3, 50, 0);
+        let fees = settlement_fees(&order(1_000, 10, None)).unwrap();
+        assert_eq!(fees.maker_fee, 5);
+        assert_eq!(fees.taker_fee, 0);
+
+        // other producers keep the schedule rates
+        let fees = settlement_fees(&CreditOrder {
+            producer_id: 4,
+            ..order(1_000, 10, None)
+        })
+        .unwrap();
+        assert_eq!(fees.maker_fee, 10);
+        assert_eq!(fees.taker_fee, 30);
+    }
+
+    #[test]
+    fn tier_also_overrides_a_flat_schedule() {
+        set_schedule(FeeModel::Flat { fee_bps: 250 }, FeeAsset::Credits);
+        assign_tier(3, 100, 200);
+        let fees = settlement_fees(&order(1_000, 10, None)).unwrap();
+        assert_eq!(fees.maker_fee, 10);
+        assert_eq!(fees.taker_fee, 20);
+    }
+
+    #[test]
+    fn payment_token_fees_are_charged_on_the_order_value() {
+        set_schedule(
+            FeeModel::MakerTaker {
+                maker_fee_bps: 100,
+                taker_fee_bps: 200,
+            },
+            FeeAsset::PaymentToken,
+        );
+        // the high bid wins over the minimum offer
+        let fees = settlement_fees(&order(100, 10, Some(25))).unwrap();
+        assert_eq!(fees.maker_fee, 25);
+        assert_eq!(fees.taker_fee, 50);
+        // payment token fees leave the credits untouched
+        assert_eq!(fees.producer_credit_fee(), 0);
+        assert_eq!(fees.client_credit(100), 100);
+
+        let fees = settlement_fees(&order(100, 10, None)).unwrap();
+        assert_eq!(fees.maker_fee, 10);
+        assert_eq!(fees.taker_fee, 20);
+    }
+
+    #[test]
+    fn payment_token_base_overflow_is_rejected() {
+        set_schedule(FeeModel::Flat { fee_bps: 100 }, FeeAsset::PaymentToken);
+        assert!(matches!(
+            settlement_fees(&order(u64::MAX, 2, None)),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn treasury_overflow_is_rejected() {
+        let fees = |maker_fee, taker_fee| SettlementFees {
+            asset: FeeAsset::Credits,
+            maker_fee,
+            taker_fee,
+        };
+        assert!(matches!(
+            ensure_fees_collectable(&fees(u64::MAX, 1)),
+            Err(Error::InvalidPayload { .. })
+        ));
+
+        TREASURY_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                0,
+                Treasury {
+                    credits: u64::MAX - 10,
+                    payment_token_fees: 0,
+                },
+            )
+        });
+        assert!(ensure_fees_collectable(&fees(4, 6)).is_ok());
+        assert!(matches!(
+            ensure_fees_collectable(&fees(5, 6)),
+            Err(Error::InvalidPayload { .. })
+        ));
+        // the other asset has its own total
+        assert!(ensure_fees_collectable(&SettlementFees {
+            asset: FeeAsset::PaymentToken,
+            ..fees(5, 6)
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn fee_report_buckets_records_by_period() {
+        let start = 1_000 * SECOND;
+        insert_record(
+            1,
+            FeeSource::Settlement {
+                order_id: 1,
+                producer_id: 2,
+                client_id: 3,
+            },
+            FeeAsset::Credits,
+            5,
+            start,
+        );
+        insert_record(
+            2,
+            FeeSource::Transfer { transfer_id: 4 },
+            FeeAsset::Credits,
+            7,
+            start + SECOND - 1,
+        );
+        insert_record(
+            3,
+            FeeSource::Settlement {
+                order_id: 5,
+                producer_id: 2,
+                client_id: 3,
+            },
+            FeeAsset::PaymentToken,
+            11,
+            start + 2 * SECOND,
+        );
+        // outside of the report range
+        insert_record(
+            4,
+            FeeSource::Transfer { transfer_id: 6 },
+            FeeAsset::Credits,
+            13,
+            start - 1,
+        );
+        insert_record(
+            5,
+            FeeSource::Transfer { transfer_id: 7 },
+            FeeAsset::Credits,
+            17,
+            start + 5 * SECOND / 2,
+        );
+
+        let report = get_fee_report(FeeReportPayload {
+            start,
+            end: start + 5 * SECOND / 2,
+            period_seconds: 1,
+        })
+        .unwrap();
+        assert_eq!(report.len(), 3);
+
+        assert_eq!(report[0].start, start);
+        assert_eq!(report[0].end, start + SECOND);
+        assert_eq!(report[0].settlements, 1);
+        assert_eq!(report[0].transfers, 1);
+        assert_eq!(report[0].credits_collected, 12);
+        assert_eq!(report[0].payment_token_collected, 0);
+
+        assert_eq!(report[1].settlements + report[1].transfers, 0);
+
+        // the last period is cut at the report end
+        assert_eq!(report[2].start, start + 2 * SECOND);
+        assert_eq!(report[2].end, start + 5 * SECOND / 2);
+        assert_eq!(report[2].settlements, 1);
+        assert_eq!(report[2].transfers, 0);
+        assert_eq!(report[2].credits_collected, 0);
+        assert_eq!(report[2].payment_token_collected, 11);
+    }
+
+    #[test]
+    fn fee_report_rejects_invalid_ranges() {
+        let report = |start, end, period_seconds| {
+            get_fee_report(FeeReportPayload {
+                start,
+                end,
+                period_seconds,
+            })
+        };
+        assert!(report(10, 10, 1).is_err());
+        assert!(report(10, 20, 0).is_err());
+        assert!(report(0, (MAX_REPORT_PERIODS + 1) * SECOND, 1).is_err());
+        assert!(report(0, MAX_REPORT_PERIODS * SECOND, 1).is_ok());
+    }
+}
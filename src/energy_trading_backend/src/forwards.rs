@@ -0,0 +1,590 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+use validator::Validate;
+
+use crate::{
+    add_credit_to_client, add_credit_to_producer, authorize_admin, authorize_producer,
+    check_bid_risk, deduct_credit_from_producer, ensure_can_trade, get_facility_record, idempotent,
+    next_id, notify, record_activity, settle_direct_sale, truncate_bytes, vintage_year, AccountRef,
+    Error, Memory, CLIENT_STORAGE, MEMORY_MANAGER, PRODUCER_STORAGE,
+};
+
+const DEFAULT_COLLATERAL_PERCENT: u64 = 10;
+const NOTIFICATION_TOPIC: &str = "forward";
+const CLOSE_RETRY_SECONDS: u64 = 3600;
+const MAX_ERROR_BYTES: usize = 128;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum ForwardStatus {
+    // offered by the producer, waiting for the client
+    #[default]
+    Proposed,
+    // accepted, credits are delivered from the producer mints
+    Active,
+    Delivered,
+    // under-delivered by the delivery date, the collateral share of the shortfall went to the client
+    Defaulted,
+    Cancelled,
+}
+
+// an agreement to deliver credits minted in the future at a fixed price
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ForwardContract {
+    id: u64,
+    producer_id: u64,
+    client_id: u64,
+    // only mints of this facility are delivered, any producer mint when not set
+    facility_id: Option<u64>,
+    credits: u64,
+    price_per_credit: u64,
+    delivery_date: u64,
+    // producer credits locked until the contract is delivered or defaulted
+    collateral: u64,
+    delivered: u64,
+    status: ForwardStatus,
+    created_at: u64,
+    closed_at: Option<u64>,
+    // why the contract could not be closed at its delivery date, it is retried later
+    last_error: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ForwardSettings {
+    // share of the contracted credits the producer locks as collateral
+    collateral_percent: u64,
+}
+
+impl Storable for ForwardContract {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for ForwardSettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ForwardContract {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for ForwardSettings {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static FORWARD_STORAGE: RefCell<StableBTreeMap<u64, ForwardContract, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
+
+    static FORWARD_SETTINGS_STORAGE: RefCell<StableBTreeMap<u64, ForwardSettings, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct ForwardPayload {
+    producer_id: u64,
+    producer_password: String,
+    client_id: u64,
+    facility_id: Option<u64>,
+    #[validate(range(min = 1))]
+    credits: u64,
+    #[validate(range(min = 1))]
+    price_per_credit: u64,
+    delivery_date: u64,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct AcceptForwardPayload {
+    client_id: u64,
+    forward_id: u64,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct CancelForwardPayload {
+    producer_id: u64,
+    producer_password: String,
+    forward_id: u64,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ForwardSettingsPayload {
+    contract_password: String,
+    collateral_percent: u64,
+    idempotency_key: Option<String>,
+}
+
+fn forward_settings() -> ForwardSettings {
+    FORWARD_SETTINGS_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .unwrap_or(ForwardSettings {
+            collateral_percent: DEFAULT_COLLATERAL_PERCENT,
+        })
+}
+
+fn get_forward_record(forward_id: u64) -> Result<ForwardContract, Error> {
+    FORWARD_STORAGE
+        .with(|s| s.borrow().get(&forward_id))
+        .ok_or(Error::NotFound {
+            msg: format!("forward contract with id: {} not found", forward_id),
+        })
+}
+
+fn save_forward(forward: &ForwardContract) {
+    FORWARD_STORAGE.with(|s| s.borrow_mut().insert(forward.id, forward.clone()));
+}
+
+fn schedule_delivery_deadline(forward_id: u64, delivery_date: u64) {
+    let delay = Duration::from_nanos(delivery_date.saturating_sub(ic_cdk::api::time()));
+    ic_cdk_timers::set_timer(delay, move || close_at_delivery_date(forward_id));
+}
+
+fn notify_parties(forward: &ForwardContract, message: String) {
+    notify(
+        AccountRef::Client {
+            id: forward.client_id,
+        },
+        NOTIFICATION_TOPIC,
+        forward.id,
+        message.clone(),
+    );
+    notify(
+        AccountRef::Producer {
+            id: forward.producer_id,
+        },
+        NOTIFICATION_TOPIC,
+        forward.id,
+        message,
+    );
+}
+
+// the collateral owed to the client for the credits not delivered, rounded up
+fn defaulted_collateral(forward: &ForwardContract) -> u64 {
+    let undelivered = (forward.credits - forward.delivered) as u128;
+    (forward.collateral as u128 * undelivered).div_ceil(forward.credits as u128) as u64
+}
+
+// the collateral paid to a client for defaulted contracts, as (closed at, producer id, credits)
+pub(crate) fn client_forward_compensation(client_id: u64) -> Vec<(u64, u64, u64)> {
+    FORWARD_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, forward)| forward)
+            .filter(|forward| {
+                forward.client_id == client_id && forward.status == ForwardStatus::Defaulted
+            })
+            .map(|forward| {
+                (
+                    forward.closed_at.unwrap_or(forward.delivery_date),
+                    forward.producer_id,
+                    defaulted_collateral(&forward),
+                )
+            })
+            .collect()
+    })
+}
+
+// return the collateral of an unaccepted proposal, or split it between the client and the
+// producer when an active contract was not fully delivered
+fn pay_out_collateral(forward: &ForwardContract) -> Result<ForwardStatus, Error> {
+    // both accounts are checked first so the collateral is never paid out halfway
+    if !PRODUCER_STORAGE.with(|s| s.borrow().contains_key(&forward.producer_id)) {
+        return Err(Error::NotFound {
+            msg: "Producer not found".to_string(),
+        });
+    }
+    match forward.status {
+        ForwardStatus::Proposed => {
+            add_credit_to_producer(forward.producer_id, forward.collateral)?;
+            Ok(ForwardStatus::Cancelled)
+        }
+        _ => {
+            if !CLIENT_STORAGE.with(|s| s.borrow().contains_key(&forward.client_id)) {
+                return Err(Error::NotFound {
+                    msg: "Client not found".to_string(),
+                });
+            }
+            // the client is compensated for the shortfall, the rest is returned to the producer
+            let forfeited = defaulted_collateral(forward);
+            add_credit_to_client(Some(forward.client_id), forfeited)?;
+            if let Err(e) =
+                add_credit_to_producer(forward.producer_id, forward.collateral - forfeited)
+            {
+                // trapping discards the compensation already credited to the client
+                ic_cdk::trap(&format!(
+                    "Returning the collateral of forward contract {} failed: {:?}",
+                    forward.id, e
+                ));
+            }
+            Ok(ForwardStatus::Defaulted)
+        }
+    }
+}
+
+// expire unaccepted proposals and default active contracts that were not fully delivered
+fn close_at_delivery_date(forward_id: u64) {
+    let Ok(forward) = get_forward_record(forward_id) else {
+        return;
+    };
+    if !matches!(
+        forward.status,
+        ForwardStatus::Proposed | ForwardStatus::Active
+    ) {
+        return;
+    }
+    match pay_out_collateral(&forward) {
+        Ok(status) => {
+            let message = match status {
+                ForwardStatus::Cancelled => format!(
+                    "Forward contract {} expired unaccepted, its collateral was returned to the producer",
+                    forward.id
+                ),
+                _ => format!(
+                    "Forward contract {} defaulted with {} of {} credits delivered, the client received {} credits of collateral",
+                    forward.id,
+                    forward.delivered,
+                    forward.credits,
+                    defaulted_collateral(&forward)
+                ),
+            };
+            let forward = ForwardContract {
+                status,
+                closed_at: Some(ic_cdk::api::time()),
+                last_error: None,
+                ..forward
+            };
+            save_forward(&forward);
+            notify_parties(&forward, message);
+        }
+        Err(e) => {
+            // the contract stays open and the payout is tried again later
+            save_forward(&ForwardContract {
+                last_error: Some(truncate_bytes(format!("{:?}", e), MAX_ERROR_BYTES)),
+                ..forward
+            });
+            ic_cdk_timers::set_timer(Duration::from_secs(CLOSE_RETRY_SECONDS), move || {
+                close_at_delivery_date(forward_id)
+            });
+        }
+    }
+}
+
+// deliver freshly minted credits to the active forward contracts of the producer, earliest
+// delivery date first, whatever is left stays with the producer
+pub(crate) fn deliver_forwards(
+    producer_id: u64,
+    facility_id: Option<u64>,
+    vintage: Option<u32>,
+    credits: u64,
+) {
+    let mut forwards: Vec<ForwardContract> = FORWARD_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, forward)| forward)
+            .filter(|forward| {
+                forward.producer_id == producer_id
+                    && forward.status == ForwardStatus::Active
+                    && (forward.facility_id.is_none() || forward.facility_id == facility_id)
+            })
+            .collect()
+    });
+    forwards.sort_by_key(|forward| (forward.delivery_date, forward.id));
+
+    let mut remaining = credits;
+    for forward in forwards {
+        if remaining == 0 {
+            break;
+        }
+        let delivery = remaining.min(forward.credits - forward.delivered);
+        // each delivery settles as an order at the contract price, with the vintage of the mint
+        if let Err(e) = settle_direct_sale(
+            producer_id,
+            forward.client_id,
+            delivery,
+            forward.price_per_credit,
+            facility_id,
+            vintage.or(Some(vintage_year(ic_cdk::api::time()))),
+        ) {
+            // the credits stay with the producer, the parties are told the delivery failed
+            notify_parties(
+                &forward,
+                format!(
+                    "Delivery of {} credits on forward contract {} failed: {:?}",
+                    delivery, forward.id, e
+                ),
+            );
+            continue;
+        }
+        remaining -= delivery;
+        let delivered = forward.delivered + delivery;
+        let forward = match delivered == forward.credits {
+            // fully delivered, release the collateral
+            true => {
+                let _ = add_credit_to_producer(producer_id, forward.collateral);
+                ForwardContract {
+                    delivered,
+                    status: ForwardStatus::Delivered,
+                    closed_at: Some(ic_cdk::api::time()),
+                    ..forward
+                }
+            }
+            false => ForwardContract {
+                delivered,
+                ..forward
+            },
+        };
+        save_forward(&forward);
+    }
+}
+
+// re-arm the delivery deadlines of open forward contracts
+pub(crate) fn restore_forward_timers() {
+    FORWARD_STORAGE.with(|s| {
+        for (id, forward) in s.borrow().iter() {
+            if matches!(
+                forward.status,
+                ForwardStatus::Proposed | ForwardStatus::Active
+            ) {
+                schedule_delivery_deadline(id, forward.delivery_date);
+            }
+        }
+    });
+}
+
+// offer a client a forward contract, locking the collateral from the producer credits
+#[ic_cdk::update]
+fn propose_forward(payload: ForwardPayload) -> Result<ForwardContract, Error> {
+    idempotent(
+        "propose_forward",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            let producer = authorize_producer(payload.producer_id, &payload.producer_password)?;
+            ensure_can_trade(AccountRef::Producer { id: producer.id })?;
+            if !CLIENT_STORAGE.with(|s| s.borrow().contains_key(&payload.client_id)) {
+                return Err(Error::NotFound {
+                    msg: "Client not found".to_string(),
+                });
+            }
+            if let Some(facility_id) = payload.facility_id {
+                if get_facility_record(facility_id)?.producer_id != producer.id {
+                    return Err(Error::Unauthorized {
+                        msg: "Facility does not belong to producer".to_string(),
+                    });
+                }
+            }
+            let now = ic_cdk::api::time();
+            if payload.delivery_date <= now {
+                return Err(Error::InvalidPayload {
+                    msg: "Delivery date must be in the future".to_string(),
+                });
+            }
+            // rounded up so every contract carries some collateral
+            let Some(collateral) = payload
+                .credits
+                .checked_mul(forward_settings().collateral_percent)
+                .map(|collateral| collateral.div_ceil(100))
+            else {
+                return Err(Error::InvalidPayload {
+                    msg: "Forward contract is too large to collateralize".to_string(),
+                });
+            };
+            deduct_credit_from_producer(producer.id, collateral).map_err(|_| {
+                Error::InvalidPayload {
+                    msg: format!(
+                        "Producer needs {} credits available as collateral",
+                        collateral
+                    ),
+                }
+            })?;
+
+            let forward = ForwardContract {
+                id: next_id(),
+                producer_id: producer.id,
+                client_id: payload.client_id,
+                facility_id: payload.facility_id,
+                credits: payload.credits,
+                price_per_credit: payload.price_per_credit,
+                delivery_date: payload.delivery_date,
+                collateral,
+                delivered: 0,
+                status: ForwardStatus::Proposed,
+                created_at: now,
+                closed_at: None,
+                last_error: None,
+            };
+            save_forward(&forward);
+            schedule_delivery_deadline(forward.id, forward.delivery_date);
+            Ok(forward)
+        },
+    )
+}
+
+// accept a proposed forward contract, future mints of the producer are delivered to the client
+#[ic_cdk::update]
+fn accept_forward(payload: AcceptForwardPayload) -> Result<ForwardContract, Error> {
+    idempotent(
+        "accept_forward",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            let forward = get_forward_record(payload.forward_id)?;
+            if forward.client_id != payload.client_id {
+                return Err(Error::Unauthorized {
+                    msg: "Forward contract was not offered to client".to_string(),
+                });
+            }
+            if forward.status != ForwardStatus::Proposed {
+                return Err(Error::InvalidPayload {
+                    msg: "Forward contract is not awaiting acceptance".to_string(),
+                });
+            }
+            ensure_can_trade(AccountRef::Client {
+                id: payload.client_id,
+            })?;
+            check_bid_risk(payload.client_id, forward.producer_id)?;
+            let forward = ForwardContract {
+                status: ForwardStatus::Active,
+                ..forward
+            };
+            save_forward(&forward);
+            record_activity(AccountRef::Client {
+                id: payload.client_id,
+            });
+            Ok(forward)
+        },
+    )
+}
+
+// withdraw a forward contract the client has not accepted yet, refunding the collateral
+#[ic_cdk::update]
+fn cancel_forward(payload: CancelForwardPayload) -> Result<ForwardContract, Error> {
+    idempotent(
+        "cancel_forward",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_producer(payload.producer_id, &payload.producer_password)?;
+            let forward = get_forward_record(payload.forward_id)?;
+            if forward.producer_id != payload.producer_id {
+                return Err(Error::Unauthorized {
+                    msg: "Forward contract does not belong to producer".to_string(),
+                });
+            }
+            if forward.status != ForwardStatus::Proposed {
+                return Err(Error::InvalidPayload {
+                    msg: "Only proposed forward contracts can be cancelled".to_string(),
+                });
+            }
+            add_credit_to_producer(forward.producer_id, forward.collateral)?;
+            let forward = ForwardContract {
+                status: ForwardStatus::Cancelled,
+                closed_at: Some(ic_cdk::api::time()),
+                ..forward
+            };
+            save_forward(&forward);
+            Ok(forward)
+        },
+    )
+}
+
+// set the collateral share producers lock on new forward contracts
+#[ic_cdk::update]
+fn set_forward_settings(payload: ForwardSettingsPayload) -> Result<ForwardSettings, Error> {
+    idempotent(
+        "set_forward_settings",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_admin(&payload.contract_password)?;
+            if payload.collateral_percent > 100 {
+                return Err(Error::InvalidPayload {
+                    msg: "Collateral cannot exceed 100 percent".to_string(),
+                });
+            }
+            let settings = ForwardSettings {
+                collateral_percent: payload.collateral_percent,
+            };
+            FORWARD_SETTINGS_STORAGE.with(|s| s.borrow_mut().insert(0, settings.clone()));
+            Ok(settings)
+        },
+    )
+}
+
+// get the forward contract collateral settings
+#[ic_cdk::query]
+fn get_forward_settings() -> ForwardSettings {
+    forward_settings()
+}
+
+// get a forward contract
+#[ic_cdk::query]
+fn get_forward(id: u64) -> Result<ForwardContract, Error> {
+    get_forward_record(id)
+}
+
+// get the forward contracts of a client or producer
+#[ic_cdk::query]
+fn get_account_forwards(account: AccountRef) -> Vec<ForwardContract> {
+    FORWARD_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, forward)| forward)
+            .filter(|forward| match account {
+                AccountRef::Client { id } => forward.client_id == id,
+                AccountRef::Producer { id } => forward.producer_id == id,
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn largest_forward_contract_fits() {
+        let forward = ForwardContract {
+            id: u64::MAX,
+            producer_id: u64::MAX,
+            client_id: u64::MAX,
+            facility_id: Some(u64::MAX),
+            credits: u64::MAX,
+            price_per_credit: u64::MAX,
+            delivery_date: u64::MAX,
+            collateral: u64::MAX,
+            delivered: u64::MAX,
+            status: ForwardStatus::Defaulted,
+            created_at: u64::MAX,
+            closed_at: Some(u64::MAX),
+            last_error: Some(truncate_bytes("\u{10ffff}".repeat(1000), MAX_ERROR_BYTES)),
+        };
+        assert!(forward.to_bytes().len() <= ForwardContract::MAX_SIZE as usize);
+    }
+}
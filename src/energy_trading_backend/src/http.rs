@@ -0,0 +1,389 @@
+use serde_json::{json, Value};
+
+use crate::{
+    display_name, get_market_stats, get_price_stats, AccountRef, CreditOrder, OrderStatus,
+    Producer, Trade, CLIENT_STORAGE, CREDIT_ORDER_STORAGE, PRODUCER_STORAGE, TRADE_STORAGE,
+};
+
+const DEFAULT_PAGE_LIMIT: usize = 50;
+const MAX_PAGE_LIMIT: usize = 200;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+// query string parameters of a request, percent-decoded
+struct QueryParams(Vec<(String, String)>);
+
+// decode a percent-encoded query string component, '+' stands for a space
+fn percent_decode(component: &str) -> Result<String, String> {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let byte = bytes
+                    .get(index + 1..index + 3)
+                    .and_then(|hex| hex::decode(hex).ok())
+                    .ok_or_else(|| format!("invalid percent-encoding in {}", component))?;
+                decoded.extend(byte);
+                index += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8(decoded).map_err(|_| format!("invalid percent-encoding in {}", component))
+}
+
+impl QueryParams {
+    fn parse(query: &str) -> Result<Self, String> {
+        query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Ok((percent_decode(key)?, percent_decode(value)?))
+            })
+            .collect::<Result<_, String>>()
+            .map(QueryParams)
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn get_u64(&self, name: &str) -> Result<Option<u64>, String> {
+        match self.get(name) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("query parameter {} must be a number", name)),
+            None => Ok(None),
+        }
+    }
+
+    // offset and limit of the requested page
+    fn page(&self) -> Result<(usize, usize), String> {
+        let offset = usize::try_from(self.get_u64("offset")?.unwrap_or(0))
+            .map_err(|_| "offset is too large".to_string())?;
+        let limit = match self.get_u64("limit")? {
+            Some(limit) => usize::try_from(limit).unwrap_or(usize::MAX),
+            None => DEFAULT_PAGE_LIMIT,
+        };
+        if limit == 0 || limit > MAX_PAGE_LIMIT {
+            return Err(format!("limit must be between 1 and {}", MAX_PAGE_LIMIT));
+        }
+        Ok((offset, limit))
+    }
+}
+
+fn response(status_code: u16, body: Value) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            (
+                "Content-Type".to_string(),
+                "application/json; charset=utf-8".to_string(),
+            ),
+            ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+            (
+                "Access-Control-Allow-Methods".to_string(),
+                "GET, OPTIONS".to_string(),
+            ),
+            (
+                "Access-Control-Allow-Headers".to_string(),
+                "Content-Type".to_string(),
+            ),
+        ],
+        body: serde_json::to_vec(&body).unwrap_or_default(),
+    }
+}
+
+fn error_response(status_code: u16, msg: &str) -> HttpResponse {
+    response(status_code, json!({ "error": msg }))
+}
+
+// slice the matching items into the requested page
+fn paginate(items: Vec<Value>, params: &QueryParams) -> Result<Value, String> {
+    let (offset, limit) = params.page()?;
+    let total = items.len();
+    let items: Vec<Value> = items.into_iter().skip(offset).take(limit).collect();
+    Ok(json!({
+        "items": items,
+        "total": total,
+        "offset": offset,
+        "limit": limit,
+    }))
+}
+
+fn order_status_name(status: OrderStatus) -> &'static str {
+    match status {
+        OrderStatus::Open => "open",
+        OrderStatus::Frozen => "frozen",
+        OrderStatus::Paid => "paid",
+        OrderStatus::Cancelled => "cancelled",
+    }
+}
+
+fn order_json(credit_order: &CreditOrder) -> Value {
+    json!({
+        "id": credit_order.id,
+        "producer_id": credit_order.producer_id,
+        "client_id": credit_order.client_id,
+        "credits": credit_order.credits,
+        "order_type": credit_order.order_type.name(),
+        "min_offer_per_credit": credit_order.min_offer_per_credit,
+        "high_bid": credit_order.high_bid,
+        "ask_price": credit_order.ask_price,
+        "status": order_status_name(credit_order.status),
+        "paid": credit_order.paid,
+    })
+}
+
+// public producer fields, the producer is only known by its display name
+fn producer_json(producer: &Producer) -> Value {
+    json!({
+        "id": producer.id,
+        "display_name": display_name(AccountRef::Producer { id: producer.id }),
+    })
+}
+
+fn trade_json(trade: &Trade) -> Value {
+    json!({
+        "id": trade.id,
+        "order_id": trade.order_id,
+        "producer_id": trade.producer_id,
+        "client_id": trade.client_id,
+        "credits": trade.credits,
+        "price_per_credit": trade.price_per_credit,
+        "settled_at": trade.settled_at,
+        "reversed_at": trade.reversed_at,
+    })
+}
+
+// filters: status, producer_id, client_id
+fn list_orders(params: &QueryParams) -> Result<Value, String> {
+    let status = params.get("status");
+    let producer_id = params.get_u64("producer_id")?;
+    let client_id = params.get_u64("client_id")?;
+    let orders = CREDIT_ORDER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, credit_order)| credit_order)
+            .filter(|credit_order| {
+                status.is_none_or(|status| {
+                    order_status_name(credit_order.status).eq_ignore_ascii_case(status)
+                }) && producer_id.is_none_or(|id| credit_order.producer_id == id)
+                    && client_id.is_none_or(|id| credit_order.client_id == Some(id))
+            })
+            .map(|credit_order| order_json(&credit_order))
+            .collect()
+    });
+    paginate(orders, params)
+}
+
+// producers are listed by display name only, like their public profile
+fn list_producers(params: &QueryParams) -> Result<Value, String> {
+    let producers = PRODUCER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, producer)| producer_json(&producer))
+            .collect()
+    });
+    paginate(producers, params)
+}
+
+// filters: producer_id, client_id, since (nanoseconds)
+fn list_trades(params: &QueryParams) -> Result<Value, String> {
+    let producer_id = params.get_u64("producer_id")?;
+    let client_id = params.get_u64("client_id")?;
+    let since = params.get_u64("since")?.unwrap_or(0);
+    let trades = TRADE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, trade)| trade)
+            .filter(|trade| {
+                trade.settled_at >= since
+                    && producer_id.is_none_or(|id| trade.producer_id == id)
+                    && client_id.is_none_or(|id| trade.client_id == id)
+            })
+            .map(|trade| trade_json(&trade))
+            .collect()
+    });
+    paginate(trades, params)
+}
+
+fn market_stats() -> Value {
+    let (orders, open_orders, credits_in_escrow) = CREDIT_ORDER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .fold((0u64, 0u64, 0u64), |(orders, open, escrow), (_, order)| {
+                (
+                    orders + 1,
+                    open + (order.status == OrderStatus::Open) as u64,
+                    escrow + order.escrow,
+                )
+            })
+    });
+    let (producers, producer_credits) = PRODUCER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .fold((0u64, 0u64), |(count, credits), (_, producer)| {
+                (count + 1, credits + producer.credits)
+            })
+    });
+    let (clients, client_credits) = CLIENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .fold((0u64, 0u64), |(count, credits), (_, client)| {
+                (count + 1, credits + client.credits)
+            })
+    });
+    let (trades, traded_credits) = TRADE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, trade)| trade.reversed_at.is_none())
+            .fold((0u64, 0u64), |(count, credits), (_, trade)| {
+                (count + 1, credits + trade.credits)
+            })
+    });
+    json!({
+        "producers": producers,
+        "clients": clients,
+        "orders": orders,
+        "open_orders": open_orders,
+        "credits_in_escrow": credits_in_escrow,
+        "producer_credits": producer_credits,
+        "client_credits": client_credits,
+        "trades": trades,
+        "traded_credits": traded_credits,
+        "market": get_market_stats(),
+        "price_24h": get_price_stats(24 * 60 * 60),
+    })
+}
+
+fn route(path: &str, params: &QueryParams) -> HttpResponse {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let result = match segments.as_slice() {
+        ["orders"] => list_orders(params),
+        ["orders", id] => {
+            let Ok(id) = id.parse::<u64>() else {
+                return error_response(400, "order id must be a number");
+            };
+            match CREDIT_ORDER_STORAGE.with(|s| s.borrow().get(&id)) {
+                Some(credit_order) => Ok(order_json(&credit_order)),
+                None => return error_response(404, "credit order not found"),
+            }
+        }
+        ["producers"] => list_producers(params),
+        ["stats"] => Ok(market_stats()),
+        ["trades"] => list_trades(params),
+        _ => return error_response(404, "not found"),
+    };
+    match result {
+        Ok(body) => response(200, body),
+        Err(msg) => error_response(400, &msg),
+    }
+}
+
+// serve public market data as JSON to non-IC clients through the HTTP gateway
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    match request.method.to_ascii_uppercase().as_str() {
+        "GET" => {}
+        "OPTIONS" => {
+            // CORS preflight
+            return HttpResponse {
+                body: Vec::new(),
+                ..response(204, Value::Null)
+            };
+        }
+        _ => return error_response(405, "method not allowed"),
+    }
+    let (path, query) = request.url.split_once('?').unwrap_or((&request.url, ""));
+    match QueryParams::parse(query) {
+        Ok(params) => route(path, &params),
+        Err(msg) => error_response(400, &msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(query: &str) -> Result<(usize, usize), String> {
+        QueryParams::parse(query)?.page()
+    }
+
+    #[test]
+    fn percent_escapes_are_decoded() {
+        assert_eq!(percent_decode("a%20b%2Bc").unwrap(), "a b+c");
+        assert_eq!(percent_decode("%e2%82%ac").unwrap(), "\u{20ac}");
+        assert_eq!(percent_decode("").unwrap(), "");
+    }
+
+    #[test]
+    fn plus_stands_for_a_space() {
+        assert_eq!(percent_decode("solar+farm").unwrap(), "solar farm");
+        assert_eq!(percent_decode("++").unwrap(), "  ");
+        let params = QueryParams::parse("status=open+now&a+b=c").unwrap();
+        assert_eq!(params.get("status"), Some("open now"));
+        assert_eq!(params.get("a b"), Some("c"));
+    }
+
+    #[test]
+    fn malformed_escapes_are_rejected() {
+        for component in ["%", "%4", "%zz", "%4g", "a%", "%%41", "%\u{e9}"] {
+            assert!(percent_decode(component).is_err(), "{}", component);
+        }
+        assert!(QueryParams::parse("status=%zz").is_err());
+        assert!(QueryParams::parse("%=open").is_err());
+    }
+
+    #[test]
+    fn invalid_utf8_is_rejected() {
+        assert!(percent_decode("%ff").is_err());
+        assert!(percent_decode("%c3").is_err());
+        assert!(percent_decode("%c3%28").is_err());
+        assert!(percent_decode("%c3%a9").is_ok());
+    }
+
+    #[test]
+    fn page_bounds_are_checked() {
+        assert_eq!(page("").unwrap(), (0, DEFAULT_PAGE_LIMIT));
+        assert_eq!(page("offset=10&limit=200").unwrap(), (10, MAX_PAGE_LIMIT));
+        assert!(page("limit=0").is_err());
+        assert!(page("limit=201").is_err());
+        assert!(page(&format!("limit={}", u64::MAX)).is_err());
+        // numbers beyond u64 are rejected rather than wrapped
+        assert!(page("limit=18446744073709551616").is_err());
+        assert!(page("offset=18446744073709551616").is_err());
+        assert!(page("offset=-1").is_err());
+        assert!(page("offset=ten").is_err());
+    }
+
+    #[test]
+    fn oversized_offset_returns_an_empty_page() {
+        let params = QueryParams::parse(&format!("offset={}", u64::MAX)).unwrap();
+        let page = paginate(vec![json!(1), json!(2)], &params).unwrap();
+        assert_eq!(page["items"], json!([]));
+        assert_eq!(page["total"], 2);
+    }
+}
@@ -0,0 +1,294 @@
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{authorize_admin, next_id, Error, Memory, MEMORY_MANAGER};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const DEFAULT_RETENTION_SECONDS: u64 = 24 * 60 * 60;
+const MAX_KEY_LENGTH: usize = 64;
+// stored responses are split into chunks so large results do not bound every entry
+const CHUNK_SIZE: usize = 1024;
+// expired responses removed per call, keeps the pruning cost of a call bounded
+const MAX_PRUNED_PER_CALL: usize = 100;
+
+// idempotency keys are scoped to the caller and the method they were used with
+#[derive(
+    candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+struct IdempotencyKey {
+    method: String,
+    key: String,
+    // None for keys recorded before they were scoped to the caller, these expire unmatched
+    caller: Option<Principal>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct IdempotencyRecord {
+    response_id: u64,
+    chunks: u64,
+    recorded_at: u64,
+    // SHA-256 of the candid encoded payload, None for responses recorded before payloads were
+    // hashed
+    payload_hash: Option<Vec<u8>>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct IdempotencySettings {
+    retention_seconds: u64,
+}
+
+// a slice of a candid encoded response
+struct ResponseChunk(Vec<u8>);
+
+impl Storable for IdempotencyKey {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for IdempotencyRecord {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for IdempotencySettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for ResponseChunk {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        ResponseChunk(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for IdempotencyKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for IdempotencyRecord {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for IdempotencySettings {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for ResponseChunk {
+    const MAX_SIZE: u32 = CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static IDEMPOTENCY_STORAGE: RefCell<StableBTreeMap<IdempotencyKey, IdempotencyRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    // (recorded at, response id) -> key, oldest responses first for pruning
+    static IDEMPOTENCY_EXPIRY_STORAGE: RefCell<StableBTreeMap<(u64, u64), IdempotencyKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    // (response id, chunk index) -> chunk
+    static RESPONSE_CHUNK_STORAGE: RefCell<StableBTreeMap<(u64, u64), ResponseChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    static IDEMPOTENCY_SETTINGS_STORAGE: RefCell<StableBTreeMap<u64, IdempotencySettings, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct IdempotencySettingsPayload {
+    contract_password: String,
+    retention_seconds: u64,
+    idempotency_key: Option<String>,
+}
+
+fn idempotency_settings() -> IdempotencySettings {
+    IDEMPOTENCY_SETTINGS_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .unwrap_or(IdempotencySettings {
+            retention_seconds: DEFAULT_RETENTION_SECONDS,
+        })
+}
+
+fn remove_response(key: &IdempotencyKey, record: &IdempotencyRecord) {
+    IDEMPOTENCY_STORAGE.with(|s| s.borrow_mut().remove(key));
+    IDEMPOTENCY_EXPIRY_STORAGE.with(|s| {
+        s.borrow_mut()
+            .remove(&(record.recorded_at, record.response_id))
+    });
+    RESPONSE_CHUNK_STORAGE.with(|s| {
+        for index in 0..record.chunks {
+            s.borrow_mut().remove(&(record.response_id, index));
+        }
+    });
+}
+
+// drop the oldest responses that are past the retention window
+fn prune_expired() {
+    let retention = idempotency_settings()
+        .retention_seconds
+        .saturating_mul(NANOS_PER_SECOND);
+    let cutoff = ic_cdk::api::time().saturating_sub(retention);
+    let expired: Vec<IdempotencyKey> = IDEMPOTENCY_EXPIRY_STORAGE.with(|s| {
+        s.borrow()
+            .range(..(cutoff, 0))
+            .take(MAX_PRUNED_PER_CALL)
+            .map(|(_, key)| key)
+            .collect()
+    });
+    for key in expired {
+        if let Some(record) = IDEMPOTENCY_STORAGE.with(|s| s.borrow().get(&key)) {
+            remove_response(&key, &record);
+        }
+    }
+}
+
+fn load_response<T: CandidType + DeserializeOwned>(record: &IdempotencyRecord) -> T {
+    let bytes: Vec<u8> = RESPONSE_CHUNK_STORAGE.with(|s| {
+        s.borrow()
+            .range((record.response_id, 0)..(record.response_id, record.chunks))
+            .flat_map(|(_, chunk)| chunk.0)
+            .collect()
+    });
+    Decode!(&bytes, T).unwrap()
+}
+
+fn store_response<T: CandidType>(key: IdempotencyKey, payload_hash: Vec<u8>, response: &T) {
+    let bytes = Encode!(response).unwrap();
+    let response_id = next_id();
+    let mut chunks = 0;
+    RESPONSE_CHUNK_STORAGE.with(|s| {
+        for chunk in bytes.chunks(CHUNK_SIZE) {
+            s.borrow_mut()
+                .insert((response_id, chunks), ResponseChunk(chunk.to_vec()));
+            chunks += 1;
+        }
+    });
+    let recorded_at = ic_cdk::api::time();
+    IDEMPOTENCY_EXPIRY_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert((recorded_at, response_id), key.clone())
+    });
+    IDEMPOTENCY_STORAGE.with(|s| {
+        s.borrow_mut().insert(
+            key,
+            IdempotencyRecord {
+                response_id,
+                chunks,
+                recorded_at,
+                payload_hash: Some(payload_hash),
+            },
+        )
+    });
+}
+
+// run an update once per caller and idempotency key, replays of the same payload within the
+// retention window return the original response and reusing the key for a different payload is
+// rejected, failed calls are not recorded so they can be retried with the same key
+// the payload is compared by its own encoding, so calls nested in a batch are told apart
+pub(crate) fn idempotent<P: CandidType, T: CandidType + DeserializeOwned>(
+    method: &str,
+    key: Option<String>,
+    payload: P,
+    call: impl FnOnce(P) -> Result<T, Error>,
+) -> Result<T, Error> {
+    prune_expired();
+    let Some(key) = key else {
+        return call(payload);
+    };
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Idempotency key must be between 1 and {} bytes",
+                MAX_KEY_LENGTH
+            ),
+        });
+    }
+    let key = IdempotencyKey {
+        method: method.to_string(),
+        key,
+        caller: Some(ic_cdk::caller()),
+    };
+    let payload_hash = Sha256::digest(Encode!(&payload).unwrap()).to_vec();
+    if let Some(record) = IDEMPOTENCY_STORAGE.with(|s| s.borrow().get(&key)) {
+        if record
+            .payload_hash
+            .as_ref()
+            .is_some_and(|hash| *hash != payload_hash)
+        {
+            return Err(Error::InvalidPayload {
+                msg: "Idempotency key was already used with a different payload".to_string(),
+            });
+        }
+        return Ok(load_response(&record));
+    }
+    let response = call(payload)?;
+    store_response(key, payload_hash, &response);
+    Ok(response)
+}
+
+// set how long responses are kept for replay
+#[ic_cdk::update]
+fn set_idempotency_settings(
+    payload: IdempotencySettingsPayload,
+) -> Result<IdempotencySettings, Error> {
+    idempotent(
+        "set_idempotency_settings",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_admin(&payload.contract_password)?;
+            if payload.retention_seconds == 0 {
+                return Err(Error::InvalidPayload {
+                    msg: "Retention window must be at least one second".to_string(),
+                });
+            }
+            let settings = IdempotencySettings {
+                retention_seconds: payload.retention_seconds,
+            };
+            IDEMPOTENCY_SETTINGS_STORAGE.with(|s| s.borrow_mut().insert(0, settings.clone()));
+            Ok(settings)
+        },
+    )
+}
+
+// get the idempotency retention window
+#[ic_cdk::query]
+fn get_idempotency_settings() -> IdempotencySettings {
+    idempotency_settings()
+}
@@ -6,6 +6,63 @@ use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTree
 use std::{borrow::Cow, cell::RefCell};
 use validator::Validate;
 
+mod accounts;
+mod auctions;
+mod batch;
+mod bulk;
+mod certified;
+mod disputes;
+mod events;
+mod facilities;
+mod fees;
+mod forwards;
+mod http;
+mod idempotency;
+mod migrations;
+mod notifications;
+mod portfolio;
+mod privacy;
+mod profile;
+mod receipts;
+mod recurring;
+mod reports;
+mod retirements;
+mod rfq;
+mod risk;
+mod sealed_bids;
+mod stats;
+mod trades;
+mod transfers;
+mod verification;
+use accounts::*;
+use auctions::*;
+use batch::*;
+use bulk::*;
+use certified::*;
+use disputes::*;
+use events::*;
+use facilities::*;
+use fees::*;
+use forwards::*;
+use http::*;
+use idempotency::*;
+use migrations::*;
+use notifications::*;
+use portfolio::*;
+use privacy::*;
+use profile::*;
+use receipts::*;
+use recurring::*;
+use reports::*;
+use retirements::*;
+use rfq::*;
+use risk::*;
+use sealed_bids::*;
+use stats::*;
+use trades::*;
+use transfers::*;
+use verification::*;
+
 // Define type aliases for convenience
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
@@ -21,18 +78,33 @@ struct Contract {
 struct Client {
     id: u64,
     name: String,
+    organization_name: String,
+    legal_entity_id: String,
+    country_code: String,
+    email: String,
     phone: String,
+    // None for clients registered before they had credentials, they cannot send credits
+    password: Option<String>,
     credits: u64,
+    verification: VerificationStatus,
+    // accounts are soft deleted to keep their history for audits
+    deactivated_at: Option<u64>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Producer {
     id: u64,
     name: String,
+    organization_name: String,
+    legal_entity_id: String,
+    country_code: String,
+    email: String,
     password: String,
     phone: String,
     energy_supply: u64,
     credits: u64,
+    verification: VerificationStatus,
+    deactivated_at: Option<u64>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
@@ -41,8 +113,54 @@ struct CreditOrder {
     client_id: Option<u64>,
     producer_id: u64,
     credits: u64,
+    // reserve of an English auction, fixed price of a buy-it-now or floor of a Dutch auction
     min_offer_per_credit: u64,
+    // current high bid of an auction, or the price the order was bought at
+    high_bid: Option<u64>,
+    // price the order can be bought at right away, None for orders taking bids
+    ask_price: Option<u64>,
+    order_type: OrderType,
+    // facility and vintage year the credits were generated in, when declared by the producer
+    facility_id: Option<u64>,
+    vintage: Option<u32>,
     paid: bool,
+    // credits held back from the producer until the order is settled or cancelled
+    escrow: u64,
+    status: OrderStatus,
+    dispute_ids: Vec<u64>,
+    created_at: u64,
+}
+
+impl CreditOrder {
+    // the price per credit the order settles at
+    fn price_per_credit(&self) -> u64 {
+        self.high_bid.unwrap_or(self.min_offer_per_credit)
+    }
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+enum OrderStatus {
+    #[default]
+    Open,
+    // escrow is locked, no bids or settlement until the order is released
+    Frozen,
+    Paid,
+    Cancelled,
+}
+
+// reference to a client or producer account
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+enum AccountRef {
+    Client { id: u64 },
+    Producer { id: u64 },
+}
+
+impl Default for AccountRef {
+    fn default() -> Self {
+        AccountRef::Client { id: 0 }
+    }
 }
 
 // Implement the 'Storable' trait for Producer, Client and CreditOrder
@@ -51,9 +169,9 @@ impl Storable for Client {
     fn to_bytes(&self) -> Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
-    // Conversion from bytes
+    // Conversion from bytes, falling back to the layout clients had before the organization profile
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|_| decode_legacy_client(bytes.as_ref()))
     }
 }
 
@@ -62,13 +180,24 @@ impl Storable for Producer {
     fn to_bytes(&self) -> Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
-    // Conversion from bytes
+    // Conversion from bytes, falling back to the layout producers had before the organization profile
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|_| decode_legacy_producer(bytes.as_ref()))
     }
 }
 
 impl Storable for CreditOrder {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes, falling back to the layout orders had before escrow
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|_| decode_legacy_credit_order(bytes.as_ref()))
+    }
+}
+
+impl Storable for AccountRef {
     // Conversion to bytes
     fn to_bytes(&self) -> Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
@@ -106,6 +235,11 @@ impl BoundedStorable for CreditOrder {
     const IS_FIXED_SIZE: bool = false;
 }
 
+impl BoundedStorable for AccountRef {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 impl BoundedStorable for Contract {
     const MAX_SIZE: u32 = 1024;
     const IS_FIXED_SIZE: bool = false;
@@ -144,44 +278,101 @@ thread_local! {
 }
 
 // Define structs for payload data
+// text fields are bounded in bytes so the largest account fits its record, legal entity ids,
+// country codes and phone numbers are ASCII only
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
 struct ClientPayload {
-    #[validate(length(min = 3))]
+    #[validate(length(min = 3), custom = "validate_max_bytes::<128>")]
     name: String,
-    #[validate(length(min = 5))]
+    #[validate(length(min = 2), custom = "validate_max_bytes::<128>")]
+    organization_name: String,
+    #[validate(length(min = 3, max = 64), custom = "validate_legal_entity_id")]
+    legal_entity_id: String,
+    #[validate(custom = "validate_country_code")]
+    country_code: String,
+    #[validate(email, custom = "validate_max_bytes::<254>")]
+    email: String,
+    #[validate(custom = "validate_e164_phone")]
     phone: String,
+    #[validate(length(min = 4), custom = "validate_max_bytes::<128>")]
+    password: String,
+    idempotency_key: Option<String>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct ProducerEnergyPayload {
     contract_password: String,
     producer_id: u64,
+    // facility of the producer that generated the energy
+    facility_id: u64,
     energy_supply: u64,
+    // generation period in nanoseconds since the epoch
+    period_start: u64,
+    period_end: u64,
+    idempotency_key: Option<String>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct InitPayload {
     password: String,
     credit_per_energy: u64,
+    idempotency_key: Option<String>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
 struct UpdateClientPayload {
     id: u64,
-    #[validate(length(min = 3))]
+    #[validate(length(min = 3), custom = "validate_max_bytes::<128>")]
     name: String,
-    #[validate(length(min = 5))]
+    #[validate(length(min = 2), custom = "validate_max_bytes::<128>")]
+    organization_name: String,
+    #[validate(length(min = 3, max = 64), custom = "validate_legal_entity_id")]
+    legal_entity_id: String,
+    #[validate(custom = "validate_country_code")]
+    country_code: String,
+    #[validate(email, custom = "validate_max_bytes::<254>")]
+    email: String,
+    #[validate(custom = "validate_e164_phone")]
     phone: String,
+    idempotency_key: Option<String>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
 struct ProducerPayload {
-    #[validate(length(min = 3))]
+    #[validate(length(min = 3), custom = "validate_max_bytes::<128>")]
     name: String,
-    #[validate(length(min = 5))]
+    #[validate(length(min = 2), custom = "validate_max_bytes::<128>")]
+    organization_name: String,
+    #[validate(length(min = 3, max = 64), custom = "validate_legal_entity_id")]
+    legal_entity_id: String,
+    #[validate(custom = "validate_country_code")]
+    country_code: String,
+    #[validate(email, custom = "validate_max_bytes::<254>")]
+    email: String,
+    #[validate(custom = "validate_e164_phone")]
     phone: String,
-    #[validate(length(min = 4))]
+    #[validate(length(min = 4), custom = "validate_max_bytes::<128>")]
     password: String,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+struct UpdateProducerPayload {
+    id: u64,
+    password: String,
+    #[validate(length(min = 3), custom = "validate_max_bytes::<128>")]
+    name: String,
+    #[validate(length(min = 2), custom = "validate_max_bytes::<128>")]
+    organization_name: String,
+    #[validate(length(min = 3, max = 64), custom = "validate_legal_entity_id")]
+    legal_entity_id: String,
+    #[validate(custom = "validate_country_code")]
+    country_code: String,
+    #[validate(email, custom = "validate_max_bytes::<254>")]
+    email: String,
+    #[validate(custom = "validate_e164_phone")]
+    phone: String,
+    idempotency_key: Option<String>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
@@ -189,6 +380,12 @@ struct CreditOrderPayload {
     producer_id: u64,
     credits: u64,
     min_offer_per_credit: u64,
+    // English auction when not set
+    order_type: Option<OrderType>,
+    // optional source of the credits, the vintage is required with a facility
+    facility_id: Option<u64>,
+    vintage: Option<u32>,
+    idempotency_key: Option<String>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
@@ -196,197 +393,434 @@ struct BidPayload {
     client_id: u64,
     credit_order_id: u64,
     offer_per_credit: u64,
+    idempotency_key: Option<String>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct PaidPayload {
     order_id: u64,
     password: String,
+    idempotency_key: Option<String>,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct ProducerReturn {
-    id: u64,
-    name: String,
-    phone: String,
-    energy_supply: u64,
-    credits: u64,
+// get the next id from the shared id counter
+fn next_id() -> u64 {
+    ID_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids")
+}
+
+// check the contract password and return the contract
+fn authorize_admin(password: &str) -> Result<Contract, Error> {
+    match CONTRACT_STORAGE.with(|s| s.borrow().get(&0)) {
+        Some(contract) if contract.password == password => Ok(contract),
+        Some(_) => Err(Error::Unauthorized {
+            msg: "Unauthorized, method only available to contract Admins".to_string(),
+        }),
+        None => Err(Error::NotFound {
+            msg: "Contract not found, please initialize contract".to_string(),
+        }),
+    }
+}
+
+// check the producer password and return the producer
+fn authorize_producer(producer_id: u64, password: &str) -> Result<Producer, Error> {
+    match PRODUCER_STORAGE.with(|s| s.borrow().get(&producer_id)) {
+        Some(producer) if producer.password == password => Ok(producer),
+        Some(_) => Err(Error::Unauthorized {
+            msg: "Unauthorized, method only available to producers".to_string(),
+        }),
+        None => Err(Error::NotFound {
+            msg: "Producer not found".to_string(),
+        }),
+    }
+}
+
+// check the client password and return the client
+fn authorize_client(client_id: u64, password: &str) -> Result<Client, Error> {
+    match CLIENT_STORAGE.with(|s| s.borrow().get(&client_id)) {
+        Some(client) if client.password.as_deref() == Some(password) => Ok(client),
+        Some(_) => Err(Error::Unauthorized {
+            msg: "Unauthorized, method only available to the client".to_string(),
+        }),
+        None => Err(Error::NotFound {
+            msg: "Client not found".to_string(),
+        }),
+    }
+}
+
+// authenticate a client or producer account with its password
+fn authorize_account(account: AccountRef, password: &str) -> Result<(), Error> {
+    match account {
+        AccountRef::Client { id } => authorize_client(id, password).map(|_| ()),
+        AccountRef::Producer { id } => authorize_producer(id, password).map(|_| ()),
+    }
 }
 
 // initiate the contract
 #[ic_cdk::update]
 fn init_contract(payload: InitPayload) -> Result<String, Error> {
-    let contract = Contract {
-        password: payload.password,
-        credit_per_energy: payload.credit_per_energy,
-    };
-    match CONTRACT_STORAGE.with(|s| s.borrow_mut().insert(0, contract)) {
-        Some(_) => Err(Error::InvalidPayload {
-            msg: "Could not initiate contract, try again".to_string(),
-        }),
-        None => Ok("Contract initiated successfully".to_string()),
-    }
+    idempotent(
+        "init_contract",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            let contract = Contract {
+                password: payload.password,
+                credit_per_energy: payload.credit_per_energy,
+            };
+            match CONTRACT_STORAGE.with(|s| s.borrow_mut().insert(0, contract)) {
+                Some(_) => Err(Error::InvalidPayload {
+                    msg: "Could not initiate contract, try again".to_string(),
+                }),
+                None => Ok("Contract initiated successfully".to_string()),
+            }
+        },
+    )
 }
 
 // Define functions to add data to the storage
 #[ic_cdk::update]
 fn add_client(payload: ClientPayload) -> Result<Client, Error> {
+    idempotent(
+        "add_client",
+        payload.idempotency_key.clone(),
+        payload,
+        create_client,
+    )
+}
+
+// validate and register a client
+fn create_client(payload: ClientPayload) -> Result<Client, Error> {
     // Validate the payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
-        return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
-        });
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
     }
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_id = *counter.borrow().get();
-            counter.borrow_mut().set(current_id + 1)
-        })
-        .expect("Cannot increment Ids");
+    // Check the organization is not registered yet
+    ensure_unique_client(
+        &ProfileIdentity {
+            email: &payload.email,
+            phone: &payload.phone,
+            legal_entity_id: &payload.legal_entity_id,
+        },
+        None,
+    )?;
+
+    let id = next_id();
 
     let client = Client {
         id,
         name: payload.name.clone(),
+        organization_name: payload.organization_name,
+        legal_entity_id: payload.legal_entity_id,
+        country_code: payload.country_code.to_ascii_uppercase(),
+        email: payload.email.to_ascii_lowercase(),
         phone: payload.phone,
+        password: Some(payload.password),
         credits: 0,
+        verification: VerificationStatus::Unverified,
+        deactivated_at: None,
     };
 
     match CLIENT_STORAGE.with(|s| s.borrow_mut().insert(id, client.clone())) {
         Some(_) => Err(Error::InvalidPayload {
             msg: format!("Could not add client name: {}", payload.name),
         }),
-        None => Ok(client),
+        None => {
+            certify_client(id);
+            Ok(client)
+        }
     }
 }
 
-// Define query functions to get client by id
+// Define query functions to get the public profile of a client by id
 #[ic_cdk::query]
-fn get_client(id: u64) -> Result<Client, Error> {
-    match CLIENT_STORAGE.with(|s| s.borrow().get(&id)) {
-        Some(client) => Ok(client.clone()),
-        None => Err(Error::NotFound {
-            msg: format!("client with id: {} not found", id),
-        }),
-    }
+fn get_client(id: u64) -> Result<AccountProfile, Error> {
+    public_profile(AccountRef::Client { id })
 }
 
-// Define query functions to get all clients
+// Define query functions to get the public profiles of all clients
 #[ic_cdk::query]
-fn get_clients() -> Result<Vec<Client>, Error> {
-    // Retrieve all clients from the storage
-    let clients_vec: Vec<(u64, Client)> = CLIENT_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the clients from the tuple and create a vector
-    let clients: Vec<Client> = clients_vec.into_iter().map(|(_, client)| client).collect();
-
+fn get_clients() -> Result<Vec<AccountProfile>, Error> {
+    // Retrieve all client ids from the storage
+    let ids: Vec<u64> = CLIENT_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect());
     // Check if any clients are found
-    match clients.len() {
+    match ids.len() {
         0 => Err(Error::NotFound {
             msg: format!("no clients found"),
         }),
-        _ => Ok(clients),
+        _ => ids
+            .into_iter()
+            .map(|id| public_profile(AccountRef::Client { id }))
+            .collect(),
     }
 }
 
 // Define functions to update data in the storage
 #[ic_cdk::update]
 fn update_client(payload: UpdateClientPayload) -> Result<String, Error> {
-    // get client from storage by id
-    let client = CLIENT_STORAGE.with(|s| s.borrow().get(&payload.id));
-    match client {
-        Some(client) => {
-            CLIENT_STORAGE.with(|s| {
-                s.borrow_mut().insert(
-                    payload.id,
-                    Client {
-                        name: payload.name,
-                        phone: payload.phone,
-                        ..client
-                    },
-                )
-            });
-            Ok(format!("Client id: {} updated successfully", payload.id))
-        }
-        None => Err(Error::NotFound {
-            msg: "Client not found".to_string(),
-        }),
-    }
+    idempotent(
+        "update_client",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            // Validate the payload
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+
+            // get client from storage by id
+            let client = CLIENT_STORAGE.with(|s| s.borrow().get(&payload.id));
+            match client {
+                Some(client) => {
+                    ensure_unique_client(
+                        &ProfileIdentity {
+                            email: &payload.email,
+                            phone: &payload.phone,
+                            legal_entity_id: &payload.legal_entity_id,
+                        },
+                        Some(payload.id),
+                    )?;
+                    CLIENT_STORAGE.with(|s| {
+                        s.borrow_mut().insert(
+                            payload.id,
+                            Client {
+                                name: payload.name,
+                                organization_name: payload.organization_name,
+                                legal_entity_id: payload.legal_entity_id,
+                                country_code: payload.country_code.to_ascii_uppercase(),
+                                email: payload.email.to_ascii_lowercase(),
+                                phone: payload.phone,
+                                ..client
+                            },
+                        )
+                    });
+                    Ok(format!("Client id: {} updated successfully", payload.id))
+                }
+                None => Err(Error::NotFound {
+                    msg: "Client not found".to_string(),
+                }),
+            }
+        },
+    )
 }
 
 // function to add producer
 #[ic_cdk::update]
 fn add_producer(payload: ProducerPayload) -> Result<Producer, Error> {
+    idempotent(
+        "add_producer",
+        payload.idempotency_key.clone(),
+        payload,
+        create_producer,
+    )
+}
+
+// validate and register a producer
+fn create_producer(payload: ProducerPayload) -> Result<Producer, Error> {
     // Validate the payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
-        return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
-        });
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
     }
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_id = *counter.borrow().get();
-            counter.borrow_mut().set(current_id + 1)
-        })
-        .expect("Cannot increment Ids");
+    // Check the organization is not registered yet
+    ensure_unique_producer(
+        &ProfileIdentity {
+            email: &payload.email,
+            phone: &payload.phone,
+            legal_entity_id: &payload.legal_entity_id,
+        },
+        None,
+    )?;
+
+    let id = next_id();
 
     let producer = Producer {
         id,
         name: payload.name.clone(),
+        organization_name: payload.organization_name,
+        legal_entity_id: payload.legal_entity_id,
+        country_code: payload.country_code.to_ascii_uppercase(),
+        email: payload.email.to_ascii_lowercase(),
         phone: payload.phone,
         password: payload.password,
         energy_supply: 0,
         credits: 0,
+        verification: VerificationStatus::Unverified,
+        deactivated_at: None,
     };
 
     match PRODUCER_STORAGE.with(|s| s.borrow_mut().insert(id, producer.clone())) {
         Some(_) => Err(Error::InvalidPayload {
             msg: format!("Could not add producer name: {}", payload.name),
         }),
-        None => Ok(producer),
+        None => {
+            certify_producer(id);
+            Ok(producer)
+        }
     }
 }
 
-// award producer carbon credits per renewable energy supply
+// function to update producer profile
 #[ic_cdk::update]
-fn award_producer_energy(payload: ProducerEnergyPayload) -> Result<String, Error> {
-    // check if producer exists
-    let producer = PRODUCER_STORAGE.with(|s| s.borrow().get(&payload.producer_id));
-    match producer {
-        Some(producer) => {
-            match CONTRACT_STORAGE.with(|s| s.borrow().get(&0)) {
-                Some(contract) => {
-                    if contract.password != payload.contract_password {
+fn update_producer(payload: UpdateProducerPayload) -> Result<String, Error> {
+    idempotent(
+        "update_producer",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            // Validate the payload
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+
+            // get producer from storage by id
+            let producer = PRODUCER_STORAGE.with(|s| s.borrow().get(&payload.id));
+            match producer {
+                Some(producer) => {
+                    if producer.password != payload.password {
                         return Err(Error::Unauthorized {
-                            msg: "Unauthorized, method only available to contract Admins"
-                                .to_string(),
+                            msg: "Unauthorized, method only available to producers".to_string(),
                         });
                     }
+                    ensure_unique_producer(
+                        &ProfileIdentity {
+                            email: &payload.email,
+                            phone: &payload.phone,
+                            legal_entity_id: &payload.legal_entity_id,
+                        },
+                        Some(payload.id),
+                    )?;
                     PRODUCER_STORAGE.with(|s| {
                         s.borrow_mut().insert(
-                            payload.producer_id,
+                            payload.id,
                             Producer {
-                                energy_supply: producer.energy_supply + payload.energy_supply,
-                                credits: producer.credits
-                                    + payload.energy_supply * contract.credit_per_energy,
-                                ..producer.clone()
+                                name: payload.name,
+                                organization_name: payload.organization_name,
+                                legal_entity_id: payload.legal_entity_id,
+                                country_code: payload.country_code.to_ascii_uppercase(),
+                                email: payload.email.to_ascii_lowercase(),
+                                phone: payload.phone,
+                                ..producer
                             },
                         )
                     });
+                    Ok(format!("Producer id: {} updated successfully", payload.id))
                 }
-                None => {
-                    return Err(Error::NotFound {
-                        msg: "Contract not found, please initialize contract".to_string(),
-                    });
-                }
+                None => Err(Error::NotFound {
+                    msg: "Producer not found".to_string(),
+                }),
             }
+        },
+    )
+}
 
+// award producer carbon credits per renewable energy supply
+#[ic_cdk::update]
+fn award_producer_energy(payload: ProducerEnergyPayload) -> Result<String, Error> {
+    idempotent(
+        "award_producer_energy",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            // check if producer exists
+            if !PRODUCER_STORAGE.with(|s| s.borrow().contains_key(&payload.producer_id)) {
+                return Err(Error::NotFound {
+                    msg: "Producer not found".to_string(),
+                });
+            }
+            let contract = authorize_admin(&payload.contract_password)?;
+            award_producer_facility(
+                &contract,
+                payload.producer_id,
+                payload.facility_id,
+                payload.energy_supply,
+                payload.period_start,
+                payload.period_end,
+            )?;
             Ok(format!(
                 "Producer id: {} awarded successfully",
                 payload.producer_id
             ))
+        },
+    )
+}
+
+// get a facility, checking it belongs to the producer
+fn producer_facility(producer_id: u64, facility_id: u64) -> Result<Facility, Error> {
+    let facility = get_facility_record(facility_id)?;
+    if facility.producer_id != producer_id {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Facility id: {} does not belong to producer id: {}",
+                facility_id, producer_id
+            ),
+        });
+    }
+    Ok(facility)
+}
+
+// award the energy supply of a facility of the producer
+fn award_producer_facility(
+    contract: &Contract,
+    producer_id: u64,
+    facility_id: u64,
+    energy_supply: u64,
+    period_start: u64,
+    period_end: u64,
+) -> Result<FacilityAward, Error> {
+    let facility = producer_facility(producer_id, facility_id)?;
+    award_facility(contract, facility, energy_supply, period_start, period_end)
+}
+
+// mint the credits earned by a producer for an energy supply, awards go through award_facility
+fn award_energy(
+    contract: &Contract,
+    producer_id: u64,
+    facility_id: Option<u64>,
+    vintage: Option<u32>,
+    energy_supply: u64,
+) -> Result<u64, Error> {
+    match PRODUCER_STORAGE.with(|s| s.borrow().get(&producer_id)) {
+        Some(producer) => {
+            check_award_risk(producer_id)?;
+            let credits = energy_supply * contract.credit_per_energy;
+            PRODUCER_STORAGE.with(|s| {
+                s.borrow_mut().insert(
+                    producer_id,
+                    Producer {
+                        energy_supply: producer.energy_supply + energy_supply,
+                        credits: producer.credits + credits,
+                        ..producer
+                    },
+                )
+            });
+            certify_producer(producer_id);
+            record_activity(AccountRef::Producer { id: producer_id });
+            if let (Some(facility_id), Some(vintage)) = (facility_id, vintage) {
+                record_vintage_award(facility_id, vintage, credits);
+            }
+            record_mint_stats(credits);
+            emit_event(
+                EventKind::CreditsMinted,
+                None,
+                Some(AccountRef::Producer { id: producer_id }),
+                credits,
+                None,
+            );
+            issue_receipt(
+                ReceiptKind::Mint { facility_id },
+                AccountRef::Producer { id: producer_id },
+                credits,
+                None,
+            );
+            // forward contracts are delivered from the new credits first
+            deliver_forwards(producer_id, facility_id, vintage, credits);
+            Ok(credits)
         }
         None => Err(Error::NotFound {
             msg: "Producer not found".to_string(),
@@ -394,103 +828,111 @@ fn award_producer_energy(payload: ProducerEnergyPayload) -> Result<String, Error
     }
 }
 
-// function to get all producers
+// function to get the public profiles of all producers
 #[ic_cdk::query]
-fn get_producers() -> Result<Vec<ProducerReturn>, Error> {
-    // Retrieve all producers from the storage
-    let producers_vec: Vec<(u64, Producer)> =
-        PRODUCER_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the producers from the tuple and create a vector
-    let producers: Vec<Producer> = producers_vec
-        .into_iter()
-        .map(|(_, producer)| producer)
-        .collect();
-
+fn get_producers() -> Result<Vec<AccountProfile>, Error> {
+    // Retrieve all producer ids from the storage
+    let ids: Vec<u64> = PRODUCER_STORAGE.with(|s| s.borrow().iter().map(|(id, _)| id).collect());
     // Check if any producers are found
-    match producers.len() {
+    match ids.len() {
         0 => Err(Error::NotFound {
             msg: format!("no producers found"),
         }),
-        _ => {
-            let producers_return: Vec<ProducerReturn> = producers
-                .into_iter()
-                .map(|producer| ProducerReturn {
-                    id: producer.id,
-                    name: producer.name,
-                    phone: producer.phone,
-                    energy_supply: producer.energy_supply,
-                    credits: producer.credits,
-                })
-                .collect();
-            Ok(producers_return)
-        }
+        _ => ids
+            .into_iter()
+            .map(|id| public_profile(AccountRef::Producer { id }))
+            .collect(),
     }
 }
 
-// function to get producer by id
+// function to get the public profile of a producer by id
 #[ic_cdk::query]
-fn get_producer(id: u64) -> Result<ProducerReturn, Error> {
-    // Retrieve producer from the storage
-    let producer = PRODUCER_STORAGE.with(|s| s.borrow().get(&id));
-
-    // Check if producer is found
-    match producer {
-        Some(producer) => Ok(ProducerReturn {
-            id: producer.id,
-            name: producer.name.clone(),
-            phone: producer.phone.clone(),
-            energy_supply: producer.energy_supply,
-            credits: producer.credits,
-        }),
-        None => Err(Error::NotFound {
-            msg: format!("producer with id: {} not found", id),
-        }),
-    }
+fn get_producer(id: u64) -> Result<AccountProfile, Error> {
+    public_profile(AccountRef::Producer { id })
 }
 
 // function to add credit order
 #[ic_cdk::update]
 fn add_credit_order(payload: CreditOrderPayload) -> Result<CreditOrder, Error> {
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_id = *counter.borrow().get();
-            counter.borrow_mut().set(current_id + 1)
-        })
-        .expect("Cannot increment Ids");
+    idempotent(
+        "add_credit_order",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            let id = next_id();
 
-    // check if producer exists and has enough credits for order
-    let producer = PRODUCER_STORAGE.with(|s| s.borrow().get(&payload.producer_id));
+            // check if producer exists and has enough credits for order
+            let producer = PRODUCER_STORAGE.with(|s| s.borrow().get(&payload.producer_id));
 
-    match producer {
-        Some(producer) => {
-            if producer.credits < payload.credits {
-                return Err(Error::InvalidPayload {
-                    msg: "Producer does not have enough credits".to_string(),
-                });
+            match producer {
+                Some(producer) => {
+                    ensure_can_trade(AccountRef::Producer { id: producer.id })?;
+                    if producer.credits < payload.credits {
+                        return Err(Error::InvalidPayload {
+                            msg: "Producer does not have enough credits".to_string(),
+                        });
+                    }
+                }
+                None => {
+                    return Err(Error::NotFound {
+                        msg: "Producer not found".to_string(),
+                    });
+                }
             }
-        }
-        None => {
-            return Err(Error::NotFound {
-                msg: "Producer not found".to_string(),
-            });
-        }
-    }
 
-    let credit_order = CreditOrder {
-        id,
-        client_id: None,
-        producer_id: payload.producer_id,
-        credits: payload.credits,
-        min_offer_per_credit: payload.min_offer_per_credit,
-        paid: false,
-    };
-
-    match CREDIT_ORDER_STORAGE.with(|s| s.borrow_mut().insert(id, credit_order.clone())) {
-        Some(_) => Err(Error::InvalidPayload {
-            msg: "Invalid payload".to_string(),
-        }),
-        None => Ok(credit_order),
-    }
+            let order_type = payload.order_type.unwrap_or_default();
+            validate_order_type(&order_type, payload.min_offer_per_credit)?;
+            validate_credit_source(
+                payload.producer_id,
+                payload.facility_id,
+                payload.vintage,
+                payload.credits,
+            )?;
+
+            // move the order credits into escrow
+            deduct_credit_from_producer(payload.producer_id, payload.credits)?;
+            commit_vintage_credits(payload.facility_id, payload.vintage, payload.credits);
+
+            let credit_order = CreditOrder {
+                id,
+                client_id: None,
+                producer_id: payload.producer_id,
+                credits: payload.credits,
+                min_offer_per_credit: payload.min_offer_per_credit,
+                high_bid: None,
+                ask_price: initial_ask_price(&order_type, payload.min_offer_per_credit),
+                order_type,
+                facility_id: payload.facility_id,
+                vintage: payload.vintage,
+                paid: false,
+                escrow: payload.credits,
+                status: OrderStatus::Open,
+                dispute_ids: Vec::new(),
+                created_at: ic_cdk::api::time(),
+            };
+
+            match CREDIT_ORDER_STORAGE.with(|s| s.borrow_mut().insert(id, credit_order.clone())) {
+                Some(_) => Err(Error::InvalidPayload {
+                    msg: "Invalid payload".to_string(),
+                }),
+                None => {
+                    certify_order(id);
+                    emit_event(
+                        EventKind::OrderCreated,
+                        Some(id),
+                        Some(AccountRef::Producer {
+                            id: credit_order.producer_id,
+                        }),
+                        credit_order.credits,
+                        credit_order.ask_price,
+                    );
+                    schedule_price_decay(&credit_order);
+                    open_sealed_auction(&credit_order);
+                    Ok(credit_order)
+                }
+            }
+        },
+    )
 }
 
 // get all incomplete orders
@@ -513,7 +955,9 @@ fn get_all_incomplete_orders() -> Result<Vec<CreditOrder>, Error> {
         _ => {
             let incomplete_orders: Vec<CreditOrder> = credit_orders
                 .into_iter()
-                .filter(|credit_order| !credit_order.paid)
+                .filter(|credit_order| {
+                    !credit_order.paid && credit_order.status != OrderStatus::Cancelled
+                })
                 .collect();
             Ok(incomplete_orders)
         }
@@ -559,14 +1003,163 @@ fn get_credit_order_by_id(id: u64) -> Result<CreditOrder, Error> {
 // function for clients to bid for credit order
 #[ic_cdk::update]
 fn bid(payload: BidPayload) -> Result<String, Error> {
-    // check if credit order exists
-    let credit_order = CREDIT_ORDER_STORAGE.with(|s| s.borrow().get(&payload.credit_order_id));
-    match credit_order {
-        Some(credit_order) => {
-            // check if client exists
-            let client = CLIENT_STORAGE.with(|s| s.borrow().get(&payload.client_id));
-            match client {
-                Some(client) => {
+    idempotent(
+        "bid",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            // check if credit order exists
+            let credit_order =
+                CREDIT_ORDER_STORAGE.with(|s| s.borrow().get(&payload.credit_order_id));
+            match credit_order {
+                Some(credit_order) => {
+                    // check if client exists
+                    let client = CLIENT_STORAGE.with(|s| s.borrow().get(&payload.client_id));
+                    match client {
+                        Some(client) => {
+                            // check if client is allowed to trade
+                            ensure_can_trade(AccountRef::Client { id: client.id })?;
+                            // check if credit order has already been paid
+                            if credit_order.paid {
+                                return Err(Error::AlreadyPaid {
+                                    msg: "Credit order has already been paid".to_string(),
+                                });
+                            }
+                            // check if credit order is still open for bids
+                            if credit_order.status != OrderStatus::Open {
+                                return Err(Error::InvalidPayload {
+                                    msg: "Credit order is not open for bids".to_string(),
+                                });
+                            }
+                            // check if client has already bid for credit order
+                            if credit_order.client_id == Some(client.id) {
+                                return Err(Error::InvalidPayload {
+                                    msg: "Client has already bid for credit order".to_string(),
+                                });
+                            }
+                            // check if credit order is an English auction
+                            match credit_order.order_type {
+                                OrderType::EnglishAuction => {}
+                                OrderType::SealedBid { .. } => {
+                                    return Err(Error::InvalidPayload {
+                                        msg: "Credit order is a sealed-bid auction, \
+                                          use commit_sealed_bid"
+                                            .to_string(),
+                                    })
+                                }
+                                _ => {
+                                    return Err(Error::InvalidPayload {
+                                        msg: "Credit order is not an auction, buy it with buy_now"
+                                            .to_string(),
+                                    })
+                                }
+                            }
+                            // check if the offer meets the reserve
+                            if credit_order.min_offer_per_credit > payload.offer_per_credit {
+                                return Err(Error::InvalidPayload {
+                                msg:
+                                    "Client cannot bid for credit order with lower offer_per_credit"
+                                        .to_string(),
+                            });
+                            }
+                            // check if the offer beats the current high bid
+                            if credit_order
+                                .high_bid
+                                .is_some_and(|high_bid| high_bid >= payload.offer_per_credit)
+                            {
+                                return Err(Error::InvalidPayload {
+                                    msg: "Client must bid more than the current high bid"
+                                        .to_string(),
+                                });
+                            }
+                            // check the bid against the risk rules
+                            check_bid_risk(client.id, credit_order.producer_id)?;
+
+                            // update credit order
+                            CREDIT_ORDER_STORAGE.with(|s| {
+                                s.borrow_mut().insert(
+                                    payload.credit_order_id,
+                                    CreditOrder {
+                                        client_id: Some(payload.client_id),
+                                        high_bid: Some(payload.offer_per_credit),
+                                        ..credit_order.clone()
+                                    },
+                                )
+                            });
+                            certify_order(payload.credit_order_id);
+                            record_activity(AccountRef::Client { id: client.id });
+                            emit_event(
+                                EventKind::BidPlaced,
+                                Some(credit_order.id),
+                                Some(AccountRef::Client { id: client.id }),
+                                credit_order.credits,
+                                Some(payload.offer_per_credit),
+                            );
+                            // tell the displaced high bidder
+                            if let Some(outbid_id) = credit_order.client_id {
+                                notify(
+                                    AccountRef::Client { id: outbid_id },
+                                    "outbid",
+                                    credit_order.id,
+                                    format!(
+                                        "Outbid on credit order {} at {} per credit",
+                                        credit_order.id, payload.offer_per_credit
+                                    ),
+                                );
+                                emit_event(
+                                    EventKind::Outbid,
+                                    Some(credit_order.id),
+                                    Some(AccountRef::Client { id: outbid_id }),
+                                    credit_order.credits,
+                                    Some(payload.offer_per_credit),
+                                );
+                            }
+                            Ok(format!("Client id: {} bid successfully", payload.client_id))
+                        }
+                        None => Err(Error::NotFound {
+                            msg: "Client not found".to_string(),
+                        }),
+                    }
+                }
+                None => Err(Error::NotFound {
+                    msg: "Credit order not found".to_string(),
+                }),
+            }
+        },
+    )
+}
+
+// function for producers to mark bid as paid and complete it
+#[ic_cdk::update]
+fn mark_order_paid(payload: PaidPayload) -> Result<String, Error> {
+    idempotent(
+        "mark_order_paid",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            // check if credit order exists
+            let credit_order = CREDIT_ORDER_STORAGE.with(|s| s.borrow().get(&payload.order_id));
+
+            match credit_order {
+                Some(credit_order) => {
+                    let producer =
+                        PRODUCER_STORAGE.with(|s| s.borrow().get(&credit_order.producer_id));
+                    match producer {
+                        Some(producer) => {
+                            if producer.password != payload.password {
+                                return Err(Error::Unauthorized {
+                                    msg: "Unauthorized, method only available to producers"
+                                        .to_string(),
+                                });
+                            }
+                        }
+                        None => {
+                            return Err(Error::NotFound {
+                                msg: "Producer not found".to_string(),
+                            });
+                        }
+                    }
+
                     // check if credit order has already been paid
                     if credit_order.paid {
                         return Err(Error::AlreadyPaid {
@@ -574,116 +1167,264 @@ fn bid(payload: BidPayload) -> Result<String, Error> {
                         });
                     }
                     // check if client has already bid for credit order
-                    if credit_order.client_id == Some(client.id) {
+                    if credit_order.client_id.is_none() {
                         return Err(Error::InvalidPayload {
-                            msg: "Client has already bid for credit order".to_string(),
+                            msg: "Client has not bid for credit order".to_string(),
                         });
                     }
-                    // check if client is bidding for credit order with lower offer_per_credit
-                    if credit_order.min_offer_per_credit > payload.offer_per_credit {
+
+                    // check if credit order is open for settlement
+                    if credit_order.status != OrderStatus::Open {
                         return Err(Error::InvalidPayload {
-                            msg: "Client cannot bid for credit order with lower offer_per_credit"
-                                .to_string(),
+                            msg: "Credit order is frozen or cancelled".to_string(),
                         });
                     }
 
-                    // update credit order
-                    CREDIT_ORDER_STORAGE.with(|s| {
-                        s.borrow_mut().insert(
-                            payload.credit_order_id,
-                            CreditOrder {
-                                client_id: Some(payload.client_id),
-                                min_offer_per_credit: payload.offer_per_credit,
-                                ..credit_order.clone()
-                            },
-                        )
-                    });
-                    Ok(format!("Client id: {} bid successfully", payload.client_id))
+                    settle_credit_order(credit_order)?;
+                    Ok(format!(
+                        "Credit order id: {} marked as paid, receipt id: {}",
+                        payload.order_id,
+                        order_receipt_id(payload.order_id).unwrap_or_default()
+                    ))
                 }
                 None => Err(Error::NotFound {
-                    msg: "Client not found".to_string(),
+                    msg: "Credit order not found".to_string(),
                 }),
             }
-        }
-        None => Err(Error::NotFound {
-            msg: "Credit order not found".to_string(),
-        }),
+        },
+    )
+}
+
+// release the escrow of a credit order to its client, charging the marketplace fees
+fn settle_credit_order(credit_order: CreditOrder) -> Result<CreditOrder, Error> {
+    let credits = credit_order.escrow;
+    settle_escrow(credit_order, credits)
+}
+
+// move the credits of a settlement between the parties and the treasury
+fn move_settlement_credits(
+    credit_order: &CreditOrder,
+    client_id: u64,
+    credits: u64,
+    client_credits: u64,
+    fees: SettlementFees,
+) -> Result<(), Error> {
+    // update producer to deduct any maker fee charged in credits
+    deduct_credit_from_producer(credit_order.producer_id, fees.producer_credit_fee())?;
+
+    // update client to add the escrowed credits
+    add_credit_to_client(Some(client_id), client_credits)?;
+
+    // refund the escrow that is not settled
+    if credits < credit_order.escrow {
+        add_credit_to_producer(credit_order.producer_id, credit_order.escrow - credits)?;
+        release_vintage_credits(credit_order, credit_order.escrow - credits);
     }
+
+    // route the fees to the treasury
+    collect_settlement_fees(credit_order, client_id, fees)
 }
 
-// function for producers to mark bid as paid and complete it
-#[ic_cdk::update]
-fn mark_order_paid(payload: PaidPayload) -> Result<String, Error> {
-    // check if credit order exists
-    let credit_order = CREDIT_ORDER_STORAGE.with(|s| s.borrow().get(&payload.order_id));
+// release part of the escrow of a credit order to its client, charging the marketplace fees on
+// that part, and refund the rest of the escrow to the producer
+fn settle_escrow(credit_order: CreditOrder, credits: u64) -> Result<CreditOrder, Error> {
+    let client_id = credit_order.client_id.ok_or(Error::InvalidPayload {
+        msg: "Client has not bid for credit order".to_string(),
+    })?;
+    if credits > credit_order.escrow {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Cannot settle more than the {} escrowed credits",
+                credit_order.escrow
+            ),
+        });
+    }
 
-    match credit_order {
-        Some(credit_order) => {
-            let producer = PRODUCER_STORAGE.with(|s| s.borrow().get(&credit_order.producer_id));
-            match producer {
-                Some(producer) => {
-                    if producer.password != payload.password {
-                        return Err(Error::Unauthorized {
-                            msg: "Unauthorized, method only available to producers".to_string(),
-                        });
-                    }
-                }
-                None => {
-                    return Err(Error::NotFound {
-                        msg: "Producer not found".to_string(),
-                    });
-                }
-            }
+    // compute the marketplace fees due on the settled credits
+    let fees = settlement_fees(&CreditOrder {
+        credits,
+        ..credit_order.clone()
+    })?;
+
+    // check every step before moving any credits
+    account_credits(AccountRef::Client { id: client_id })?;
+    let producer_credits = account_credits(AccountRef::Producer {
+        id: credit_order.producer_id,
+    })?;
+    if producer_credits < fees.producer_credit_fee() {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Producer has insufficient credits for the {} credit fee",
+                fees.producer_credit_fee()
+            ),
+        });
+    }
+    ensure_fees_collectable(&fees)?;
+
+    let client_credits = fees.client_credit(credits);
+    let settled = move_settlement_credits(&credit_order, client_id, credits, client_credits, fees);
+    if let Err(e) = settled {
+        // trapping discards the credits already moved, the order stays unsettled
+        ic_cdk::trap(&format!(
+            "Settlement of credit order {} rolled back: {:?}",
+            credit_order.id, e
+        ));
+    }
+    record_trade(&credit_order, client_id, credits, credits - client_credits);
 
-            // check if credit order has already been paid
-            if credit_order.paid {
-                return Err(Error::AlreadyPaid {
-                    msg: "Credit order has already been paid".to_string(),
-                });
-            }
-            // check if client has already bid for credit order
-            if credit_order.client_id.is_none() {
-                return Err(Error::InvalidPayload {
-                    msg: "Client has not bid for credit order".to_string(),
-                });
-            }
+    // update credit order
+    let credit_order = CreditOrder {
+        paid: true,
+        escrow: 0,
+        status: OrderStatus::Paid,
+        ..credit_order
+    };
+    CREDIT_ORDER_STORAGE.with(|s| s.borrow_mut().insert(credit_order.id, credit_order.clone()));
+    certify_order(credit_order.id);
+    Ok(credit_order)
+}
 
-            // update producer to deduct credits
-            match deduct_credit_from_producer(credit_order.producer_id, credit_order.credits) {
-                Ok(_) => {}
-                Err(e) => {
-                    return Err(e);
-                }
-            }
+// sell credits from a producer balance to a client at an agreed price, escrowing them into a
+// buy-it-now order that is settled at once
+fn settle_direct_sale(
+    producer_id: u64,
+    client_id: u64,
+    credits: u64,
+    price_per_credit: u64,
+    facility_id: Option<u64>,
+    vintage: Option<u32>,
+) -> Result<CreditOrder, Error> {
+    // move the credits into escrow
+    ensure_vintage_credits(facility_id, vintage, credits)?;
+    deduct_credit_from_producer(producer_id, credits)?;
+    commit_vintage_credits(facility_id, vintage, credits);
+    let id = next_id();
+    let credit_order = CreditOrder {
+        id,
+        client_id: Some(client_id),
+        producer_id,
+        credits,
+        min_offer_per_credit: price_per_credit,
+        high_bid: Some(price_per_credit),
+        ask_price: None,
+        order_type: OrderType::BuyItNow,
+        facility_id,
+        vintage,
+        paid: false,
+        escrow: credits,
+        status: OrderStatus::Open,
+        dispute_ids: Vec::new(),
+        created_at: ic_cdk::api::time(),
+    };
+    CREDIT_ORDER_STORAGE.with(|s| s.borrow_mut().insert(id, credit_order.clone()));
+    certify_order(id);
+    settle_credit_order(credit_order.clone()).inspect_err(|_| {
+        let _ = cancel_credit_order(credit_order);
+    })
+}
 
-            // update client to add credits
-            match add_credit_to_client(credit_order.client_id, credit_order.credits) {
-                Ok(_) => {}
-                Err(e) => {
-                    return Err(e);
-                }
-            }
-            // update credit order
-            CREDIT_ORDER_STORAGE.with(|s| {
+// cancel an unsettled credit order and refund its escrow to the producer
+fn cancel_credit_order(credit_order: CreditOrder) -> Result<CreditOrder, Error> {
+    add_credit_to_producer(credit_order.producer_id, credit_order.escrow)?;
+    release_vintage_credits(&credit_order, credit_order.escrow);
+    let credit_order = CreditOrder {
+        escrow: 0,
+        status: OrderStatus::Cancelled,
+        ..credit_order
+    };
+    CREDIT_ORDER_STORAGE.with(|s| s.borrow_mut().insert(credit_order.id, credit_order.clone()));
+    certify_order(credit_order.id);
+    Ok(credit_order)
+}
+
+// function to return credits to a producer
+fn add_credit_to_producer(producer_id: u64, credits: u64) -> Result<String, Error> {
+    let producer = PRODUCER_STORAGE.with(|s| s.borrow().get(&producer_id));
+    match producer {
+        Some(producer) => {
+            PRODUCER_STORAGE.with(|s| {
                 s.borrow_mut().insert(
-                    payload.order_id,
-                    CreditOrder {
-                        paid: true,
-                        ..credit_order.clone()
+                    producer_id,
+                    Producer {
+                        credits: producer.credits + credits,
+                        ..producer.clone()
                     },
                 )
             });
+            certify_producer(producer_id);
             Ok(format!(
-                "Credit order id: {} marked as paid",
-                payload.order_id
+                "Producer id: {} refunded successfully",
+                producer_id
             ))
         }
         None => Err(Error::NotFound {
-            msg: "Credit order not found".to_string(),
+            msg: "Producer not found".to_string(),
         }),
     }
 }
 
+// function to deduct credit from client
+fn deduct_credit_from_client(client_id: u64, credits: u64) -> Result<String, Error> {
+    let client = CLIENT_STORAGE.with(|s| s.borrow().get(&client_id));
+    match client {
+        Some(client) => {
+            if client.credits < credits {
+                return Err(Error::InvalidPayload {
+                    msg: "Client does not have enough credits".to_string(),
+                });
+            }
+            CLIENT_STORAGE.with(|s| {
+                s.borrow_mut().insert(
+                    client_id,
+                    Client {
+                        credits: client.credits - credits,
+                        ..client.clone()
+                    },
+                )
+            });
+            certify_client(client_id);
+            Ok(format!("Client id: {} debited successfully", client_id))
+        }
+        None => Err(Error::NotFound {
+            msg: "Client not found".to_string(),
+        }),
+    }
+}
+
+// get the credit balance of an account
+fn account_credits(account: AccountRef) -> Result<u64, Error> {
+    match account {
+        AccountRef::Client { id } => match CLIENT_STORAGE.with(|s| s.borrow().get(&id)) {
+            Some(client) => Ok(client.credits),
+            None => Err(Error::NotFound {
+                msg: format!("client with id: {} not found", id),
+            }),
+        },
+        AccountRef::Producer { id } => match PRODUCER_STORAGE.with(|s| s.borrow().get(&id)) {
+            Some(producer) => Ok(producer.credits),
+            None => Err(Error::NotFound {
+                msg: format!("producer with id: {} not found", id),
+            }),
+        },
+    }
+}
+
+// function to add credit to any account
+fn credit_account(account: AccountRef, credits: u64) -> Result<String, Error> {
+    match account {
+        AccountRef::Client { id } => add_credit_to_client(Some(id), credits),
+        AccountRef::Producer { id } => add_credit_to_producer(id, credits),
+    }
+}
+
+// function to deduct credit from any account
+fn debit_account(account: AccountRef, credits: u64) -> Result<String, Error> {
+    match account {
+        AccountRef::Client { id } => deduct_credit_from_client(id, credits),
+        AccountRef::Producer { id } => deduct_credit_from_producer(id, credits),
+    }
+}
+
 // fuction to add credit to client
 fn add_credit_to_client(client_id: Option<u64>, credits: u64) -> Result<String, Error> {
     match client_id.is_some() {
@@ -702,6 +1443,7 @@ fn add_credit_to_client(client_id: Option<u64>, credits: u64) -> Result<String,
                             },
                         )
                     });
+                    certify_client(client.id);
                     Ok(format!("Client id: {} credited successfully", client.id))
                 }
                 None => Err(Error::NotFound {
@@ -719,6 +1461,11 @@ fn deduct_credit_from_producer(producer_id: u64, credits: u64) -> Result<String,
     let producer = PRODUCER_STORAGE.with(|s| s.borrow().get(&producer_id));
     match producer {
         Some(producer) => {
+            if producer.credits < credits {
+                return Err(Error::InvalidPayload {
+                    msg: "Producer does not have enough credits".to_string(),
+                });
+            }
             // update producer
             PRODUCER_STORAGE.with(|s| {
                 s.borrow_mut().insert(
@@ -729,6 +1476,7 @@ fn deduct_credit_from_producer(producer_id: u64, credits: u64) -> Result<String,
                     },
                 )
             });
+            certify_producer(producer_id);
             Ok(format!(
                 "Producer id: {} credited successfully",
                 producer_id
@@ -741,7 +1489,7 @@ fn deduct_credit_from_producer(producer_id: u64, credits: u64) -> Result<String,
 }
 
 // Define an Error enum for handling errors
-#[derive(candid::CandidType, Deserialize, Serialize)]
+#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
 enum Error {
     NotFound { msg: String },
     AlreadyPaid { msg: String },
@@ -749,5 +1497,21 @@ enum Error {
     Unauthorized { msg: String },
 }
 
+// re-arm the timers lost during an upgrade
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrate_legacy_records();
+    backfill_trade_fees();
+    restore_dispute_timers();
+    restore_price_decay_timers();
+    restore_sealed_auction_timers();
+    restore_forward_timers();
+    restore_recurring_agreement_timers();
+    restore_receipt_signing();
+    restore_certified_data();
+    restore_event_delivery();
+    seed_vintage_ledger();
+}
+
 // Candid generator for exporting the Candid interface
 ic_cdk::export_candid!();
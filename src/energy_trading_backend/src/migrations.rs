@@ -0,0 +1,166 @@
+use candid::Decode;
+
+use crate::{
+    client_credit_fees, Client, CreditOrder, OrderStatus, Producer, Trade, CREDIT_ORDER_STORAGE,
+    PRODUCER_STORAGE, TRADE_STORAGE,
+};
+
+// client as stored before the organization profile, verification and deactivation were added
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyClient {
+    id: u64,
+    name: String,
+    phone: String,
+    credits: u64,
+}
+
+// producer as stored before the organization profile, verification and deactivation were added
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyProducer {
+    id: u64,
+    name: String,
+    password: String,
+    phone: String,
+    energy_supply: u64,
+    credits: u64,
+}
+
+// credit order as stored before escrow, order types and disputes were added
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyCreditOrder {
+    id: u64,
+    client_id: Option<u64>,
+    producer_id: u64,
+    credits: u64,
+    min_offer_per_credit: u64,
+    paid: bool,
+}
+
+// decode a client in the layout it had before the organization profile was added, the missing
+// profile is left empty and the client stays unverified until it is updated and verified
+pub(crate) fn decode_legacy_client(bytes: &[u8]) -> Client {
+    let legacy = Decode!(bytes, LegacyClient).unwrap();
+    Client {
+        id: legacy.id,
+        name: legacy.name,
+        phone: legacy.phone,
+        credits: legacy.credits,
+        ..Default::default()
+    }
+}
+
+// decode a producer in the layout it had before the organization profile was added
+pub(crate) fn decode_legacy_producer(bytes: &[u8]) -> Producer {
+    let legacy = Decode!(bytes, LegacyProducer).unwrap();
+    Producer {
+        id: legacy.id,
+        name: legacy.name,
+        password: legacy.password,
+        phone: legacy.phone,
+        energy_supply: legacy.energy_supply,
+        credits: legacy.credits,
+        ..Default::default()
+    }
+}
+
+// decode a credit order in the layout it had before escrow was added, its credits are escrowed
+// by migrate_legacy_records after the upgrade
+pub(crate) fn decode_legacy_credit_order(bytes: &[u8]) -> CreditOrder {
+    let legacy = Decode!(bytes, LegacyCreditOrder).unwrap();
+    CreditOrder {
+        id: legacy.id,
+        client_id: legacy.client_id,
+        producer_id: legacy.producer_id,
+        credits: legacy.credits,
+        min_offer_per_credit: legacy.min_offer_per_credit,
+        // bids used to overwrite the minimum offer of the order
+        high_bid: legacy.client_id.map(|_| legacy.min_offer_per_credit),
+        paid: legacy.paid,
+        status: match legacy.paid {
+            true => OrderStatus::Paid,
+            false => OrderStatus::Open,
+        },
+        // the creation time was not stored, 0 marks an order that has not been migrated yet
+        created_at: 0,
+        ..Default::default()
+    }
+}
+
+// rewrite records decoded from an older layout, moving the credits of unsettled legacy orders into
+// escrow as add_credit_order does now, or cancelling them if the producer no longer has the
+// credits since they were only checked when the order was placed
+pub(crate) fn migrate_legacy_records() {
+    let now = ic_cdk::api::time();
+    let legacy_orders: Vec<CreditOrder> = CREDIT_ORDER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, credit_order)| credit_order)
+            .filter(|credit_order| credit_order.created_at == 0)
+            .collect()
+    });
+    for credit_order in legacy_orders {
+        let status = match credit_order.status {
+            OrderStatus::Open => {
+                let producer = PRODUCER_STORAGE.with(|s| s.borrow().get(&credit_order.producer_id));
+                match producer {
+                    Some(producer) if producer.credits >= credit_order.credits => {
+                        PRODUCER_STORAGE.with(|s| {
+                            s.borrow_mut().insert(
+                                producer.id,
+                                Producer {
+                                    credits: producer.credits - credit_order.credits,
+                                    ..producer
+                                },
+                            )
+                        });
+                        OrderStatus::Open
+                    }
+                    _ => OrderStatus::Cancelled,
+                }
+            }
+            status => status,
+        };
+        let escrow = match status {
+            OrderStatus::Open => credit_order.credits,
+            _ => 0,
+        };
+        CREDIT_ORDER_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                credit_order.id,
+                CreditOrder {
+                    escrow,
+                    status,
+                    created_at: now,
+                    ..credit_order
+                },
+            )
+        });
+    }
+}
+
+// keep the taker fee on trades recorded before it was stored, from the fee records of their order
+pub(crate) fn backfill_trade_fees() {
+    let trades: Vec<Trade> = TRADE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, trade)| trade)
+            .filter(|trade| trade.client_fee.is_none())
+            .collect()
+    });
+    if trades.is_empty() {
+        return;
+    }
+    let fees = client_credit_fees();
+    TRADE_STORAGE.with(|s| {
+        for trade in trades {
+            let client_fee = fees.get(&trade.order_id).copied().unwrap_or(0);
+            s.borrow_mut().insert(
+                trade.id,
+                Trade {
+                    client_fee: Some(client_fee),
+                    ..trade
+                },
+            );
+        }
+    });
+}
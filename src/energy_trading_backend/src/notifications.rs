@@ -0,0 +1,147 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{authorize_account, idempotent, next_id, AccountRef, Error, Memory, MEMORY_MANAGER};
+
+// a message recorded on an account about something that happened to it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Notification {
+    id: u64,
+    account: AccountRef,
+    // what the notification is about: "outbid", "auction_result", "payment_request",
+    // "settlement", "dispute", "recurring_agreement" or "forward"
+    topic: String,
+    reference_id: u64,
+    message: String,
+    created_at: u64,
+    // unread until the account marks it read
+    read_at: Option<u64>,
+}
+
+impl Storable for Notification {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Notification {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // (account, notification id) -> notification
+    static NOTIFICATION_STORAGE: RefCell<StableBTreeMap<(AccountRef, u64), Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct NotificationsRequest {
+    account: AccountRef,
+    // client or producer password of the account
+    password: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct MarkReadPayload {
+    account: AccountRef,
+    // client or producer password of the account
+    password: String,
+    // empty to mark every notification of the account read
+    notification_ids: Vec<u64>,
+    idempotency_key: Option<String>,
+}
+
+// record a notification on an account
+pub(crate) fn notify(account: AccountRef, topic: &str, reference_id: u64, message: String) {
+    let id = next_id();
+    let notification = Notification {
+        id,
+        account,
+        topic: topic.to_string(),
+        reference_id,
+        message,
+        created_at: ic_cdk::api::time(),
+        read_at: None,
+    };
+    NOTIFICATION_STORAGE.with(|s| s.borrow_mut().insert((account, id), notification));
+}
+
+fn unread_notifications(account: AccountRef) -> Vec<Notification> {
+    NOTIFICATION_STORAGE.with(|s| {
+        s.borrow()
+            .range((account, 0)..=(account, u64::MAX))
+            .map(|(_, notification)| notification)
+            .filter(|notification| notification.read_at.is_none())
+            .collect()
+    })
+}
+
+// get the notifications of a client or producer, oldest first, only available to the account
+#[ic_cdk::query]
+fn get_notifications(request: NotificationsRequest) -> Result<Vec<Notification>, Error> {
+    authorize_account(request.account, &request.password)?;
+    Ok(NOTIFICATION_STORAGE.with(|s| {
+        s.borrow()
+            .range((request.account, 0)..=(request.account, u64::MAX))
+            .map(|(_, notification)| notification)
+            .collect()
+    }))
+}
+
+// get the notifications of a client or producer that are not read yet, oldest first
+#[ic_cdk::query]
+fn get_unread_notifications(request: NotificationsRequest) -> Result<Vec<Notification>, Error> {
+    authorize_account(request.account, &request.password)?;
+    Ok(unread_notifications(request.account))
+}
+
+// mark notifications of an account read, returns how many were unread
+#[ic_cdk::update]
+fn mark_notifications_read(payload: MarkReadPayload) -> Result<u64, Error> {
+    idempotent(
+        "mark_notifications_read",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_account(payload.account, &payload.password)?;
+            let account = payload.account;
+            let unread: Vec<Notification> = match payload.notification_ids.is_empty() {
+                true => unread_notifications(account),
+                false => NOTIFICATION_STORAGE.with(|s| {
+                    let storage = s.borrow();
+                    payload
+                        .notification_ids
+                        .iter()
+                        .filter_map(|id| storage.get(&(account, *id)))
+                        .filter(|notification| notification.read_at.is_none())
+                        .collect()
+                }),
+            };
+            let now = ic_cdk::api::time();
+            let marked = unread.len() as u64;
+            NOTIFICATION_STORAGE.with(|s| {
+                let mut storage = s.borrow_mut();
+                for notification in unread {
+                    storage.insert(
+                        (account, notification.id),
+                        Notification {
+                            read_at: Some(now),
+                            ..notification
+                        },
+                    );
+                }
+            });
+            Ok(marked)
+        },
+    )
+}
@@ -0,0 +1,426 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, VecDeque};
+use std::{borrow::Cow, cell::RefCell};
+use validator::Validate;
+
+use crate::{
+    authorize_client, client_clawbacks, client_forward_compensation, idempotent, vintage_year,
+    AccountRef, Error, FacilityTechnology, Memory, Retirement, CLIENT_STORAGE,
+    CREDIT_ORDER_STORAGE, FACILITY_STORAGE, MEMORY_MANAGER, RETIREMENT_STORAGE, TRADE_STORAGE,
+    TRANSFER_STORAGE,
+};
+
+// an annual amount of credits a client aims to retire to offset its emissions
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct OffsetGoal {
+    client_id: u64,
+    year: u32,
+    // tonnes of CO2e to offset, one credit per tonne
+    target_credits: u64,
+    updated_at: u64,
+}
+
+// credits attributed to the order and facility vintage they came from, sources are unknown
+// for credits received by transfer or held before orders declared them
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct SourceAllocation {
+    pub(crate) producer_id: Option<u64>,
+    pub(crate) order_id: Option<u64>,
+    pub(crate) facility_id: Option<u64>,
+    pub(crate) technology: Option<FacilityTechnology>,
+    pub(crate) vintage: Option<u32>,
+    pub(crate) credits: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct SourceBreakdown {
+    vintage: Option<u32>,
+    technology: Option<FacilityTechnology>,
+    bought: u64,
+    retired: u64,
+    held: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct GoalProgress {
+    year: u32,
+    target_credits: u64,
+    retired: u64,
+    // credits left to retire in the year to meet the target
+    still_to_retire: u64,
+    // part of that not covered by the balance, open goals draw on the balance in year order
+    still_to_buy: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct PortfolioSummary {
+    client_id: u64,
+    balance: u64,
+    bought: u64,
+    retired: u64,
+    transferred_in: u64,
+    transferred_out: u64,
+    by_source: Vec<SourceBreakdown>,
+    goals: Vec<GoalProgress>,
+}
+
+impl Storable for OffsetGoal {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OffsetGoal {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // (client id, year) -> goal
+    static OFFSET_GOAL_STORAGE: RefCell<StableBTreeMap<(u64, u32), OffsetGoal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct OffsetGoalPayload {
+    client_id: u64,
+    password: String,
+    #[validate(range(min = 2000, max = 2100))]
+    year: u32,
+    #[validate(range(min = 1))]
+    target_credits: u64,
+    idempotency_key: Option<String>,
+}
+
+// the movements of a client balance in the order they happened
+enum LedgerEntry {
+    Acquired(SourceAllocation),
+    Retired(Retirement),
+    Disposed(u64),
+    // credits taken back from the client when the producer contested the order settlement
+    Reclaimed { order_id: u64, credits: u64 },
+}
+
+// the provenance of a client balance, replayed from its trades, transfers, retirements, contested
+// settlements and forward defaults
+pub(crate) struct ClientLedger {
+    pub(crate) bought: Vec<SourceAllocation>,
+    pub(crate) held: Vec<SourceAllocation>,
+    pub(crate) retirements: Vec<(Retirement, Vec<SourceAllocation>)>,
+    pub(crate) transferred_in: u64,
+    pub(crate) transferred_out: u64,
+}
+
+fn facility_technology(facility_id: Option<u64>) -> Option<FacilityTechnology> {
+    facility_id
+        .and_then(|id| FACILITY_STORAGE.with(|s| s.borrow().get(&id)))
+        .map(|facility| facility.technology)
+}
+
+// take credits from the oldest lots first, credits beyond the known lots have no source
+fn consume(lots: &mut VecDeque<SourceAllocation>, credits: u64) -> Vec<SourceAllocation> {
+    let mut taken = Vec::new();
+    let mut remaining = credits;
+    while remaining > 0 {
+        let Some(lot) = lots.front_mut() else {
+            taken.push(SourceAllocation {
+                credits: remaining,
+                ..Default::default()
+            });
+            break;
+        };
+        let credits = remaining.min(lot.credits);
+        taken.push(SourceAllocation {
+            credits,
+            ..lot.clone()
+        });
+        lot.credits -= credits;
+        remaining -= credits;
+        if lot.credits == 0 {
+            lots.pop_front();
+        }
+    }
+    taken
+}
+
+// take the credits of an order first, then from the oldest lots
+fn consume_order(
+    lots: &mut VecDeque<SourceAllocation>,
+    order_id: u64,
+    credits: u64,
+) -> Vec<SourceAllocation> {
+    let mut taken = Vec::new();
+    let mut remaining = credits;
+    for lot in lots.iter_mut() {
+        if remaining == 0 {
+            break;
+        }
+        if lot.order_id != Some(order_id) {
+            continue;
+        }
+        let credits = remaining.min(lot.credits);
+        taken.push(SourceAllocation {
+            credits,
+            ..lot.clone()
+        });
+        lot.credits -= credits;
+        remaining -= credits;
+    }
+    lots.retain(|lot| lot.credits > 0);
+    taken.extend(
+        consume(lots, remaining)
+            .into_iter()
+            .filter(|lot| lot.credits > 0),
+    );
+    taken
+}
+
+// replay the balance movements of a client, attributing retirements and transfers out to the
+// credits bought earliest
+pub(crate) fn client_ledger(client_id: u64) -> ClientLedger {
+    let account = AccountRef::Client { id: client_id };
+    // (time, rank, entry), acquisitions rank first so same-time disposals can draw on them
+    let mut entries: Vec<(u64, u8, LedgerEntry)> = Vec::new();
+    let mut bought = Vec::new();
+    TRADE_STORAGE.with(|s| {
+        for (_, trade) in s.borrow().iter() {
+            if trade.client_id != client_id {
+                continue;
+            }
+            let lot = SourceAllocation {
+                producer_id: Some(trade.producer_id),
+                order_id: Some(trade.order_id),
+                facility_id: trade.facility_id,
+                technology: facility_technology(trade.facility_id),
+                vintage: trade.vintage,
+                credits: trade.credits,
+            };
+            // a reversed purchase is replayed until the clawback takes it back, but was not bought
+            if trade.reversed_at.is_none() {
+                bought.push(lot.clone());
+            }
+            // the taker fee charged in credits never reached the client balance
+            let received = SourceAllocation {
+                credits: trade.received(),
+                ..lot
+            };
+            entries.push((trade.settled_at, 0, LedgerEntry::Acquired(received)));
+        }
+    });
+    let (mut transferred_in, mut transferred_out) = (0, 0);
+    TRANSFER_STORAGE.with(|s| {
+        for (_, transfer) in s.borrow().iter() {
+            if transfer.to == account {
+                transferred_in += transfer.amount;
+                let lot = SourceAllocation {
+                    credits: transfer.amount,
+                    ..Default::default()
+                };
+                entries.push((transfer.created_at, 0, LedgerEntry::Acquired(lot)));
+            }
+            if transfer.from == account {
+                transferred_out += transfer.amount;
+                let debited = transfer.amount + transfer.fee;
+                entries.push((transfer.created_at, 1, LedgerEntry::Disposed(debited)));
+            }
+        }
+    });
+    RETIREMENT_STORAGE.with(|s| {
+        for (_, retirement) in s.borrow().iter() {
+            if retirement.client_id == client_id {
+                entries.push((retirement.retired_at, 1, LedgerEntry::Retired(retirement)));
+            }
+        }
+    });
+    for clawback in client_clawbacks(client_id) {
+        let reclaimed = LedgerEntry::Reclaimed {
+            order_id: clawback.order_id,
+            credits: clawback.credits,
+        };
+        entries.push((clawback.taken_at, 1, reclaimed));
+        if let Some((returned_at, credits)) = clawback.returned {
+            let credit_order = CREDIT_ORDER_STORAGE.with(|s| s.borrow().get(&clawback.order_id));
+            let lot = SourceAllocation {
+                producer_id: credit_order.as_ref().map(|order| order.producer_id),
+                order_id: Some(clawback.order_id),
+                facility_id: credit_order.as_ref().and_then(|order| order.facility_id),
+                technology: facility_technology(
+                    credit_order.as_ref().and_then(|order| order.facility_id),
+                ),
+                vintage: credit_order.as_ref().and_then(|order| order.vintage),
+                credits,
+            };
+            entries.push((returned_at, 0, LedgerEntry::Acquired(lot)));
+        }
+    }
+    // collateral of defaulted forwards, the credits come from the producer with no declared source
+    for (closed_at, producer_id, credits) in client_forward_compensation(client_id) {
+        let lot = SourceAllocation {
+            producer_id: Some(producer_id),
+            credits,
+            ..Default::default()
+        };
+        entries.push((closed_at, 0, LedgerEntry::Acquired(lot)));
+    }
+    entries.sort_by_key(|(time, rank, _)| (*time, *rank));
+
+    let mut lots = VecDeque::new();
+    let mut retirements = Vec::new();
+    for (_, _, entry) in entries {
+        match entry {
+            LedgerEntry::Acquired(lot) => lots.push_back(lot),
+            LedgerEntry::Disposed(credits) => {
+                consume(&mut lots, credits);
+            }
+            LedgerEntry::Reclaimed { order_id, credits } => {
+                consume_order(&mut lots, order_id, credits);
+            }
+            LedgerEntry::Retired(retirement) => {
+                let sources = consume(&mut lots, retirement.credits);
+                retirements.push((retirement, sources));
+            }
+        }
+    }
+    // balances from before their movements were recorded have no known source
+    let balance = CLIENT_STORAGE.with(|s| s.borrow().get(&client_id).map_or(0, |c| c.credits));
+    let replayed: u64 = lots.iter().map(|lot| lot.credits).sum();
+    match balance.cmp(&replayed) {
+        Ordering::Greater => lots.push_back(SourceAllocation {
+            credits: balance - replayed,
+            ..Default::default()
+        }),
+        Ordering::Less => {
+            consume(&mut lots, replayed - balance);
+        }
+        Ordering::Equal => {}
+    }
+    ClientLedger {
+        bought,
+        held: lots.into_iter().collect(),
+        retirements,
+        transferred_in,
+        transferred_out,
+    }
+}
+
+// set the credits a client aims to retire in a year
+#[ic_cdk::update]
+fn set_offset_goal(payload: OffsetGoalPayload) -> Result<OffsetGoal, Error> {
+    idempotent(
+        "set_offset_goal",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            authorize_client(payload.client_id, &payload.password)?;
+            let goal = OffsetGoal {
+                client_id: payload.client_id,
+                year: payload.year,
+                target_credits: payload.target_credits,
+                updated_at: ic_cdk::api::time(),
+            };
+            OFFSET_GOAL_STORAGE.with(|s| {
+                s.borrow_mut()
+                    .insert((payload.client_id, payload.year), goal.clone())
+            });
+            Ok(goal)
+        },
+    )
+}
+
+// get the portfolio of a client, its credits by vintage and technology and its goal progress
+#[ic_cdk::query]
+fn get_portfolio(client_id: u64) -> Result<PortfolioSummary, Error> {
+    let client = CLIENT_STORAGE
+        .with(|s| s.borrow().get(&client_id))
+        .ok_or(Error::NotFound {
+            msg: format!("client with id: {} not found", client_id),
+        })?;
+    let ledger = client_ledger(client_id);
+
+    let mut by_source: BTreeMap<(Option<u32>, Option<FacilityTechnology>), SourceBreakdown> =
+        BTreeMap::new();
+    let retired = ledger.retirements.iter().flat_map(|(_, sources)| sources);
+    let allocations = (ledger.bought.iter().map(|allocation| (allocation, 0)))
+        .chain(retired.map(|allocation| (allocation, 1)))
+        .chain(ledger.held.iter().map(|allocation| (allocation, 2)));
+    for (allocation, column) in allocations {
+        let breakdown = by_source
+            .entry((allocation.vintage, allocation.technology))
+            .or_insert_with(|| SourceBreakdown {
+                vintage: allocation.vintage,
+                technology: allocation.technology,
+                ..Default::default()
+            });
+        match column {
+            0 => breakdown.bought += allocation.credits,
+            1 => breakdown.retired += allocation.credits,
+            _ => breakdown.held += allocation.credits,
+        }
+    }
+
+    let mut retired_by_year: BTreeMap<u32, u64> = BTreeMap::new();
+    for (retirement, _) in &ledger.retirements {
+        *retired_by_year
+            .entry(vintage_year(retirement.retired_at))
+            .or_default() += retirement.credits;
+    }
+    let current_year = vintage_year(ic_cdk::api::time());
+    let mut available = client.credits;
+    let goals = OFFSET_GOAL_STORAGE.with(|s| {
+        s.borrow()
+            .range((client_id, 0)..=(client_id, u32::MAX))
+            .map(|(_, goal)| {
+                let retired = retired_by_year.get(&goal.year).copied().unwrap_or(0);
+                let still_to_retire = goal.target_credits.saturating_sub(retired);
+                // past years can no longer be met from the balance
+                let covered = match goal.year >= current_year {
+                    true => still_to_retire.min(available),
+                    false => 0,
+                };
+                available -= covered;
+                GoalProgress {
+                    year: goal.year,
+                    target_credits: goal.target_credits,
+                    retired,
+                    still_to_retire,
+                    still_to_buy: match goal.year >= current_year {
+                        true => still_to_retire - covered,
+                        false => 0,
+                    },
+                }
+            })
+            .collect()
+    });
+
+    Ok(PortfolioSummary {
+        client_id,
+        balance: client.credits,
+        bought: ledger
+            .bought
+            .iter()
+            .map(|allocation| allocation.credits)
+            .sum(),
+        retired: ledger
+            .retirements
+            .iter()
+            .map(|(retirement, _)| retirement.credits)
+            .sum(),
+        transferred_in: ledger.transferred_in,
+        transferred_out: ledger.transferred_out,
+        by_source: by_source.into_values().collect(),
+        goals,
+    })
+}
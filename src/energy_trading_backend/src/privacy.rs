@@ -0,0 +1,281 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+use validator::{Validate, ValidationError};
+
+use crate::{
+    authorize_account, idempotent, validate_max_bytes, AccountRef, Error, Memory,
+    VerificationStatus, CLIENT_STORAGE, CONTRACT_STORAGE, MEMORY_MANAGER, PRODUCER_STORAGE,
+    TRADE_STORAGE,
+};
+
+// how much of a profile a viewer gets to see
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, PartialOrd,
+)]
+pub(crate) enum Disclosure {
+    // account id and display name only
+    Pseudonymous,
+    // also the legal name, organization, registry id, country, balances and status
+    #[default]
+    Organization,
+    // also the email and phone
+    Contact,
+}
+
+// what other accounts see of an account
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct PrivacySettings {
+    // shown in public views instead of the account name
+    display_name: Option<String>,
+    // what accounts that traded with this one see
+    counterparty_disclosure: Disclosure,
+    updated_at: u64,
+}
+
+// a client or producer profile with the fields the viewer may not see left out
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct AccountProfile {
+    account: AccountRef,
+    display_name: String,
+    disclosure: Disclosure,
+    name: Option<String>,
+    organization_name: Option<String>,
+    legal_entity_id: Option<String>,
+    country_code: Option<String>,
+    email: Option<String>,
+    phone: Option<String>,
+    credits: Option<u64>,
+    // producers only
+    energy_supply: Option<u64>,
+    verification: Option<VerificationStatus>,
+    // None when the account is active or the viewer may not see its status
+    deactivated_at: Option<u64>,
+}
+
+impl Storable for PrivacySettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PrivacySettings {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PRIVACY_SETTINGS_STORAGE: RefCell<StableBTreeMap<AccountRef, PrivacySettings, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct PrivacySettingsPayload {
+    account: AccountRef,
+    // client or producer password of the account
+    password: String,
+    #[validate(
+        length(min = 1),
+        custom = "validate_max_bytes::<64>",
+        custom = "validate_display_name"
+    )]
+    display_name: Option<String>,
+    counterparty_disclosure: Disclosure,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ProfileRequest {
+    account: AccountRef,
+    // the account asking, to get the counterparty view after a trade
+    viewer: Option<AccountRef>,
+    // contract password for admins, client or producer password for the account owner or viewer
+    password: Option<String>,
+}
+
+fn privacy_settings(account: AccountRef) -> PrivacySettings {
+    PRIVACY_SETTINGS_STORAGE
+        .with(|s| s.borrow().get(&account))
+        .unwrap_or_default()
+}
+
+// the name shown for an account in public views, chosen by the account or derived from its id
+pub(crate) fn display_name(account: AccountRef) -> String {
+    privacy_settings(account)
+        .display_name
+        .unwrap_or_else(|| match account {
+            AccountRef::Client { id } => format!("client-{}", id),
+            AccountRef::Producer { id } => format!("producer-{}", id),
+        })
+}
+
+// names shown for accounts without a display name cannot be taken by another account
+fn validate_display_name(display_name: &str) -> Result<(), ValidationError> {
+    let lowercase = display_name.to_ascii_lowercase();
+    let reserved = ["client-", "producer-"].iter().any(|prefix| {
+        lowercase
+            .strip_prefix(prefix)
+            .is_some_and(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+    });
+    match reserved {
+        true => Err(ValidationError::new("reserved_display_name")),
+        false => Ok(()),
+    }
+}
+
+fn password_matches(account: AccountRef, password: Option<&String>) -> bool {
+    password.is_some_and(|password| authorize_account(account, password).is_ok())
+}
+
+// the profile of an account as seen with a level of disclosure
+pub(crate) fn account_profile(
+    account: AccountRef,
+    disclosure: Disclosure,
+) -> Result<AccountProfile, Error> {
+    let mut profile = AccountProfile {
+        account,
+        display_name: display_name(account),
+        disclosure,
+        ..Default::default()
+    };
+    let disclosed = disclosure >= Disclosure::Organization;
+    let (name, organization_name, legal_entity_id, country_code, email, phone) = match account {
+        AccountRef::Client { id } => {
+            let client = CLIENT_STORAGE
+                .with(|s| s.borrow().get(&id))
+                .ok_or(Error::NotFound {
+                    msg: format!("client with id: {} not found", id),
+                })?;
+            if disclosed {
+                profile.credits = Some(client.credits);
+                profile.verification = Some(client.verification);
+                profile.deactivated_at = client.deactivated_at;
+            }
+            (
+                client.name,
+                client.organization_name,
+                client.legal_entity_id,
+                client.country_code,
+                client.email,
+                client.phone,
+            )
+        }
+        AccountRef::Producer { id } => {
+            let producer =
+                PRODUCER_STORAGE
+                    .with(|s| s.borrow().get(&id))
+                    .ok_or(Error::NotFound {
+                        msg: format!("producer with id: {} not found", id),
+                    })?;
+            if disclosed {
+                profile.credits = Some(producer.credits);
+                profile.energy_supply = Some(producer.energy_supply);
+                profile.verification = Some(producer.verification);
+                profile.deactivated_at = producer.deactivated_at;
+            }
+            (
+                producer.name,
+                producer.organization_name,
+                producer.legal_entity_id,
+                producer.country_code,
+                producer.email,
+                producer.phone,
+            )
+        }
+    };
+    if disclosed {
+        profile.name = Some(name);
+        profile.organization_name = Some(organization_name);
+        profile.legal_entity_id = Some(legal_entity_id);
+        profile.country_code = Some(country_code);
+    }
+    if disclosure == Disclosure::Contact {
+        profile.email = Some(email);
+        profile.phone = Some(phone);
+    }
+    Ok(profile)
+}
+
+// the profile of an account as anyone may see it
+pub(crate) fn public_profile(account: AccountRef) -> Result<AccountProfile, Error> {
+    account_profile(account, Disclosure::Pseudonymous)
+}
+
+// whether two accounts have settled a trade with each other
+fn traded_with(account: AccountRef, viewer: AccountRef) -> bool {
+    let (client_id, producer_id) = match (account, viewer) {
+        (AccountRef::Client { id: client_id }, AccountRef::Producer { id: producer_id })
+        | (AccountRef::Producer { id: producer_id }, AccountRef::Client { id: client_id }) => {
+            (client_id, producer_id)
+        }
+        _ => return false,
+    };
+    TRADE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .any(|(_, trade)| trade.client_id == client_id && trade.producer_id == producer_id)
+    })
+}
+
+// the disclosure a request is entitled to
+fn disclosure_for(request: &ProfileRequest) -> Disclosure {
+    let password = request.password.as_ref();
+    let is_admin = CONTRACT_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .is_some_and(|contract| Some(&contract.password) == password);
+    if is_admin || password_matches(request.account, password) {
+        return Disclosure::Contact;
+    }
+    let Some(viewer) = request.viewer else {
+        return Disclosure::Pseudonymous;
+    };
+    match password_matches(viewer, password) && traded_with(request.account, viewer) {
+        true => privacy_settings(request.account).counterparty_disclosure,
+        false => Disclosure::Pseudonymous,
+    }
+}
+
+// get the profile of an account, in full for its owner and admins, as set by the account for
+// its counterparties and pseudonymous for anyone else
+#[ic_cdk::query]
+fn get_account_profile(request: ProfileRequest) -> Result<AccountProfile, Error> {
+    account_profile(request.account, disclosure_for(&request))
+}
+
+// set the display name of an account and what its counterparties see
+#[ic_cdk::update]
+fn set_privacy_settings(payload: PrivacySettingsPayload) -> Result<PrivacySettings, Error> {
+    idempotent(
+        "set_privacy_settings",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            authorize_account(payload.account, &payload.password)?;
+            let settings = PrivacySettings {
+                display_name: payload.display_name,
+                counterparty_disclosure: payload.counterparty_disclosure,
+                updated_at: ic_cdk::api::time(),
+            };
+            PRIVACY_SETTINGS_STORAGE
+                .with(|s| s.borrow_mut().insert(payload.account, settings.clone()));
+            Ok(settings)
+        },
+    )
+}
+
+// get the privacy settings of an account
+#[ic_cdk::query]
+fn get_privacy_settings(account: AccountRef) -> PrivacySettings {
+    privacy_settings(account)
+}
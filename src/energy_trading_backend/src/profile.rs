@@ -0,0 +1,323 @@
+use validator::ValidationError;
+
+use crate::{Error, CLIENT_STORAGE, PRODUCER_STORAGE};
+
+// ISO 3166-1 alpha-2 country codes
+const COUNTRY_CODES: [&str; 249] = [
+    "AD", "AE", "AF", "AG", "AI", "AL", "AM", "AO", "AQ", "AR", "AS", "AT", "AU", "AW", "AX", "AZ",
+    "BA", "BB", "BD", "BE", "BF", "BG", "BH", "BI", "BJ", "BL", "BM", "BN", "BO", "BQ", "BR", "BS",
+    "BT", "BV", "BW", "BY", "BZ", "CA", "CC", "CD", "CF", "CG", "CH", "CI", "CK", "CL", "CM", "CN",
+    "CO", "CR", "CU", "CV", "CW", "CX", "CY", "CZ", "DE", "DJ", "DK", "DM", "DO", "DZ", "EC", "EE",
+    "EG", "EH", "ER", "ES", "ET", "FI", "FJ", "FK", "FM", "FO", "FR", "GA", "GB", "GD", "GE", "GF",
+    "GG", "GH", "GI", "GL", "GM", "GN", "GP", "GQ", "GR", "GS", "GT", "GU", "GW", "GY", "HK", "HM",
+    "HN", "HR", "HT", "HU", "ID", "IE", "IL", "IM", "IN", "IO", "IQ", "IR", "IS", "IT", "JE", "JM",
+    "JO", "JP", "KE", "KG", "KH", "KI", "KM", "KN", "KP", "KR", "KW", "KY", "KZ", "LA", "LB", "LC",
+    "LI", "LK", "LR", "LS", "LT", "LU", "LV", "LY", "MA", "MC", "MD", "ME", "MF", "MG", "MH", "MK",
+    "ML", "MM", "MN", "MO", "MP", "MQ", "MR", "MS", "MT", "MU", "MV", "MW", "MX", "MY", "MZ", "NA",
+    "NC", "NE", "NF", "NG", "NI", "NL", "NO", "NP", "NR", "NU", "NZ", "OM", "PA", "PE", "PF", "PG",
+    "PH", "PK", "PL", "PM", "PN", "PR", "PS", "PT", "PW", "PY", "QA", "RE", "RO", "RS", "RU", "RW",
+    "SA", "SB", "SC", "SD", "SE", "SG", "SH", "SI", "SJ", "SK", "SL", "SM", "SN", "SO", "SR", "SS",
+    "ST", "SV", "SX", "SY", "SZ", "TC", "TD", "TF", "TG", "TH", "TJ", "TK", "TL", "TM", "TN", "TO",
+    "TR", "TT", "TV", "TW", "TZ", "UA", "UG", "UM", "US", "UY", "UZ", "VA", "VC", "VE", "VG", "VI",
+    "VN", "VU", "WF", "WS", "YE", "YT", "ZA", "ZM", "ZW",
+];
+
+// phone numbers in E.164 format, a '+' followed by 7 to 15 digits without a leading zero
+pub(crate) fn validate_e164_phone(phone: &str) -> Result<(), ValidationError> {
+    let digits = phone.strip_prefix('+').unwrap_or_default();
+    if (7..=15).contains(&digits.len())
+        && !digits.starts_with('0')
+        && digits.bytes().all(|b| b.is_ascii_digit())
+    {
+        return Ok(());
+    }
+    Err(ValidationError::new("e164_phone"))
+}
+
+pub(crate) fn validate_country_code(country_code: &str) -> Result<(), ValidationError> {
+    if COUNTRY_CODES.contains(&country_code.to_ascii_uppercase().as_str()) {
+        return Ok(());
+    }
+    Err(ValidationError::new("iso_country_code"))
+}
+
+// validator counts characters, stored records are bounded in bytes
+pub(crate) fn validate_max_bytes<const MAX: usize>(value: &str) -> Result<(), ValidationError> {
+    if value.len() <= MAX {
+        return Ok(());
+    }
+    Err(ValidationError::new("max_bytes"))
+}
+
+// cut text written by the canister itself to at most max bytes, at a character boundary
+pub(crate) fn truncate_bytes(mut text: String, max: usize) -> String {
+    if text.len() > max {
+        let mut end = max;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+    text
+}
+
+// registry identifiers such as an LEI, letters, digits and dashes only
+pub(crate) fn validate_legal_entity_id(legal_entity_id: &str) -> Result<(), ValidationError> {
+    if legal_entity_id
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    {
+        return Ok(());
+    }
+    Err(ValidationError::new("legal_entity_id"))
+}
+
+// the contact fields that identify an organization across registrations
+pub(crate) struct ProfileIdentity<'a> {
+    pub(crate) email: &'a str,
+    pub(crate) phone: &'a str,
+    pub(crate) legal_entity_id: &'a str,
+}
+
+fn find_duplicate<'a>(
+    identity: &ProfileIdentity,
+    mut existing: impl Iterator<Item = (u64, ProfileIdentity<'a>)>,
+    exclude_id: Option<u64>,
+) -> Option<&'static str> {
+    existing.find_map(|(id, other)| {
+        if Some(id) == exclude_id {
+            None
+        } else if other.email.eq_ignore_ascii_case(identity.email) {
+            Some("email")
+        } else if other.phone == identity.phone {
+            Some("phone")
+        } else if other
+            .legal_entity_id
+            .eq_ignore_ascii_case(identity.legal_entity_id)
+        {
+            Some("legal entity id")
+        } else {
+            None
+        }
+    })
+}
+
+// refuse a client registration that reuses the email, phone or legal entity id of another client
+pub(crate) fn ensure_unique_client(
+    identity: &ProfileIdentity,
+    exclude_id: Option<u64>,
+) -> Result<(), Error> {
+    let duplicate = CLIENT_STORAGE.with(|s| {
+        let clients: Vec<_> = s.borrow().iter().map(|(_, client)| client).collect();
+        find_duplicate(
+            identity,
+            clients.iter().map(|client| {
+                (
+                    client.id,
+                    ProfileIdentity {
+                        email: &client.email,
+                        phone: &client.phone,
+                        legal_entity_id: &client.legal_entity_id,
+                    },
+                )
+            }),
+            exclude_id,
+        )
+    });
+    match duplicate {
+        Some(field) => Err(Error::InvalidPayload {
+            msg: format!("A client with this {} is already registered", field),
+        }),
+        None => Ok(()),
+    }
+}
+
+// refuse a producer registration that reuses the email, phone or legal entity id of another producer
+pub(crate) fn ensure_unique_producer(
+    identity: &ProfileIdentity,
+    exclude_id: Option<u64>,
+) -> Result<(), Error> {
+    let duplicate = PRODUCER_STORAGE.with(|s| {
+        let producers: Vec<_> = s.borrow().iter().map(|(_, producer)| producer).collect();
+        find_duplicate(
+            identity,
+            producers.iter().map(|producer| {
+                (
+                    producer.id,
+                    ProfileIdentity {
+                        email: &producer.email,
+                        phone: &producer.phone,
+                        legal_entity_id: &producer.legal_entity_id,
+                    },
+                )
+            }),
+            exclude_id,
+        )
+    });
+    match duplicate {
+        Some(field) => Err(Error::InvalidPayload {
+            msg: format!("A producer with this {} is already registered", field),
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Client, ClientPayload, Producer, ProducerPayload, VerificationStatus};
+    use ic_stable_structures::{BoundedStorable, Storable};
+    use validator::Validate;
+
+    fn identity<'a>(
+        email: &'a str,
+        phone: &'a str,
+        legal_entity_id: &'a str,
+    ) -> ProfileIdentity<'a> {
+        ProfileIdentity {
+            email,
+            phone,
+            legal_entity_id,
+        }
+    }
+
+    #[test]
+    fn phones_must_be_e164() {
+        assert!(validate_e164_phone("+14155550123").is_ok());
+        assert!(validate_e164_phone("+1234567").is_ok());
+        assert!(validate_e164_phone("+123456789012345").is_ok());
+        assert!(validate_e164_phone("14155550123").is_err());
+        assert!(validate_e164_phone("+123456").is_err());
+        assert!(validate_e164_phone("+1234567890123456").is_err());
+        assert!(validate_e164_phone("+04155550123").is_err());
+        assert!(validate_e164_phone("+1 415 555 0123").is_err());
+        assert!(validate_e164_phone("+").is_err());
+    }
+
+    #[test]
+    fn country_codes_must_be_iso_3166() {
+        assert!(validate_country_code("DE").is_ok());
+        assert!(validate_country_code("de").is_ok());
+        assert!(validate_country_code("XX").is_err());
+        assert!(validate_country_code("DEU").is_err());
+        assert!(validate_country_code("").is_err());
+    }
+
+    #[test]
+    fn legal_entity_ids_are_alphanumeric_with_dashes() {
+        assert!(validate_legal_entity_id("5493001KJTIIGC8Y1R12").is_ok());
+        assert!(validate_legal_entity_id("HRB-12345").is_ok());
+        assert!(validate_legal_entity_id("HRB 12345").is_err());
+        assert!(validate_legal_entity_id("HRB_12345").is_err());
+        assert!(validate_legal_entity_id("ÄG-1").is_err());
+    }
+
+    #[test]
+    fn text_is_bounded_in_bytes() {
+        assert!(validate_max_bytes::<4>("abcd").is_ok());
+        assert!(validate_max_bytes::<4>("ééé").is_err());
+        assert_eq!(truncate_bytes("ééé".to_string(), 5), "éé");
+        assert_eq!(truncate_bytes("abc".to_string(), 5), "abc");
+    }
+
+    #[test]
+    fn duplicates_match_email_phone_or_legal_entity_id() {
+        let existing = [
+            (1, identity("ops@solar.example", "+14155550123", "LEI-1")),
+            (2, identity("desk@wind.example", "+14155550124", "LEI-2")),
+        ];
+        let find = |candidate: &ProfileIdentity, exclude_id| {
+            find_duplicate(
+                candidate,
+                existing.iter().map(|(id, other)| {
+                    (
+                        *id,
+                        identity(other.email, other.phone, other.legal_entity_id),
+                    )
+                }),
+                exclude_id,
+            )
+        };
+        let email = identity("OPS@solar.example", "+14155550199", "LEI-9");
+        assert_eq!(find(&email, None), Some("email"));
+        let phone = identity("new@hydro.example", "+14155550124", "LEI-9");
+        assert_eq!(find(&phone, None), Some("phone"));
+        let legal_entity_id = identity("new@hydro.example", "+14155550199", "lei-2");
+        assert_eq!(find(&legal_entity_id, None), Some("legal entity id"));
+        let unique = identity("new@hydro.example", "+14155550199", "LEI-9");
+        assert_eq!(find(&unique, None), None);
+        // an account updating its own profile is not its own duplicate
+        assert_eq!(find(&email, Some(1)), None);
+    }
+
+    #[test]
+    fn largest_accounts_fit_their_records() {
+        let name = "\u{10ffff}".repeat(32);
+        // 254 bytes, the longest address the email validator and the byte bound let through
+        let email = format!(
+            "{}@{}.{}.{}.example",
+            "a".repeat(64),
+            "b".repeat(60),
+            "c".repeat(60),
+            "d".repeat(59)
+        );
+        assert_eq!(email.len(), 254);
+        let client_payload = ClientPayload {
+            name: name.clone(),
+            organization_name: name.clone(),
+            legal_entity_id: "A".repeat(64),
+            country_code: "DE".to_string(),
+            email: email.clone(),
+            phone: "+123456789012345".to_string(),
+            password: name.clone(),
+            idempotency_key: None,
+        };
+        assert!(client_payload.validate().is_ok());
+        let producer_payload = ProducerPayload {
+            name: client_payload.name.clone(),
+            organization_name: client_payload.organization_name.clone(),
+            legal_entity_id: client_payload.legal_entity_id.clone(),
+            country_code: client_payload.country_code.clone(),
+            email: client_payload.email.clone(),
+            phone: client_payload.phone.clone(),
+            password: client_payload.password.clone(),
+            idempotency_key: None,
+        };
+        assert!(producer_payload.validate().is_ok());
+        let too_long = ClientPayload {
+            name: format!("{}a", name),
+            ..client_payload.clone()
+        };
+        assert!(too_long.validate().is_err());
+
+        let client = Client {
+            id: u64::MAX,
+            name: client_payload.name,
+            organization_name: client_payload.organization_name,
+            legal_entity_id: client_payload.legal_entity_id,
+            country_code: client_payload.country_code,
+            email: client_payload.email,
+            phone: client_payload.phone,
+            password: Some(client_payload.password),
+            credits: u64::MAX,
+            verification: VerificationStatus::Suspended,
+            deactivated_at: Some(u64::MAX),
+        };
+        assert!(client.to_bytes().len() <= Client::MAX_SIZE as usize);
+        let producer = Producer {
+            id: u64::MAX,
+            name: producer_payload.name,
+            organization_name: producer_payload.organization_name,
+            legal_entity_id: producer_payload.legal_entity_id,
+            country_code: producer_payload.country_code,
+            email: producer_payload.email,
+            password: producer_payload.password,
+            phone: producer_payload.phone,
+            energy_supply: u64::MAX,
+            credits: u64::MAX,
+            verification: VerificationStatus::Suspended,
+            deactivated_at: Some(u64::MAX),
+        };
+        assert!(producer.to_bytes().len() <= Producer::MAX_SIZE as usize);
+    }
+}
@@ -0,0 +1,591 @@
+use candid::{Decode, Encode};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+    SignWithEcdsaArgument,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use k256::ecdsa::signature::hazmat::PrehashVerifier;
+use k256::ecdsa::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use std::{borrow::Cow, cell::Cell, cell::RefCell};
+
+use crate::{authorize_admin, idempotent, next_id, AccountRef, Error, Memory, MEMORY_MANAGER};
+
+// receipts signed by one run of the threshold signer, and the wait before retrying failures
+const MAX_SIGNED_PER_RUN: usize = 20;
+const SIGNING_RETRY_SECONDS: u64 = 60;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ReceiptKind {
+    Settlement { order_id: u64, trade_id: u64 },
+    Mint { facility_id: Option<u64> },
+    Retirement { retirement_id: u64 },
+}
+
+impl Default for ReceiptKind {
+    fn default() -> Self {
+        ReceiptKind::Mint { facility_id: None }
+    }
+}
+
+// who signs receipts, receipts stay unsigned until admins configure a threshold ECDSA key
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum SignerKind {
+    #[default]
+    Unconfigured,
+    ThresholdEcdsa {
+        key_name: String,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ReceiptSigner {
+    kind: SignerKind,
+    // hex encoded SEC1 compressed secp256k1 key, fetched on first use for threshold ECDSA
+    public_key: Option<String>,
+}
+
+// the signed content of a receipt
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ReceiptBody {
+    receipt_id: u64,
+    canister_id: String,
+    kind: ReceiptKind,
+    account: AccountRef,
+    credits: u64,
+    price_per_credit: Option<u64>,
+    issued_at: u64,
+}
+
+// proof of a settlement, mint or retirement that can be verified offline against the signer key
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Receipt {
+    pub(crate) id: u64,
+    kind: ReceiptKind,
+    account: AccountRef,
+    credits: u64,
+    price_per_credit: Option<u64>,
+    issued_at: u64,
+    // the body as JSON, the exact bytes that were hashed
+    document: String,
+    // hex encoded SHA-256 of the document
+    message_hash: String,
+    // hex encoded 64 byte secp256k1 signature of the message hash, None until signed
+    signature: Option<String>,
+    public_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct VerifyReceiptPayload {
+    document: String,
+    signature: String,
+    // the key of this canister when not set
+    public_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ReceiptVerification {
+    valid: bool,
+    message_hash: String,
+    public_key: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ReceiptSignerPayload {
+    contract_password: String,
+    signer: SignerKind,
+    idempotency_key: Option<String>,
+}
+
+impl Storable for Receipt {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for ReceiptSigner {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes, a signer stored with the retired local stand-in key is unconfigured
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_default()
+    }
+}
+
+impl BoundedStorable for Receipt {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for ReceiptSigner {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static RECEIPT_STORAGE: RefCell<StableBTreeMap<u64, Receipt, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+    ));
+
+    static RECEIPT_SIGNER_STORAGE: RefCell<StableBTreeMap<u64, ReceiptSigner, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+
+    // a threshold signing run is pending, so receipts issued meanwhile do not start another
+    static SIGNING_SCHEDULED: Cell<bool> = const { Cell::new(false) };
+}
+
+fn receipt_signer() -> ReceiptSigner {
+    RECEIPT_SIGNER_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .unwrap_or_default()
+}
+
+fn save_receipt(receipt: &Receipt) {
+    RECEIPT_STORAGE.with(|s| s.borrow_mut().insert(receipt.id, receipt.clone()));
+}
+
+// an unsigned receipt for a body, with the JSON document and its hash
+fn receipt_for(body: ReceiptBody) -> Receipt {
+    let document = serde_json::to_string(&body).unwrap();
+    let message_hash = Sha256::digest(document.as_bytes());
+    Receipt {
+        id: body.receipt_id,
+        kind: body.kind,
+        account: body.account,
+        credits: body.credits,
+        price_per_credit: body.price_per_credit,
+        issued_at: body.issued_at,
+        document,
+        message_hash: hex::encode(message_hash),
+        signature: None,
+        public_key: None,
+    }
+}
+
+// record a receipt, it is signed shortly after by a timer once a threshold ECDSA key is configured
+pub(crate) fn issue_receipt(
+    kind: ReceiptKind,
+    account: AccountRef,
+    credits: u64,
+    price_per_credit: Option<u64>,
+) -> Receipt {
+    let receipt = receipt_for(ReceiptBody {
+        receipt_id: next_id(),
+        canister_id: ic_cdk::id().to_text(),
+        kind,
+        account,
+        credits,
+        price_per_credit,
+        issued_at: ic_cdk::api::time(),
+    });
+    save_receipt(&receipt);
+    if let SignerKind::ThresholdEcdsa { .. } = receipt_signer().kind {
+        schedule_signing(Duration::ZERO);
+    }
+    receipt
+}
+
+fn schedule_signing(delay: Duration) {
+    if SIGNING_SCHEDULED.with(|scheduled| scheduled.replace(true)) {
+        return;
+    }
+    ic_cdk_timers::set_timer(delay, || ic_cdk::spawn(sign_pending_receipts()));
+}
+
+async fn sign_pending_receipts() {
+    let next_run = sign_receipt_batch().await;
+    SIGNING_SCHEDULED.with(|scheduled| scheduled.set(false));
+    if let Some(delay) = next_run {
+        schedule_signing(delay);
+    }
+}
+
+// sign a batch of receipts not signed by the threshold key yet, returns when to run again if
+// receipts are left or the signer failed
+async fn sign_receipt_batch() -> Option<Duration> {
+    let signer = receipt_signer();
+    let SignerKind::ThresholdEcdsa { key_name } = signer.kind.clone() else {
+        return None;
+    };
+    let key_id = EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: key_name,
+    };
+    let retry = Some(Duration::from_secs(SIGNING_RETRY_SECONDS));
+
+    let public_key = match signer.public_key {
+        Some(public_key) => public_key,
+        None => {
+            let argument = EcdsaPublicKeyArgument {
+                canister_id: None,
+                derivation_path: Vec::new(),
+                key_id: key_id.clone(),
+            };
+            let Ok((response,)) = ecdsa_public_key(argument).await else {
+                return retry;
+            };
+            let public_key = hex::encode(response.public_key);
+            RECEIPT_SIGNER_STORAGE.with(|s| {
+                s.borrow_mut().insert(
+                    0,
+                    ReceiptSigner {
+                        public_key: Some(public_key.clone()),
+                        ..signer
+                    },
+                )
+            });
+            public_key
+        }
+    };
+
+    let pending: Vec<Receipt> = RECEIPT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, receipt)| receipt)
+            // receipts signed by an earlier key, such as the retired local stand-in, are re-signed
+            .filter(|receipt| {
+                receipt.signature.is_none() || receipt.public_key.as_ref() != Some(&public_key)
+            })
+            .take(MAX_SIGNED_PER_RUN + 1)
+            .collect()
+    });
+    let more = pending.len() > MAX_SIGNED_PER_RUN;
+    for receipt in pending.into_iter().take(MAX_SIGNED_PER_RUN) {
+        let argument = SignWithEcdsaArgument {
+            message_hash: hex::decode(&receipt.message_hash).unwrap(),
+            derivation_path: Vec::new(),
+            key_id: key_id.clone(),
+        };
+        let Ok((response,)) = sign_with_ecdsa(argument).await else {
+            return retry;
+        };
+        save_receipt(&Receipt {
+            signature: Some(hex::encode(response.signature)),
+            public_key: Some(public_key.clone()),
+            ..receipt
+        });
+    }
+    more.then_some(Duration::ZERO)
+}
+
+// resume signing receipts left unsigned by an upgrade
+pub(crate) fn restore_receipt_signing() {
+    if let SignerKind::ThresholdEcdsa { .. } = receipt_signer().kind {
+        schedule_signing(Duration::ZERO);
+    }
+}
+
+// the latest settlement receipt of a credit order
+pub(crate) fn order_receipt_id(order_id: u64) -> Option<u64> {
+    RECEIPT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, receipt)| {
+                matches!(receipt.kind, ReceiptKind::Settlement { order_id: id, .. } if id == order_id)
+            })
+            .map(|(id, _)| id)
+            .last()
+    })
+}
+
+// choose the threshold ECDSA key receipts are signed with, earlier receipts are re-signed with it
+#[ic_cdk::update]
+fn set_receipt_signer(payload: ReceiptSignerPayload) -> Result<ReceiptSigner, Error> {
+    idempotent(
+        "set_receipt_signer",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_admin(&payload.contract_password)?;
+            if payload.signer == SignerKind::Unconfigured {
+                return Err(Error::InvalidPayload {
+                    msg: "Receipts must be signed with a threshold ECDSA key".to_string(),
+                });
+            }
+            let signer = ReceiptSigner {
+                kind: payload.signer,
+                public_key: None,
+            };
+            RECEIPT_SIGNER_STORAGE.with(|s| s.borrow_mut().insert(0, signer.clone()));
+            restore_receipt_signing();
+            Ok(signer)
+        },
+    )
+}
+
+// get the receipt signer and its public key
+#[ic_cdk::query]
+fn get_receipt_signer() -> ReceiptSigner {
+    receipt_signer()
+}
+
+// get a receipt
+#[ic_cdk::query]
+fn get_receipt(id: u64) -> Result<Receipt, Error> {
+    RECEIPT_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("receipt with id: {} not found", id),
+        })
+}
+
+// get the receipts issued to a client or producer
+#[ic_cdk::query]
+fn get_account_receipts(account: AccountRef) -> Vec<Receipt> {
+    RECEIPT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, receipt)| receipt)
+            .filter(|receipt| receipt.account == account)
+            .collect()
+    })
+}
+
+// check a receipt document and signature against a public key, the same check can be done
+// offline by hashing the document with SHA-256 and verifying the secp256k1 signature
+#[ic_cdk::query]
+fn verify_receipt(payload: VerifyReceiptPayload) -> Result<ReceiptVerification, Error> {
+    let public_key = match payload.public_key {
+        Some(public_key) => public_key,
+        None => receipt_signer().public_key.ok_or(Error::NotFound {
+            msg: "Receipt signer is not configured or its key has not been fetched yet".to_string(),
+        })?,
+    };
+    verify_signature(&payload.document, &payload.signature, public_key)
+}
+
+fn verify_signature(
+    document: &str,
+    signature: &str,
+    public_key: String,
+) -> Result<ReceiptVerification, Error> {
+    let invalid = |msg: &str| Error::InvalidPayload {
+        msg: msg.to_string(),
+    };
+    let key_bytes = hex::decode(&public_key).map_err(|_| invalid("Public key is not hex"))?;
+    let verifying_key = VerifyingKey::from_sec1_bytes(&key_bytes)
+        .map_err(|_| invalid("Public key is not a SEC1 secp256k1 key"))?;
+    let signature_bytes = hex::decode(signature).map_err(|_| invalid("Signature is not hex"))?;
+    let signature = Signature::from_slice(&signature_bytes)
+        .map_err(|_| invalid("Signature is not a 64 byte secp256k1 signature"))?;
+    let message_hash = Sha256::digest(document.as_bytes());
+    Ok(ReceiptVerification {
+        valid: verifying_key
+            .verify_prehash(&message_hash, &signature)
+            .is_ok(),
+        message_hash: hex::encode(message_hash),
+        public_key,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use k256::ecdsa::signature::hazmat::PrehashSigner;
+    use k256::ecdsa::SigningKey;
+
+    // a stand-in for the threshold key, derived from public data so only fit for tests
+    fn local_signing_key(canister_id: Principal) -> SigningKey {
+        let seed = Sha256::digest(format!("local-receipt-signer:{}", canister_id));
+        SigningKey::from_slice(&seed).expect("seed is a valid secp256k1 scalar")
+    }
+
+    fn encode_public_key(signing_key: &SigningKey) -> String {
+        hex::encode(
+            signing_key
+                .verifying_key()
+                .to_encoded_point(true)
+                .as_bytes(),
+        )
+    }
+
+    fn sign_locally(receipt: Receipt, signing_key: &SigningKey) -> Receipt {
+        let message_hash = hex::decode(&receipt.message_hash).unwrap();
+        let signature: Signature = signing_key
+            .sign_prehash(&message_hash)
+            .expect("message hash is 32 bytes");
+        Receipt {
+            signature: Some(hex::encode(signature.to_bytes())),
+            public_key: Some(encode_public_key(signing_key)),
+            ..receipt
+        }
+    }
+
+    fn canister_id() -> Principal {
+        Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+    }
+
+    fn signed_receipt(kind: ReceiptKind, account: AccountRef, price: Option<u64>) -> Receipt {
+        let receipt = receipt_for(ReceiptBody {
+            receipt_id: 7,
+            canister_id: canister_id().to_text(),
+            kind,
+            account,
+            credits: 120,
+            price_per_credit: price,
+            issued_at: 1_700_000_000_000_000_000,
+        });
+        sign_locally(receipt, &local_signing_key(canister_id()))
+    }
+
+    fn verify(receipt: &Receipt, document: &str) -> ReceiptVerification {
+        verify_signature(
+            document,
+            receipt.signature.as_ref().unwrap(),
+            receipt.public_key.clone().unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn all_kinds() -> Vec<Receipt> {
+        vec![
+            signed_receipt(
+                ReceiptKind::Settlement {
+                    order_id: 3,
+                    trade_id: 4,
+                },
+                AccountRef::Client { id: 2 },
+                Some(15),
+            ),
+            signed_receipt(
+                ReceiptKind::Mint {
+                    facility_id: Some(9),
+                },
+                AccountRef::Producer { id: 1 },
+                None,
+            ),
+            signed_receipt(
+                ReceiptKind::Retirement { retirement_id: 11 },
+                AccountRef::Client { id: 2 },
+                None,
+            ),
+        ]
+    }
+
+    #[derive(candid::CandidType)]
+    enum LegacySignerKind {
+        Local,
+    }
+
+    #[derive(candid::CandidType)]
+    struct LegacyReceiptSigner {
+        kind: LegacySignerKind,
+        public_key: Option<String>,
+    }
+
+    #[test]
+    fn local_signer_decodes_as_unconfigured() {
+        let legacy = LegacyReceiptSigner {
+            kind: LegacySignerKind::Local,
+            public_key: Some("02ab".to_string()),
+        };
+        let signer = ReceiptSigner::from_bytes(Cow::Owned(Encode!(&legacy).unwrap()));
+        assert!(signer.kind == SignerKind::Unconfigured);
+        assert!(signer.public_key.is_none());
+
+        let threshold = ReceiptSigner {
+            kind: SignerKind::ThresholdEcdsa {
+                key_name: "key_1".to_string(),
+            },
+            public_key: Some("02ab".to_string()),
+        };
+        let decoded = ReceiptSigner::from_bytes(threshold.to_bytes());
+        assert!(decoded.kind == threshold.kind);
+        assert_eq!(decoded.public_key, threshold.public_key);
+    }
+
+    #[test]
+    fn signed_receipts_verify() {
+        for receipt in all_kinds() {
+            let verification = verify(&receipt, &receipt.document);
+            assert!(verification.valid);
+            assert_eq!(verification.message_hash, receipt.message_hash);
+        }
+    }
+
+    #[test]
+    fn document_holds_the_receipt_fields() {
+        let receipt = signed_receipt(
+            ReceiptKind::Settlement {
+                order_id: 3,
+                trade_id: 4,
+            },
+            AccountRef::Client { id: 2 },
+            Some(15),
+        );
+        let document: serde_json::Value = serde_json::from_str(&receipt.document).unwrap();
+        assert_eq!(document["receipt_id"], 7);
+        assert_eq!(document["canister_id"], canister_id().to_text());
+        assert_eq!(document["kind"]["Settlement"]["order_id"], 3);
+        assert_eq!(document["credits"], 120);
+        assert_eq!(document["price_per_credit"], 15);
+    }
+
+    #[test]
+    fn tampered_document_does_not_verify() {
+        for receipt in all_kinds() {
+            let tampered = receipt.document.replace("120", "1200");
+            assert!(!verify(&receipt, &tampered).valid);
+        }
+    }
+
+    #[test]
+    fn other_key_does_not_verify() {
+        let receipt = all_kinds().remove(1);
+        let other_key = encode_public_key(&local_signing_key(Principal::anonymous()));
+        let verification = verify_signature(
+            &receipt.document,
+            receipt.signature.as_ref().unwrap(),
+            other_key,
+        )
+        .unwrap();
+        assert!(!verification.valid);
+    }
+
+    #[test]
+    fn local_key_depends_on_the_canister() {
+        assert_eq!(
+            encode_public_key(&local_signing_key(canister_id())),
+            encode_public_key(&local_signing_key(canister_id()))
+        );
+        assert_ne!(
+            encode_public_key(&local_signing_key(canister_id())),
+            encode_public_key(&local_signing_key(Principal::anonymous()))
+        );
+    }
+
+    #[test]
+    fn malformed_signature_or_key_is_rejected() {
+        let receipt = all_kinds().remove(2);
+        let public_key = receipt.public_key.clone().unwrap();
+        assert!(matches!(
+            verify_signature(&receipt.document, "zz", public_key.clone()),
+            Err(Error::InvalidPayload { .. })
+        ));
+        assert!(matches!(
+            verify_signature(&receipt.document, "abcd", public_key),
+            Err(Error::InvalidPayload { .. })
+        ));
+        assert!(matches!(
+            verify_signature(
+                &receipt.document,
+                receipt.signature.as_ref().unwrap(),
+                "02ff".to_string()
+            ),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+}
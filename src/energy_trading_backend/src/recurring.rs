@@ -0,0 +1,437 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+use validator::Validate;
+
+use crate::{
+    authorize_account, authorize_producer, check_bid_risk, ensure_can_trade, idempotent, next_id,
+    notify, record_activity, settle_direct_sale, AccountRef, CreditOrder, Error, Memory,
+    CLIENT_STORAGE, MEMORY_MANAGER, PRODUCER_STORAGE,
+};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// at most a year between executions
+const MAX_INTERVAL_SECONDS: u64 = 365 * 24 * 60 * 60;
+// executions in a row that may fail before the agreement is stopped
+const MAX_CONSECUTIVE_FAILURES: u64 = 3;
+const NOTIFICATION_TOPIC: &str = "recurring_agreement";
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum AgreementStatus {
+    // offered by the producer, waiting for the client
+    #[default]
+    Proposed,
+    Active,
+    // the end date has passed
+    Ended,
+    // stopped after too many failed executions
+    Failed,
+    Cancelled,
+}
+
+// a standing order for a client to buy credits from a producer every interval
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RecurringAgreement {
+    id: u64,
+    client_id: u64,
+    producer_id: u64,
+    credits: u64,
+    price_per_credit: u64,
+    interval_seconds: u64,
+    ends_at: u64,
+    status: AgreementStatus,
+    next_execution_at: Option<u64>,
+    executions: u64,
+    failures: u64,
+    consecutive_failures: u64,
+    last_order_id: Option<u64>,
+    created_at: u64,
+}
+
+impl Storable for RecurringAgreement {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RecurringAgreement {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static RECURRING_AGREEMENT_STORAGE: RefCell<StableBTreeMap<u64, RecurringAgreement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct RecurringAgreementPayload {
+    producer_id: u64,
+    producer_password: String,
+    client_id: u64,
+    #[validate(range(min = 1))]
+    credits: u64,
+    #[validate(range(min = 1))]
+    price_per_credit: u64,
+    // at least an hour between executions
+    #[validate(range(min = 3600, max = "MAX_INTERVAL_SECONDS"))]
+    interval_seconds: u64,
+    ends_at: u64,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct AcceptAgreementPayload {
+    client_id: u64,
+    agreement_id: u64,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct CancelAgreementPayload {
+    // the cancelling client or producer and its password
+    account: AccountRef,
+    password: String,
+    agreement_id: u64,
+    idempotency_key: Option<String>,
+}
+
+fn get_agreement_record(agreement_id: u64) -> Result<RecurringAgreement, Error> {
+    RECURRING_AGREEMENT_STORAGE
+        .with(|s| s.borrow().get(&agreement_id))
+        .ok_or(Error::NotFound {
+            msg: format!("recurring agreement with id: {} not found", agreement_id),
+        })
+}
+
+fn save_agreement(agreement: &RecurringAgreement) {
+    RECURRING_AGREEMENT_STORAGE.with(|s| s.borrow_mut().insert(agreement.id, agreement.clone()));
+}
+
+// record the same notification on both parties of an agreement
+fn notify_parties(agreement: &RecurringAgreement, message: String) {
+    notify(
+        AccountRef::Client {
+            id: agreement.client_id,
+        },
+        NOTIFICATION_TOPIC,
+        agreement.id,
+        message.clone(),
+    );
+    notify(
+        AccountRef::Producer {
+            id: agreement.producer_id,
+        },
+        NOTIFICATION_TOPIC,
+        agreement.id,
+        message,
+    );
+}
+
+fn schedule_execution(agreement_id: u64, execute_at: u64) {
+    let delay = Duration::from_nanos(execute_at.saturating_sub(ic_cdk::api::time()));
+    ic_cdk_timers::set_timer(delay, move || execute_agreement(agreement_id));
+}
+
+// create an order for one period of an agreement and settle it to the client
+fn purchase(agreement: &RecurringAgreement) -> Result<CreditOrder, Error> {
+    ensure_can_trade(AccountRef::Client {
+        id: agreement.client_id,
+    })?;
+    ensure_can_trade(AccountRef::Producer {
+        id: agreement.producer_id,
+    })?;
+    let producer = PRODUCER_STORAGE
+        .with(|s| s.borrow().get(&agreement.producer_id))
+        .ok_or(Error::NotFound {
+            msg: "Producer not found".to_string(),
+        })?;
+    if producer.credits < agreement.credits {
+        return Err(Error::InvalidPayload {
+            msg: "Producer does not have enough credits".to_string(),
+        });
+    }
+
+    settle_direct_sale(
+        agreement.producer_id,
+        agreement.client_id,
+        agreement.credits,
+        agreement.price_per_credit,
+        None,
+        None,
+    )
+}
+
+// run one execution of an agreement and schedule the next one
+fn execute_agreement(agreement_id: u64) {
+    let Ok(agreement) = get_agreement_record(agreement_id) else {
+        return;
+    };
+    if agreement.status != AgreementStatus::Active {
+        return;
+    }
+    let now = ic_cdk::api::time();
+    if now > agreement.ends_at {
+        let agreement = RecurringAgreement {
+            status: AgreementStatus::Ended,
+            next_execution_at: None,
+            ..agreement
+        };
+        save_agreement(&agreement);
+        notify_parties(
+            &agreement,
+            format!(
+                "Recurring agreement {} ended after {} purchases",
+                agreement.id, agreement.executions
+            ),
+        );
+        return;
+    }
+
+    let next_execution_at =
+        now.saturating_add(agreement.interval_seconds.saturating_mul(NANOS_PER_SECOND));
+    let agreement = match purchase(&agreement) {
+        Ok(credit_order) => {
+            let agreement = RecurringAgreement {
+                executions: agreement.executions + 1,
+                consecutive_failures: 0,
+                last_order_id: Some(credit_order.id),
+                next_execution_at: Some(next_execution_at),
+                ..agreement
+            };
+            notify_parties(
+                &agreement,
+                format!(
+                    "Recurring agreement {} bought {} credits at {} per credit in order {}",
+                    agreement.id, agreement.credits, agreement.price_per_credit, credit_order.id
+                ),
+            );
+            agreement
+        }
+        Err(e) => {
+            let consecutive_failures = agreement.consecutive_failures + 1;
+            let failed = consecutive_failures >= MAX_CONSECUTIVE_FAILURES;
+            let agreement = RecurringAgreement {
+                failures: agreement.failures + 1,
+                consecutive_failures,
+                status: match failed {
+                    true => AgreementStatus::Failed,
+                    false => agreement.status,
+                },
+                next_execution_at: (!failed).then_some(next_execution_at),
+                ..agreement
+            };
+            let outcome = match failed {
+                true => "the agreement was stopped after repeated failures",
+                false => "it will be retried next period",
+            };
+            notify_parties(
+                &agreement,
+                format!(
+                    "Recurring agreement {} purchase failed: {:?}, {}",
+                    agreement.id, e, outcome
+                ),
+            );
+            agreement
+        }
+    };
+    save_agreement(&agreement);
+    if let Some(execute_at) = agreement.next_execution_at {
+        schedule_execution(agreement.id, execute_at);
+    }
+}
+
+// re-arm the executions of active agreements
+pub(crate) fn restore_recurring_agreement_timers() {
+    RECURRING_AGREEMENT_STORAGE.with(|s| {
+        for (id, agreement) in s.borrow().iter() {
+            if let (AgreementStatus::Active, Some(execute_at)) =
+                (agreement.status, agreement.next_execution_at)
+            {
+                schedule_execution(id, execute_at);
+            }
+        }
+    });
+}
+
+// offer a client a recurring purchase agreement
+#[ic_cdk::update]
+fn propose_recurring_agreement(
+    payload: RecurringAgreementPayload,
+) -> Result<RecurringAgreement, Error> {
+    idempotent(
+        "propose_recurring_agreement",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            let producer = authorize_producer(payload.producer_id, &payload.producer_password)?;
+            ensure_can_trade(AccountRef::Producer { id: producer.id })?;
+            if !CLIENT_STORAGE.with(|s| s.borrow().contains_key(&payload.client_id)) {
+                return Err(Error::NotFound {
+                    msg: "Client not found".to_string(),
+                });
+            }
+            let now = ic_cdk::api::time();
+            if payload.ends_at <= now {
+                return Err(Error::InvalidPayload {
+                    msg: "End date must be in the future".to_string(),
+                });
+            }
+            let agreement = RecurringAgreement {
+                id: next_id(),
+                client_id: payload.client_id,
+                producer_id: producer.id,
+                credits: payload.credits,
+                price_per_credit: payload.price_per_credit,
+                interval_seconds: payload.interval_seconds,
+                ends_at: payload.ends_at,
+                status: AgreementStatus::Proposed,
+                next_execution_at: None,
+                executions: 0,
+                failures: 0,
+                consecutive_failures: 0,
+                last_order_id: None,
+                created_at: now,
+            };
+            save_agreement(&agreement);
+            notify_parties(
+                &agreement,
+                format!(
+                    "Recurring agreement {} proposed: {} credits at {} per credit every {} seconds",
+                    agreement.id,
+                    agreement.credits,
+                    agreement.price_per_credit,
+                    agreement.interval_seconds
+                ),
+            );
+            Ok(agreement)
+        },
+    )
+}
+
+// accept a proposed agreement, the first purchase runs right away
+#[ic_cdk::update]
+fn accept_recurring_agreement(
+    payload: AcceptAgreementPayload,
+) -> Result<RecurringAgreement, Error> {
+    idempotent(
+        "accept_recurring_agreement",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            let agreement = get_agreement_record(payload.agreement_id)?;
+            if agreement.client_id != payload.client_id {
+                return Err(Error::Unauthorized {
+                    msg: "Recurring agreement was not offered to client".to_string(),
+                });
+            }
+            if agreement.status != AgreementStatus::Proposed {
+                return Err(Error::InvalidPayload {
+                    msg: "Recurring agreement is not awaiting acceptance".to_string(),
+                });
+            }
+            let now = ic_cdk::api::time();
+            if now > agreement.ends_at {
+                return Err(Error::InvalidPayload {
+                    msg: "Recurring agreement has already ended".to_string(),
+                });
+            }
+            ensure_can_trade(AccountRef::Client {
+                id: payload.client_id,
+            })?;
+            check_bid_risk(payload.client_id, agreement.producer_id)?;
+            let agreement = RecurringAgreement {
+                status: AgreementStatus::Active,
+                next_execution_at: Some(now),
+                ..agreement
+            };
+            save_agreement(&agreement);
+            record_activity(AccountRef::Client {
+                id: payload.client_id,
+            });
+            notify_parties(
+                &agreement,
+                format!("Recurring agreement {} accepted", agreement.id),
+            );
+            schedule_execution(agreement.id, now);
+            Ok(agreement)
+        },
+    )
+}
+
+// cancel a proposed or active agreement, either party can cancel
+#[ic_cdk::update]
+fn cancel_recurring_agreement(
+    payload: CancelAgreementPayload,
+) -> Result<RecurringAgreement, Error> {
+    idempotent(
+        "cancel_recurring_agreement",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            let agreement = get_agreement_record(payload.agreement_id)?;
+            authorize_account(payload.account, &payload.password)?;
+            let party = match payload.account {
+                AccountRef::Client { id } => id == agreement.client_id,
+                AccountRef::Producer { id } => id == agreement.producer_id,
+            };
+            if !party {
+                return Err(Error::Unauthorized {
+                    msg: "Account is not a party to the recurring agreement".to_string(),
+                });
+            }
+            if !matches!(
+                agreement.status,
+                AgreementStatus::Proposed | AgreementStatus::Active
+            ) {
+                return Err(Error::InvalidPayload {
+                    msg: "Recurring agreement is already closed".to_string(),
+                });
+            }
+            let agreement = RecurringAgreement {
+                status: AgreementStatus::Cancelled,
+                next_execution_at: None,
+                ..agreement
+            };
+            save_agreement(&agreement);
+            notify_parties(
+                &agreement,
+                format!("Recurring agreement {} cancelled", agreement.id),
+            );
+            Ok(agreement)
+        },
+    )
+}
+
+// get a recurring agreement
+#[ic_cdk::query]
+fn get_recurring_agreement(id: u64) -> Result<RecurringAgreement, Error> {
+    get_agreement_record(id)
+}
+
+// get the recurring agreements of a client or producer
+#[ic_cdk::query]
+fn get_account_recurring_agreements(account: AccountRef) -> Vec<RecurringAgreement> {
+    RECURRING_AGREEMENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, agreement)| agreement)
+            .filter(|agreement| match account {
+                AccountRef::Client { id } => agreement.client_id == id,
+                AccountRef::Producer { id } => agreement.producer_id == id,
+            })
+            .collect()
+    })
+}
@@ -0,0 +1,138 @@
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::{
+    client_ledger, vintage_year, Error, FacilityTechnology, SourceAllocation, CLIENT_STORAGE,
+    PRODUCER_STORAGE,
+};
+
+// a retirement is the certificate a client cites for its offset claim
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ReportCertificate {
+    certificate_id: u64,
+    credits: u64,
+    reason: String,
+    retired_at: u64,
+    sources: Vec<SourceAllocation>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ReportSourceMix {
+    technology: Option<FacilityTechnology>,
+    vintage: Option<u32>,
+    credits: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ReportProducer {
+    producer_id: u64,
+    name: String,
+    credits: u64,
+}
+
+// offset claim of a client for a reporting year, market-based scope 2 style
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct EmissionsReport {
+    client_id: u64,
+    organization_name: String,
+    legal_entity_id: String,
+    country_code: String,
+    year: u32,
+    // one credit offsets one tonne of CO2e
+    credits_retired: u64,
+    certificates: Vec<ReportCertificate>,
+    source_mix: Vec<ReportSourceMix>,
+    producers: Vec<ReportProducer>,
+    // retired credits whose source is not known
+    unattributed_credits: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct EmissionsReportDocument {
+    report: EmissionsReport,
+    // the report as canonical JSON, the form the hash is computed over
+    document: String,
+    // hex encoded SHA-256 of the document
+    content_hash: String,
+}
+
+// generate the emissions report of a client for a year from its retirements, the same data
+// always yields the same document and hash
+#[ic_cdk::query]
+fn generate_emissions_report(client_id: u64, year: u32) -> Result<EmissionsReportDocument, Error> {
+    let client = CLIENT_STORAGE
+        .with(|s| s.borrow().get(&client_id))
+        .ok_or(Error::NotFound {
+            msg: format!("client with id: {} not found", client_id),
+        })?;
+
+    let certificates: Vec<ReportCertificate> = client_ledger(client_id)
+        .retirements
+        .into_iter()
+        .filter(|(retirement, _)| vintage_year(retirement.retired_at) == year)
+        .map(|(retirement, sources)| ReportCertificate {
+            certificate_id: retirement.id,
+            credits: retirement.credits,
+            reason: retirement.reason,
+            retired_at: retirement.retired_at,
+            sources,
+        })
+        .collect();
+
+    let mut source_mix: BTreeMap<(Option<FacilityTechnology>, Option<u32>), u64> = BTreeMap::new();
+    let mut producers: BTreeMap<u64, u64> = BTreeMap::new();
+    let mut unattributed_credits = 0;
+    for source in certificates
+        .iter()
+        .flat_map(|certificate| &certificate.sources)
+    {
+        *source_mix
+            .entry((source.technology, source.vintage))
+            .or_default() += source.credits;
+        match source.producer_id {
+            Some(producer_id) => *producers.entry(producer_id).or_default() += source.credits,
+            None => unattributed_credits += source.credits,
+        }
+    }
+
+    let report = EmissionsReport {
+        client_id,
+        organization_name: client.organization_name,
+        legal_entity_id: client.legal_entity_id,
+        country_code: client.country_code,
+        year,
+        credits_retired: certificates
+            .iter()
+            .map(|certificate| certificate.credits)
+            .sum(),
+        certificates,
+        source_mix: source_mix
+            .into_iter()
+            .map(|((technology, vintage), credits)| ReportSourceMix {
+                technology,
+                vintage,
+                credits,
+            })
+            .collect(),
+        producers: producers
+            .into_iter()
+            .map(|(producer_id, credits)| ReportProducer {
+                producer_id,
+                name: PRODUCER_STORAGE
+                    .with(|s| s.borrow().get(&producer_id))
+                    .map(|producer| producer.name)
+                    .unwrap_or_default(),
+                credits,
+            })
+            .collect(),
+        unattributed_credits,
+    };
+    let document =
+        serde_json::to_string(&report).map_err(|e| Error::InvalidPayload { msg: e.to_string() })?;
+    let content_hash = hex::encode(Sha256::digest(document.as_bytes()));
+    Ok(EmissionsReportDocument {
+        report,
+        document,
+        content_hash,
+    })
+}
@@ -0,0 +1,117 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+use validator::Validate;
+
+use crate::{
+    authorize_client, deduct_credit_from_client, idempotent, issue_receipt, next_id,
+    record_retirement_stats, AccountRef, Error, Memory, ReceiptKind, CLIENT_STORAGE,
+    MEMORY_MANAGER,
+};
+
+// credits permanently taken out of circulation by a client to claim the offset
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Retirement {
+    pub(crate) id: u64,
+    pub(crate) client_id: u64,
+    pub(crate) credits: u64,
+    pub(crate) reason: String,
+    pub(crate) retired_at: u64,
+    // signed receipt of the retirement
+    pub(crate) receipt_id: u64,
+}
+
+impl Storable for Retirement {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Retirement {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    pub(crate) static RETIREMENT_STORAGE: RefCell<StableBTreeMap<u64, Retirement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct RetirePayload {
+    client_id: u64,
+    password: String,
+    #[validate(range(min = 1))]
+    credits: u64,
+    #[validate(length(max = 256))]
+    reason: String,
+    idempotency_key: Option<String>,
+}
+
+// validate and authenticate a retirement, also used to check batched retirements
+pub(crate) fn check_retirement(payload: &RetirePayload) -> Result<(), Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    authorize_client(payload.client_id, &payload.password)?;
+    Ok(())
+}
+
+// retire credits from a client balance
+#[ic_cdk::update]
+pub(crate) fn retire_credits(payload: RetirePayload) -> Result<Retirement, Error> {
+    idempotent(
+        "retire_credits",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            check_retirement(&payload)?;
+            deduct_credit_from_client(payload.client_id, payload.credits)?;
+            let id = next_id();
+            let receipt = issue_receipt(
+                ReceiptKind::Retirement { retirement_id: id },
+                AccountRef::Client {
+                    id: payload.client_id,
+                },
+                payload.credits,
+                None,
+            );
+            let retirement = Retirement {
+                id,
+                client_id: payload.client_id,
+                credits: payload.credits,
+                reason: payload.reason,
+                retired_at: ic_cdk::api::time(),
+                receipt_id: receipt.id,
+            };
+            RETIREMENT_STORAGE.with(|s| s.borrow_mut().insert(id, retirement.clone()));
+            record_retirement_stats(payload.credits);
+            Ok(retirement)
+        },
+    )
+}
+
+// get the retirements of a client
+#[ic_cdk::query]
+fn get_client_retirements(client_id: u64) -> Result<Vec<Retirement>, Error> {
+    if !CLIENT_STORAGE.with(|s| s.borrow().contains_key(&client_id)) {
+        return Err(Error::NotFound {
+            msg: format!("client with id: {} not found", client_id),
+        });
+    }
+    Ok(RETIREMENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, retirement)| retirement)
+            .filter(|retirement| retirement.client_id == client_id)
+            .collect()
+    }))
+}
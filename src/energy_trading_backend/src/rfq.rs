@@ -0,0 +1,514 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+use validator::Validate;
+
+use crate::{
+    authorize_client, authorize_producer, check_bid_risk, ensure_can_trade, get_facility_record,
+    idempotent, next_id, record_activity, remaining_vintage_credits, settle_direct_sale,
+    vintage_year, AccountRef, CertificationStatus, CreditOrder, Error, FacilityTechnology, Memory,
+    CLIENT_STORAGE, MEMORY_MANAGER,
+};
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum PurchaseRequestStatus {
+    #[default]
+    Open,
+    Accepted,
+    Cancelled,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum QuoteStatus {
+    #[default]
+    Pending,
+    Accepted,
+    // another quote was accepted or the request was cancelled
+    Rejected,
+    Withdrawn,
+}
+
+// a client request for quotes on credits matching its criteria
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct PurchaseRequest {
+    id: u64,
+    client_id: u64,
+    credits: u64,
+    // oldest vintage year accepted, any vintage when not set
+    min_vintage: Option<u32>,
+    // accepted technologies, any technology when empty
+    technologies: Vec<FacilityTechnology>,
+    max_price_per_credit: Option<u64>,
+    // quotes can be accepted until this time
+    deliver_by: u64,
+    status: PurchaseRequestStatus,
+    accepted_quote_id: Option<u64>,
+    credit_order_id: Option<u64>,
+    created_at: u64,
+}
+
+// a producer offer to fill a purchase request from one facility and vintage
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Quote {
+    id: u64,
+    request_id: u64,
+    producer_id: u64,
+    facility_id: u64,
+    technology: FacilityTechnology,
+    vintage: u32,
+    price_per_credit: u64,
+    status: QuoteStatus,
+    created_at: u64,
+}
+
+impl Storable for PurchaseRequest {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Quote {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PurchaseRequest {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Quote {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PURCHASE_REQUEST_STORAGE: RefCell<StableBTreeMap<u64, PurchaseRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+
+    // (request id, quote id) -> quote
+    static QUOTE_STORAGE: RefCell<StableBTreeMap<(u64, u64), Quote, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct PurchaseRequestPayload {
+    client_id: u64,
+    #[validate(range(min = 1))]
+    credits: u64,
+    min_vintage: Option<u32>,
+    #[validate(length(max = 6))]
+    technologies: Vec<FacilityTechnology>,
+    max_price_per_credit: Option<u64>,
+    deliver_by: u64,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct CancelPurchaseRequestPayload {
+    client_id: u64,
+    client_password: String,
+    request_id: u64,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct QuotePayload {
+    producer_id: u64,
+    producer_password: String,
+    request_id: u64,
+    facility_id: u64,
+    vintage: u32,
+    #[validate(range(min = 1))]
+    price_per_credit: u64,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct WithdrawQuotePayload {
+    producer_id: u64,
+    producer_password: String,
+    request_id: u64,
+    quote_id: u64,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct AcceptQuotePayload {
+    client_id: u64,
+    client_password: String,
+    request_id: u64,
+    quote_id: u64,
+    idempotency_key: Option<String>,
+}
+
+fn get_purchase_request_record(request_id: u64) -> Result<PurchaseRequest, Error> {
+    PURCHASE_REQUEST_STORAGE
+        .with(|s| s.borrow().get(&request_id))
+        .ok_or(Error::NotFound {
+            msg: format!("purchase request with id: {} not found", request_id),
+        })
+}
+
+fn get_quote_record(request_id: u64, quote_id: u64) -> Result<Quote, Error> {
+    QUOTE_STORAGE
+        .with(|s| s.borrow().get(&(request_id, quote_id)))
+        .ok_or(Error::NotFound {
+            msg: format!("quote with id: {} not found", quote_id),
+        })
+}
+
+fn request_quotes(request_id: u64) -> Vec<Quote> {
+    QUOTE_STORAGE.with(|s| {
+        s.borrow()
+            .range((request_id, 0)..=(request_id, u64::MAX))
+            .map(|(_, quote)| quote)
+            .collect()
+    })
+}
+
+fn ensure_request_open(request: &PurchaseRequest) -> Result<(), Error> {
+    if request.status != PurchaseRequestStatus::Open {
+        return Err(Error::InvalidPayload {
+            msg: "Purchase request is not open".to_string(),
+        });
+    }
+    if ic_cdk::api::time() > request.deliver_by {
+        return Err(Error::InvalidPayload {
+            msg: "Purchase request has expired".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// check that a facility vintage meets the request criteria and can fill it
+fn validate_quote_source(
+    request: &PurchaseRequest,
+    producer_id: u64,
+    facility_id: u64,
+    vintage: u32,
+) -> Result<FacilityTechnology, Error> {
+    let facility = get_facility_record(facility_id)?;
+    if facility.producer_id != producer_id {
+        return Err(Error::Unauthorized {
+            msg: "Facility does not belong to producer".to_string(),
+        });
+    }
+    if facility.certification != CertificationStatus::Certified {
+        return Err(Error::InvalidPayload {
+            msg: "Only certified facilities can fill purchase requests".to_string(),
+        });
+    }
+    if !request.technologies.is_empty() && !request.technologies.contains(&facility.technology) {
+        return Err(Error::InvalidPayload {
+            msg: "Facility technology does not match the purchase request".to_string(),
+        });
+    }
+    if request
+        .min_vintage
+        .is_some_and(|min_vintage| vintage < min_vintage)
+    {
+        return Err(Error::InvalidPayload {
+            msg: "Vintage is older than the purchase request accepts".to_string(),
+        });
+    }
+    if remaining_vintage_credits(facility_id, vintage) < request.credits {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "Facility has not enough uncommitted {} credits to fill the purchase request",
+                vintage
+            ),
+        });
+    }
+    Ok(facility.technology)
+}
+
+// post a request for quotes
+#[ic_cdk::update]
+fn add_purchase_request(payload: PurchaseRequestPayload) -> Result<PurchaseRequest, Error> {
+    idempotent(
+        "add_purchase_request",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            if !CLIENT_STORAGE.with(|s| s.borrow().contains_key(&payload.client_id)) {
+                return Err(Error::NotFound {
+                    msg: "Client not found".to_string(),
+                });
+            }
+            ensure_can_trade(AccountRef::Client {
+                id: payload.client_id,
+            })?;
+            let now = ic_cdk::api::time();
+            if payload.deliver_by <= now {
+                return Err(Error::InvalidPayload {
+                    msg: "Delivery date must be in the future".to_string(),
+                });
+            }
+            if payload
+                .min_vintage
+                .is_some_and(|min_vintage| min_vintage > vintage_year(now))
+            {
+                return Err(Error::InvalidPayload {
+                    msg: "Minimum vintage cannot be in the future".to_string(),
+                });
+            }
+            let id = next_id();
+            let request = PurchaseRequest {
+                id,
+                client_id: payload.client_id,
+                credits: payload.credits,
+                min_vintage: payload.min_vintage,
+                technologies: payload.technologies,
+                max_price_per_credit: payload.max_price_per_credit,
+                deliver_by: payload.deliver_by,
+                status: PurchaseRequestStatus::Open,
+                accepted_quote_id: None,
+                credit_order_id: None,
+                created_at: now,
+            };
+            PURCHASE_REQUEST_STORAGE.with(|s| s.borrow_mut().insert(id, request.clone()));
+            Ok(request)
+        },
+    )
+}
+
+// cancel an open purchase request, rejecting its pending quotes
+#[ic_cdk::update]
+fn cancel_purchase_request(
+    payload: CancelPurchaseRequestPayload,
+) -> Result<PurchaseRequest, Error> {
+    idempotent(
+        "cancel_purchase_request",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_client(payload.client_id, &payload.client_password)?;
+            let request = get_purchase_request_record(payload.request_id)?;
+            if request.client_id != payload.client_id {
+                return Err(Error::Unauthorized {
+                    msg: "Purchase request does not belong to client".to_string(),
+                });
+            }
+            if request.status != PurchaseRequestStatus::Open {
+                return Err(Error::InvalidPayload {
+                    msg: "Purchase request is not open".to_string(),
+                });
+            }
+            close_pending_quotes(request.id, None);
+            let request = PurchaseRequest {
+                status: PurchaseRequestStatus::Cancelled,
+                ..request
+            };
+            PURCHASE_REQUEST_STORAGE.with(|s| s.borrow_mut().insert(request.id, request.clone()));
+            Ok(request)
+        },
+    )
+}
+
+// reject the pending quotes of a request, except the accepted one
+fn close_pending_quotes(request_id: u64, accepted_quote_id: Option<u64>) {
+    for quote in request_quotes(request_id) {
+        if quote.status != QuoteStatus::Pending {
+            continue;
+        }
+        let status = match Some(quote.id) == accepted_quote_id {
+            true => QuoteStatus::Accepted,
+            false => QuoteStatus::Rejected,
+        };
+        QUOTE_STORAGE.with(|s| {
+            s.borrow_mut()
+                .insert((request_id, quote.id), Quote { status, ..quote })
+        });
+    }
+}
+
+// quote a price for a purchase request from one of the producer facilities
+#[ic_cdk::update]
+fn submit_quote(payload: QuotePayload) -> Result<Quote, Error> {
+    idempotent(
+        "submit_quote",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            let producer = authorize_producer(payload.producer_id, &payload.producer_password)?;
+            ensure_can_trade(AccountRef::Producer { id: producer.id })?;
+            let request = get_purchase_request_record(payload.request_id)?;
+            ensure_request_open(&request)?;
+            if request
+                .max_price_per_credit
+                .is_some_and(|max_price| payload.price_per_credit > max_price)
+            {
+                return Err(Error::InvalidPayload {
+                    msg: "Price is above the maximum of the purchase request".to_string(),
+                });
+            }
+            let technology =
+                validate_quote_source(&request, producer.id, payload.facility_id, payload.vintage)?;
+
+            let id = next_id();
+            let quote = Quote {
+                id,
+                request_id: request.id,
+                producer_id: producer.id,
+                facility_id: payload.facility_id,
+                technology,
+                vintage: payload.vintage,
+                price_per_credit: payload.price_per_credit,
+                status: QuoteStatus::Pending,
+                created_at: ic_cdk::api::time(),
+            };
+            QUOTE_STORAGE.with(|s| s.borrow_mut().insert((request.id, id), quote.clone()));
+            Ok(quote)
+        },
+    )
+}
+
+// withdraw a pending quote
+#[ic_cdk::update]
+fn withdraw_quote(payload: WithdrawQuotePayload) -> Result<Quote, Error> {
+    idempotent(
+        "withdraw_quote",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_producer(payload.producer_id, &payload.producer_password)?;
+            let quote = get_quote_record(payload.request_id, payload.quote_id)?;
+            if quote.producer_id != payload.producer_id {
+                return Err(Error::Unauthorized {
+                    msg: "Quote does not belong to producer".to_string(),
+                });
+            }
+            if quote.status != QuoteStatus::Pending {
+                return Err(Error::InvalidPayload {
+                    msg: "Only pending quotes can be withdrawn".to_string(),
+                });
+            }
+            let quote = Quote {
+                status: QuoteStatus::Withdrawn,
+                ..quote
+            };
+            QUOTE_STORAGE.with(|s| {
+                s.borrow_mut()
+                    .insert((quote.request_id, quote.id), quote.clone())
+            });
+            Ok(quote)
+        },
+    )
+}
+
+// accept a quote, escrowing the producer credits into an order that is settled to the client
+#[ic_cdk::update]
+fn accept_quote(payload: AcceptQuotePayload) -> Result<CreditOrder, Error> {
+    idempotent(
+        "accept_quote",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_client(payload.client_id, &payload.client_password)?;
+            let request = get_purchase_request_record(payload.request_id)?;
+            if request.client_id != payload.client_id {
+                return Err(Error::Unauthorized {
+                    msg: "Purchase request does not belong to client".to_string(),
+                });
+            }
+            ensure_request_open(&request)?;
+            ensure_can_trade(AccountRef::Client {
+                id: payload.client_id,
+            })?;
+            let quote = get_quote_record(payload.request_id, payload.quote_id)?;
+            if quote.status != QuoteStatus::Pending {
+                return Err(Error::InvalidPayload {
+                    msg: "Quote is no longer pending".to_string(),
+                });
+            }
+            ensure_can_trade(AccountRef::Producer {
+                id: quote.producer_id,
+            })?;
+            // the facility may have been revoked or its vintage sold since the quote was made
+            validate_quote_source(
+                &request,
+                quote.producer_id,
+                quote.facility_id,
+                quote.vintage,
+            )?;
+            check_bid_risk(payload.client_id, quote.producer_id)?;
+
+            let credit_order = settle_direct_sale(
+                quote.producer_id,
+                payload.client_id,
+                request.credits,
+                quote.price_per_credit,
+                Some(quote.facility_id),
+                Some(quote.vintage),
+            )?;
+            record_activity(AccountRef::Client {
+                id: payload.client_id,
+            });
+
+            close_pending_quotes(request.id, Some(quote.id));
+            PURCHASE_REQUEST_STORAGE.with(|s| {
+                s.borrow_mut().insert(
+                    request.id,
+                    PurchaseRequest {
+                        status: PurchaseRequestStatus::Accepted,
+                        accepted_quote_id: Some(quote.id),
+                        credit_order_id: Some(credit_order.id),
+                        ..request
+                    },
+                )
+            });
+            Ok(credit_order)
+        },
+    )
+}
+
+// get the open purchase requests producers can quote on
+#[ic_cdk::query]
+fn get_open_purchase_requests() -> Vec<PurchaseRequest> {
+    let now = ic_cdk::api::time();
+    PURCHASE_REQUEST_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| {
+                request.status == PurchaseRequestStatus::Open && request.deliver_by >= now
+            })
+            .collect()
+    })
+}
+
+// get a purchase request
+#[ic_cdk::query]
+fn get_purchase_request(id: u64) -> Result<PurchaseRequest, Error> {
+    get_purchase_request_record(id)
+}
+
+// get the quotes made on a purchase request
+#[ic_cdk::query]
+fn get_purchase_request_quotes(request_id: u64) -> Result<Vec<Quote>, Error> {
+    get_purchase_request_record(request_id)?;
+    Ok(request_quotes(request_id))
+}
@@ -0,0 +1,587 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, cmp::Ordering};
+use validator::Validate;
+
+use crate::{
+    account_credits, authorize_admin, idempotent, max_generation, next_id, truncate_bytes,
+    validate_max_bytes, AccountRef, Error, Facility, Memory, OrderStatus, CLIENT_STORAGE,
+    CREDIT_ORDER_STORAGE, MEMORY_MANAGER, PRODUCER_STORAGE,
+};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const MAX_DETAIL_BYTES: usize = 500;
+
+// what happens to an action that breaks a risk rule
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum RiskAction {
+    #[default]
+    Reject,
+    // let the action through and queue it for review by an auditor
+    Flag,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) struct RiskLimit {
+    limit: u64,
+    action: RiskAction,
+}
+
+// risk rules, a rule without a limit is not checked
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RiskSettings {
+    // largest facility award as a share of its nameplate generation over the period, in basis points
+    max_capacity_factor_bps: Option<RiskLimit>,
+    // open credit orders a client may be the highest bidder on at once
+    max_outstanding_bids: Option<RiskLimit>,
+    // bids by a client on orders of a linked producer
+    wash_trade: Option<RiskAction>,
+    max_bids_per_window: Option<RiskLimit>,
+    max_awards_per_window: Option<RiskLimit>,
+    velocity_window_seconds: u64,
+    updated_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum RiskRule {
+    #[default]
+    CapacityFactor,
+    OutstandingBids,
+    WashTrade,
+    BidVelocity,
+    AwardVelocity,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RiskReview {
+    auditor_id: u64,
+    // true when the auditor confirmed the activity as fraudulent
+    confirmed: bool,
+    note: String,
+    reviewed_at: u64,
+}
+
+// a risk rule violation, rejected or let through for review
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RiskFlag {
+    id: u64,
+    rule: RiskRule,
+    action: RiskAction,
+    account: AccountRef,
+    detail: String,
+    created_at: u64,
+    review: Option<RiskReview>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Auditor {
+    id: u64,
+    name: String,
+    password: String,
+}
+
+impl Storable for RiskSettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for RiskFlag {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Auditor {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RiskSettings {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// detail and review note are up to 500 bytes each
+impl BoundedStorable for RiskFlag {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for Auditor {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static RISK_SETTINGS_STORAGE: RefCell<StableBTreeMap<u64, RiskSettings, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    static AUDITOR_STORAGE: RefCell<StableBTreeMap<u64, Auditor, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    static RISK_FLAG_STORAGE: RefCell<StableBTreeMap<u64, RiskFlag, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    // linked accounts, stored with the lower account first
+    static ACCOUNT_LINK_STORAGE: RefCell<StableBTreeMap<(AccountRef, AccountRef), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    // (account, time) -> number of bids or awards at that time, for velocity limits
+    static ACTIVITY_STORAGE: RefCell<StableBTreeMap<(AccountRef, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RiskSettingsPayload {
+    contract_password: String,
+    max_capacity_factor_bps: Option<RiskLimit>,
+    max_outstanding_bids: Option<RiskLimit>,
+    wash_trade: Option<RiskAction>,
+    max_bids_per_window: Option<RiskLimit>,
+    max_awards_per_window: Option<RiskLimit>,
+    velocity_window_seconds: u64,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct AuditorPayload {
+    contract_password: String,
+    #[validate(length(min = 3), custom = "validate_max_bytes::<128>")]
+    name: String,
+    #[validate(length(min = 4), custom = "validate_max_bytes::<128>")]
+    password: String,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct AuditorReturn {
+    id: u64,
+    name: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct AccountLinkPayload {
+    contract_password: String,
+    first: AccountRef,
+    second: AccountRef,
+    linked: bool,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RiskFlagsPayload {
+    auditor_id: u64,
+    password: String,
+    // only the review queue, flagged actions not reviewed yet
+    unreviewed_only: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct ReviewRiskFlagPayload {
+    auditor_id: u64,
+    password: String,
+    flag_id: u64,
+    confirmed: bool,
+    #[validate(custom = "validate_max_bytes::<500>")]
+    note: String,
+    idempotency_key: Option<String>,
+}
+
+fn risk_settings() -> RiskSettings {
+    RISK_SETTINGS_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .unwrap_or_default()
+}
+
+// record a violation and reject the action, or let it through flagged for review
+fn enforce(
+    rule: RiskRule,
+    action: RiskAction,
+    account: AccountRef,
+    detail: String,
+) -> Result<(), Error> {
+    let detail = truncate_bytes(detail, MAX_DETAIL_BYTES);
+    let id = next_id();
+    RISK_FLAG_STORAGE.with(|s| {
+        s.borrow_mut().insert(
+            id,
+            RiskFlag {
+                id,
+                rule,
+                action,
+                account,
+                detail: detail.clone(),
+                created_at: ic_cdk::api::time(),
+                review: None,
+            },
+        )
+    });
+    match action {
+        RiskAction::Reject => Err(Error::Unauthorized {
+            msg: format!("Rejected by risk rules: {}", detail),
+        }),
+        RiskAction::Flag => Ok(()),
+    }
+}
+
+// count the account activity in the velocity window, the action itself is recorded once it succeeds
+fn check_velocity(
+    account: AccountRef,
+    rule: RiskRule,
+    limit: Option<RiskLimit>,
+    window_seconds: u64,
+) -> Result<(), Error> {
+    let Some(limit) = limit else {
+        return Ok(());
+    };
+    let since = velocity_window_start(window_seconds);
+    let count: u64 = ACTIVITY_STORAGE.with(|s| {
+        s.borrow()
+            .range((account, since)..=(account, u64::MAX))
+            .map(|(_, count)| count)
+            .sum()
+    });
+    if count >= limit.limit {
+        enforce(
+            rule,
+            limit.action,
+            account,
+            format!(
+                "{} actions in the last {} seconds, limit is {}",
+                count + 1,
+                window_seconds,
+                limit.limit
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+fn velocity_window_start(window_seconds: u64) -> u64 {
+    ic_cdk::api::time().saturating_sub(window_seconds.saturating_mul(NANOS_PER_SECOND))
+}
+
+// record a bid or award that went through, dropping the account activity older than the window
+pub(crate) fn record_activity(account: AccountRef) {
+    let now = ic_cdk::api::time();
+    let since = velocity_window_start(risk_settings().velocity_window_seconds);
+    ACTIVITY_STORAGE.with(|s| {
+        let mut storage = s.borrow_mut();
+        let expired: Vec<(AccountRef, u64)> = storage
+            .range((account, 0)..(account, since))
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            storage.remove(&key);
+        }
+        let count = storage.get(&(account, now)).unwrap_or(0);
+        storage.insert((account, now), count + 1);
+    });
+}
+
+fn link_key(first: AccountRef, second: AccountRef) -> (AccountRef, AccountRef) {
+    (first.min(second), first.max(second))
+}
+
+// accounts linked by an admin, or a client and producer sharing an email, phone or legal entity id
+pub(crate) fn accounts_linked(client_id: u64, producer_id: u64) -> bool {
+    let key = link_key(
+        AccountRef::Client { id: client_id },
+        AccountRef::Producer { id: producer_id },
+    );
+    if ACCOUNT_LINK_STORAGE.with(|s| s.borrow().contains_key(&key)) {
+        return true;
+    }
+    let client = CLIENT_STORAGE.with(|s| s.borrow().get(&client_id));
+    let producer = PRODUCER_STORAGE.with(|s| s.borrow().get(&producer_id));
+    match (client, producer) {
+        (Some(client), Some(producer)) => {
+            client.email.eq_ignore_ascii_case(&producer.email)
+                || client.phone == producer.phone
+                || client
+                    .legal_entity_id
+                    .eq_ignore_ascii_case(&producer.legal_entity_id)
+        }
+        _ => false,
+    }
+}
+
+// check a bid against the outstanding bid, wash trade and velocity rules
+pub(crate) fn check_bid_risk(client_id: u64, producer_id: u64) -> Result<(), Error> {
+    let settings = risk_settings();
+    let account = AccountRef::Client { id: client_id };
+    if let Some(limit) = settings.max_outstanding_bids {
+        let outstanding = CREDIT_ORDER_STORAGE.with(|s| {
+            s.borrow()
+                .iter()
+                .filter(|(_, credit_order)| {
+                    credit_order.client_id == Some(client_id)
+                        && credit_order.status == OrderStatus::Open
+                })
+                .count() as u64
+        });
+        if outstanding >= limit.limit {
+            enforce(
+                RiskRule::OutstandingBids,
+                limit.action,
+                account,
+                format!(
+                    "Client already has {} outstanding bids, limit is {}",
+                    outstanding, limit.limit
+                ),
+            )?;
+        }
+    }
+    if let Some(action) = settings.wash_trade {
+        if accounts_linked(client_id, producer_id) {
+            enforce(
+                RiskRule::WashTrade,
+                action,
+                account,
+                format!(
+                    "Client id: {} bid on an order of linked producer id: {}",
+                    client_id, producer_id
+                ),
+            )?;
+        }
+    }
+    check_velocity(
+        account,
+        RiskRule::BidVelocity,
+        settings.max_bids_per_window,
+        settings.velocity_window_seconds,
+    )
+}
+
+// check an energy award against the award velocity rule
+pub(crate) fn check_award_risk(producer_id: u64) -> Result<(), Error> {
+    let settings = risk_settings();
+    check_velocity(
+        AccountRef::Producer { id: producer_id },
+        RiskRule::AwardVelocity,
+        settings.max_awards_per_window,
+        settings.velocity_window_seconds,
+    )
+}
+
+// check a facility award against the capacity factor rule
+pub(crate) fn check_capacity_factor(
+    facility: &Facility,
+    energy_supply: u64,
+    period_start: u64,
+    period_end: u64,
+) -> Result<(), Error> {
+    let Some(limit) = risk_settings().max_capacity_factor_bps else {
+        return Ok(());
+    };
+    let max_energy = max_generation(facility, period_start, period_end);
+    let capacity_factor = energy_supply as f64 / max_energy * 10_000.0;
+    // a capacity factor that is not a number is flagged too
+    if !matches!(
+        capacity_factor.partial_cmp(&(limit.limit as f64)),
+        Some(Ordering::Less | Ordering::Equal)
+    ) {
+        return enforce(
+            RiskRule::CapacityFactor,
+            limit.action,
+            AccountRef::Producer {
+                id: facility.producer_id,
+            },
+            format!(
+                "Facility id: {} award has a capacity factor of {:.0} bps, limit is {}",
+                facility.id, capacity_factor, limit.limit
+            ),
+        );
+    }
+    Ok(())
+}
+
+fn authorize_auditor(auditor_id: u64, password: &str) -> Result<(), Error> {
+    match AUDITOR_STORAGE.with(|s| s.borrow().get(&auditor_id)) {
+        Some(auditor) if auditor.password == password => Ok(()),
+        Some(_) => Err(Error::Unauthorized {
+            msg: "Unauthorized, method only available to auditors".to_string(),
+        }),
+        None => Err(Error::NotFound {
+            msg: "Auditor not found".to_string(),
+        }),
+    }
+}
+
+// configure the risk rules
+#[ic_cdk::update]
+fn set_risk_settings(payload: RiskSettingsPayload) -> Result<RiskSettings, Error> {
+    idempotent(
+        "set_risk_settings",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_admin(&payload.contract_password)?;
+            let uses_window =
+                payload.max_bids_per_window.is_some() || payload.max_awards_per_window.is_some();
+            if uses_window && payload.velocity_window_seconds == 0 {
+                return Err(Error::InvalidPayload {
+                    msg: "Velocity limits need a velocity window".to_string(),
+                });
+            }
+            let settings = RiskSettings {
+                max_capacity_factor_bps: payload.max_capacity_factor_bps,
+                max_outstanding_bids: payload.max_outstanding_bids,
+                wash_trade: payload.wash_trade,
+                max_bids_per_window: payload.max_bids_per_window,
+                max_awards_per_window: payload.max_awards_per_window,
+                velocity_window_seconds: payload.velocity_window_seconds,
+                updated_at: ic_cdk::api::time(),
+            };
+            RISK_SETTINGS_STORAGE.with(|s| s.borrow_mut().insert(0, settings.clone()));
+            Ok(settings)
+        },
+    )
+}
+
+// get the risk rules
+#[ic_cdk::query]
+fn get_risk_settings() -> RiskSettings {
+    risk_settings()
+}
+
+// link or unlink two accounts known to be under common control
+#[ic_cdk::update]
+fn set_account_link(payload: AccountLinkPayload) -> Result<String, Error> {
+    idempotent(
+        "set_account_link",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_admin(&payload.contract_password)?;
+            if payload.first == payload.second {
+                return Err(Error::InvalidPayload {
+                    msg: "Cannot link an account to itself".to_string(),
+                });
+            }
+            account_credits(payload.first)?;
+            account_credits(payload.second)?;
+            let key = link_key(payload.first, payload.second);
+            ACCOUNT_LINK_STORAGE.with(|s| {
+                if payload.linked {
+                    s.borrow_mut().insert(key, ());
+                } else {
+                    s.borrow_mut().remove(&key);
+                }
+            });
+            Ok("Account link updated successfully".to_string())
+        },
+    )
+}
+
+// add an auditor allowed to review risk flags
+#[ic_cdk::update]
+fn add_auditor(payload: AuditorPayload) -> Result<AuditorReturn, Error> {
+    idempotent(
+        "add_auditor",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            authorize_admin(&payload.contract_password)?;
+
+            let id = next_id();
+            let auditor = Auditor {
+                id,
+                name: payload.name,
+                password: payload.password,
+            };
+            AUDITOR_STORAGE.with(|s| s.borrow_mut().insert(id, auditor.clone()));
+            Ok(AuditorReturn {
+                id,
+                name: auditor.name,
+            })
+        },
+    )
+}
+
+// get the risk flags for auditors
+#[ic_cdk::query]
+fn get_risk_flags(payload: RiskFlagsPayload) -> Result<Vec<RiskFlag>, Error> {
+    authorize_auditor(payload.auditor_id, &payload.password)?;
+    Ok(RISK_FLAG_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, flag)| flag)
+            .filter(|flag| {
+                !payload.unreviewed_only
+                    || (flag.action == RiskAction::Flag && flag.review.is_none())
+            })
+            .collect()
+    }))
+}
+
+// record an auditor review of a risk flag
+#[ic_cdk::update]
+fn review_risk_flag(payload: ReviewRiskFlagPayload) -> Result<RiskFlag, Error> {
+    idempotent(
+        "review_risk_flag",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            authorize_auditor(payload.auditor_id, &payload.password)?;
+            let flag = match RISK_FLAG_STORAGE.with(|s| s.borrow().get(&payload.flag_id)) {
+                Some(flag) => flag,
+                None => {
+                    return Err(Error::NotFound {
+                        msg: format!("risk flag with id: {} not found", payload.flag_id),
+                    })
+                }
+            };
+            if flag.review.is_some() {
+                return Err(Error::InvalidPayload {
+                    msg: "Risk flag has already been reviewed".to_string(),
+                });
+            }
+            let flag = RiskFlag {
+                review: Some(RiskReview {
+                    auditor_id: payload.auditor_id,
+                    confirmed: payload.confirmed,
+                    note: payload.note,
+                    reviewed_at: ic_cdk::api::time(),
+                }),
+                ..flag
+            };
+            RISK_FLAG_STORAGE.with(|s| s.borrow_mut().insert(flag.id, flag.clone()));
+            Ok(flag)
+        },
+    )
+}
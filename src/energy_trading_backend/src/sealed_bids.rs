@@ -0,0 +1,480 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    authorize_client, cancel_credit_order, certify_order, check_bid_risk, ensure_can_trade,
+    idempotent, notify, record_activity, AccountRef, CreditOrder, Error, Memory, OrderStatus,
+    OrderType, CREDIT_ORDER_STORAGE, MEMORY_MANAGER,
+};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// what the winner of a sealed-bid auction pays
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum SealedBidPricing {
+    // the winning offer
+    #[default]
+    FirstPrice,
+    // the second highest revealed offer, or the reserve when there is none
+    SecondPrice,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum SealedAuctionPhase {
+    #[default]
+    Commit,
+    Reveal,
+    Closed,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum SealedBidStatus {
+    #[default]
+    Committed,
+    Revealed,
+    // not revealed before the reveal phase ended
+    Forfeited,
+    Won,
+    Lost,
+}
+
+// the phases of a sealed-bid credit order, advanced by timers
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct SealedAuction {
+    order_id: u64,
+    phase: SealedAuctionPhase,
+    commit_ends_at: u64,
+    reveal_ends_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct SealedBid {
+    order_id: u64,
+    client_id: u64,
+    // hex encoded SHA-256 of "{offer_per_credit}:{salt}"
+    commitment: String,
+    // hidden until the auction is closed
+    offer_per_credit: Option<u64>,
+    status: SealedBidStatus,
+    committed_at: u64,
+}
+
+impl Storable for SealedAuction {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for SealedBid {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SealedAuction {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for SealedBid {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static SEALED_AUCTION_STORAGE: RefCell<StableBTreeMap<u64, SealedAuction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    // (order id, client id) -> sealed bid
+    static SEALED_BID_STORAGE: RefCell<StableBTreeMap<(u64, u64), SealedBid, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct CommitBidPayload {
+    client_id: u64,
+    password: String,
+    credit_order_id: u64,
+    commitment: String,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RevealBidPayload {
+    client_id: u64,
+    password: String,
+    credit_order_id: u64,
+    offer_per_credit: u64,
+    salt: String,
+    idempotency_key: Option<String>,
+}
+
+fn bid_commitment(offer_per_credit: u64, salt: &str) -> String {
+    hex::encode(Sha256::digest(format!("{}:{}", offer_per_credit, salt)))
+}
+
+fn get_sealed_auction(order_id: u64) -> Result<SealedAuction, Error> {
+    SEALED_AUCTION_STORAGE
+        .with(|s| s.borrow().get(&order_id))
+        .ok_or(Error::InvalidPayload {
+            msg: "Credit order is not a sealed-bid auction".to_string(),
+        })
+}
+
+fn order_bids(order_id: u64) -> Vec<SealedBid> {
+    SEALED_BID_STORAGE.with(|s| {
+        s.borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .map(|(_, sealed_bid)| sealed_bid)
+            .collect()
+    })
+}
+
+fn schedule_phase_end(order_id: u64, ends_at: u64) {
+    let delay = Duration::from_nanos(ends_at.saturating_sub(ic_cdk::api::time()));
+    ic_cdk_timers::set_timer(delay, move || advance_phase(order_id));
+}
+
+// open the commit phase of a sealed-bid credit order
+pub(crate) fn open_sealed_auction(credit_order: &CreditOrder) {
+    let OrderType::SealedBid {
+        commit_seconds,
+        reveal_seconds,
+        ..
+    } = credit_order.order_type
+    else {
+        return;
+    };
+    let commit_ends_at = credit_order
+        .created_at
+        .saturating_add(commit_seconds.saturating_mul(NANOS_PER_SECOND));
+    let auction = SealedAuction {
+        order_id: credit_order.id,
+        phase: SealedAuctionPhase::Commit,
+        commit_ends_at,
+        reveal_ends_at: commit_ends_at
+            .saturating_add(reveal_seconds.saturating_mul(NANOS_PER_SECOND)),
+    };
+    SEALED_AUCTION_STORAGE.with(|s| s.borrow_mut().insert(credit_order.id, auction));
+    schedule_phase_end(credit_order.id, commit_ends_at);
+}
+
+// move an auction from the commit to the reveal phase, or close it after the reveal phase
+fn advance_phase(order_id: u64) {
+    let Ok(auction) = get_sealed_auction(order_id) else {
+        return;
+    };
+    match auction.phase {
+        SealedAuctionPhase::Commit => {
+            SEALED_AUCTION_STORAGE.with(|s| {
+                s.borrow_mut().insert(
+                    order_id,
+                    SealedAuction {
+                        phase: SealedAuctionPhase::Reveal,
+                        ..auction.clone()
+                    },
+                )
+            });
+            schedule_phase_end(order_id, auction.reveal_ends_at);
+        }
+        SealedAuctionPhase::Reveal => {
+            SEALED_AUCTION_STORAGE.with(|s| {
+                s.borrow_mut().insert(
+                    order_id,
+                    SealedAuction {
+                        phase: SealedAuctionPhase::Closed,
+                        ..auction
+                    },
+                )
+            });
+            close_sealed_auction(order_id);
+        }
+        SealedAuctionPhase::Closed => {}
+    }
+}
+
+// forfeit unrevealed bids and award the order to the highest revealed offer meeting the reserve
+fn close_sealed_auction(order_id: u64) {
+    let Some(credit_order) = CREDIT_ORDER_STORAGE.with(|s| s.borrow().get(&order_id)) else {
+        return;
+    };
+    let OrderType::SealedBid { pricing, .. } = credit_order.order_type else {
+        return;
+    };
+    let mut bids = order_bids(order_id);
+    // highest offer first, earliest commitment first on a tie
+    bids.sort_by_key(|sealed_bid| {
+        (
+            std::cmp::Reverse(sealed_bid.offer_per_credit.unwrap_or(0)),
+            sealed_bid.committed_at,
+        )
+    });
+    let mut eligible = bids.iter().filter(|sealed_bid| {
+        sealed_bid.status == SealedBidStatus::Revealed
+            && sealed_bid.offer_per_credit >= Some(credit_order.min_offer_per_credit)
+    });
+    let winner = eligible.next().map(|sealed_bid| sealed_bid.client_id);
+    let runner_up = eligible
+        .next()
+        .and_then(|sealed_bid| sealed_bid.offer_per_credit);
+
+    for sealed_bid in &bids {
+        let status = match sealed_bid.status {
+            SealedBidStatus::Committed => SealedBidStatus::Forfeited,
+            _ if Some(sealed_bid.client_id) == winner => SealedBidStatus::Won,
+            _ => SealedBidStatus::Lost,
+        };
+        SEALED_BID_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                (order_id, sealed_bid.client_id),
+                SealedBid {
+                    status,
+                    ..sealed_bid.clone()
+                },
+            )
+        });
+    }
+
+    // orders cancelled while the auction ran keep their bids closed without a winner
+    if !matches!(credit_order.status, OrderStatus::Open | OrderStatus::Frozen) {
+        return;
+    }
+    for sealed_bid in bids
+        .iter()
+        .filter(|sealed_bid| Some(sealed_bid.client_id) != winner)
+    {
+        let message = match sealed_bid.status {
+            SealedBidStatus::Committed => "bid forfeited, it was not revealed",
+            _ => "bid lost",
+        };
+        notify(
+            AccountRef::Client {
+                id: sealed_bid.client_id,
+            },
+            "auction_result",
+            order_id,
+            format!(
+                "Sealed-bid auction of credit order {} closed, {}",
+                order_id, message
+            ),
+        );
+    }
+    match winner {
+        Some(client_id) => {
+            let winning_offer = bids
+                .iter()
+                .find(|sealed_bid| sealed_bid.client_id == client_id)
+                .and_then(|sealed_bid| sealed_bid.offer_per_credit)
+                .unwrap_or(credit_order.min_offer_per_credit);
+            let price = match pricing {
+                SealedBidPricing::FirstPrice => winning_offer,
+                SealedBidPricing::SecondPrice => {
+                    runner_up.unwrap_or(credit_order.min_offer_per_credit)
+                }
+            };
+            // the producer settles with the winner through mark_order_paid
+            CREDIT_ORDER_STORAGE.with(|s| {
+                s.borrow_mut().insert(
+                    order_id,
+                    CreditOrder {
+                        client_id: Some(client_id),
+                        high_bid: Some(price),
+                        ..credit_order
+                    },
+                )
+            });
+            certify_order(order_id);
+            notify(
+                AccountRef::Client { id: client_id },
+                "payment_request",
+                order_id,
+                format!(
+                    "Won the sealed-bid auction of credit order {}, pay {} per credit to the \
+                     producer to receive the credits",
+                    order_id, price
+                ),
+            );
+            notify(
+                AccountRef::Producer {
+                    id: credit_order.producer_id,
+                },
+                "auction_result",
+                order_id,
+                format!(
+                    "Sealed-bid auction of credit order {} won by client {} at {} per credit, \
+                     mark the order paid once payment is received",
+                    order_id, client_id, price
+                ),
+            );
+        }
+        // nobody met the reserve, release the escrow back to the producer
+        None if credit_order.status == OrderStatus::Open => {
+            notify(
+                AccountRef::Producer {
+                    id: credit_order.producer_id,
+                },
+                "auction_result",
+                order_id,
+                format!(
+                    "Sealed-bid auction of credit order {} closed without a bid meeting the \
+                     reserve, the escrow was refunded",
+                    order_id
+                ),
+            );
+            let _ = cancel_credit_order(credit_order);
+        }
+        None => {}
+    }
+}
+
+// re-arm the phase timers of sealed-bid auctions that are not closed yet
+pub(crate) fn restore_sealed_auction_timers() {
+    SEALED_AUCTION_STORAGE.with(|s| {
+        for (order_id, auction) in s.borrow().iter() {
+            match auction.phase {
+                SealedAuctionPhase::Commit => schedule_phase_end(order_id, auction.commit_ends_at),
+                SealedAuctionPhase::Reveal => schedule_phase_end(order_id, auction.reveal_ends_at),
+                SealedAuctionPhase::Closed => {}
+            }
+        }
+    });
+}
+
+// submit or replace the commitment to a sealed bid during the commit phase
+#[ic_cdk::update]
+fn commit_sealed_bid(payload: CommitBidPayload) -> Result<SealedBid, Error> {
+    idempotent(
+        "commit_sealed_bid",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            let auction = get_sealed_auction(payload.credit_order_id)?;
+            if auction.phase != SealedAuctionPhase::Commit {
+                return Err(Error::InvalidPayload {
+                    msg: "Sealed-bid auction is not in the commit phase".to_string(),
+                });
+            }
+            let credit_order = CREDIT_ORDER_STORAGE
+                .with(|s| s.borrow().get(&payload.credit_order_id))
+                .ok_or(Error::NotFound {
+                    msg: "Credit order not found".to_string(),
+                })?;
+            if credit_order.status != OrderStatus::Open {
+                return Err(Error::InvalidPayload {
+                    msg: "Credit order is not open for bids".to_string(),
+                });
+            }
+            authorize_client(payload.client_id, &payload.password)?;
+            ensure_can_trade(AccountRef::Client {
+                id: payload.client_id,
+            })?;
+            let commitment = payload.commitment.to_ascii_lowercase();
+            if commitment.len() != 64 || !commitment.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(Error::InvalidPayload {
+                    msg: "Commitment must be a hex encoded SHA-256 hash".to_string(),
+                });
+            }
+            check_bid_risk(payload.client_id, credit_order.producer_id)?;
+
+            let sealed_bid = SealedBid {
+                order_id: payload.credit_order_id,
+                client_id: payload.client_id,
+                commitment,
+                offer_per_credit: None,
+                status: SealedBidStatus::Committed,
+                committed_at: ic_cdk::api::time(),
+            };
+            SEALED_BID_STORAGE.with(|s| {
+                s.borrow_mut().insert(
+                    (payload.credit_order_id, payload.client_id),
+                    sealed_bid.clone(),
+                )
+            });
+            record_activity(AccountRef::Client {
+                id: payload.client_id,
+            });
+            Ok(sealed_bid)
+        },
+    )
+}
+
+// disclose the offer and salt of a committed bid during the reveal phase
+#[ic_cdk::update]
+fn reveal_sealed_bid(payload: RevealBidPayload) -> Result<SealedBid, Error> {
+    idempotent(
+        "reveal_sealed_bid",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            let auction = get_sealed_auction(payload.credit_order_id)?;
+            if auction.phase != SealedAuctionPhase::Reveal {
+                return Err(Error::InvalidPayload {
+                    msg: "Sealed-bid auction is not in the reveal phase".to_string(),
+                });
+            }
+            authorize_client(payload.client_id, &payload.password)?;
+            let key = (payload.credit_order_id, payload.client_id);
+            let sealed_bid =
+                SEALED_BID_STORAGE
+                    .with(|s| s.borrow().get(&key))
+                    .ok_or(Error::NotFound {
+                        msg: "Client has not committed a bid for credit order".to_string(),
+                    })?;
+            if sealed_bid.status != SealedBidStatus::Committed {
+                return Err(Error::InvalidPayload {
+                    msg: "Sealed bid has already been revealed".to_string(),
+                });
+            }
+            if bid_commitment(payload.offer_per_credit, &payload.salt) != sealed_bid.commitment {
+                return Err(Error::Unauthorized {
+                    msg: "Offer and salt do not match the commitment".to_string(),
+                });
+            }
+            let sealed_bid = SealedBid {
+                offer_per_credit: Some(payload.offer_per_credit),
+                status: SealedBidStatus::Revealed,
+                ..sealed_bid
+            };
+            SEALED_BID_STORAGE.with(|s| s.borrow_mut().insert(key, sealed_bid.clone()));
+            Ok(sealed_bid)
+        },
+    )
+}
+
+// get the phase of a sealed-bid auction
+#[ic_cdk::query]
+fn get_sealed_auction_phase(order_id: u64) -> Result<SealedAuction, Error> {
+    get_sealed_auction(order_id)
+}
+
+// get the bids of a sealed-bid auction, offers are disclosed once the auction is closed
+#[ic_cdk::query]
+fn get_sealed_bids(order_id: u64) -> Result<Vec<SealedBid>, Error> {
+    let auction = get_sealed_auction(order_id)?;
+    let closed = auction.phase == SealedAuctionPhase::Closed;
+    Ok(order_bids(order_id)
+        .into_iter()
+        .map(|sealed_bid| SealedBid {
+            offer_per_credit: sealed_bid.offer_per_credit.filter(|_| closed),
+            ..sealed_bid
+        })
+        .collect())
+}
@@ -0,0 +1,323 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    Error, Memory, OrderStatus, Trade, CREDIT_ORDER_STORAGE, MEMORY_MANAGER, TRADE_STORAGE,
+};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// upper bound on the number of candles a single query may return
+const MAX_CANDLES: u64 = 1_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum CandleInterval {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl CandleInterval {
+    fn nanos(&self) -> u64 {
+        match self {
+            CandleInterval::Hour => 60 * 60 * NANOS_PER_SECOND,
+            CandleInterval::Day => 24 * 60 * 60 * NANOS_PER_SECOND,
+        }
+    }
+}
+
+// open, high, low and close price of the trades settled in one interval
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Candle {
+    start: u64,
+    open: u64,
+    high: u64,
+    low: u64,
+    close: u64,
+    volume: u64,
+    trades: u64,
+}
+
+// running totals updated on each mint, settlement and retirement
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct MarketTotals {
+    credits_minted: u64,
+    credits_retired: u64,
+    traded_volume: u64,
+    traded_value: u128,
+    trades: u64,
+    last_price: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct MarketStats {
+    credits_minted: u64,
+    credits_outstanding: u64,
+    credits_retired: u64,
+    traded_volume: u64,
+    traded_value: u128,
+    trades: u64,
+    last_price: Option<u64>,
+    active_orders: u64,
+}
+
+// price discovery over the trades of a window
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct PriceStats {
+    window_seconds: u64,
+    trades: u64,
+    volume: u64,
+    last_price: Option<u64>,
+    high: Option<u64>,
+    low: Option<u64>,
+    // volume weighted average price, rounded down
+    vwap: Option<u64>,
+}
+
+impl Storable for Candle {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for MarketTotals {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Candle {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for MarketTotals {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static MARKET_TOTALS_STORAGE: RefCell<StableBTreeMap<u64, MarketTotals, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    // (interval length in nanoseconds, interval start) -> candle
+    static CANDLE_STORAGE: RefCell<StableBTreeMap<(u64, u64), Candle, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct CandlePayload {
+    interval: CandleInterval,
+    // start and end in nanoseconds since the epoch
+    start: u64,
+    end: u64,
+}
+
+fn update_totals(update: impl FnOnce(&mut MarketTotals)) {
+    MARKET_TOTALS_STORAGE.with(|s| {
+        let mut totals = s.borrow().get(&0).unwrap_or_default();
+        update(&mut totals);
+        s.borrow_mut().insert(0, totals)
+    });
+}
+
+pub(crate) fn record_mint_stats(credits: u64) {
+    update_totals(|totals| totals.credits_minted += credits);
+}
+
+pub(crate) fn record_retirement_stats(credits: u64) {
+    update_totals(|totals| totals.credits_retired += credits);
+}
+
+// fold a settled trade into the totals and the hourly and daily candles
+pub(crate) fn record_trade_stats(trade: &Trade) {
+    update_totals(|totals| {
+        totals.traded_volume += trade.credits;
+        totals.traded_value += trade.credits as u128 * trade.price_per_credit as u128;
+        totals.trades += 1;
+        totals.last_price = Some(trade.price_per_credit);
+    });
+
+    for interval in [CandleInterval::Hour, CandleInterval::Day] {
+        let length = interval.nanos();
+        let key = (length, trade.settled_at - trade.settled_at % length);
+        CANDLE_STORAGE.with(|s| {
+            let candle = match s.borrow().get(&key) {
+                Some(candle) => Candle {
+                    high: candle.high.max(trade.price_per_credit),
+                    low: candle.low.min(trade.price_per_credit),
+                    close: trade.price_per_credit,
+                    volume: candle.volume + trade.credits,
+                    trades: candle.trades + 1,
+                    ..candle
+                },
+                None => Candle {
+                    start: key.1,
+                    open: trade.price_per_credit,
+                    high: trade.price_per_credit,
+                    low: trade.price_per_credit,
+                    close: trade.price_per_credit,
+                    volume: trade.credits,
+                    trades: 1,
+                },
+            };
+            s.borrow_mut().insert(key, candle)
+        });
+    }
+}
+
+// take a reversed trade back out of the totals and candles, the prices it set are kept
+pub(crate) fn reverse_trade_stats(trade: &Trade) {
+    update_totals(|totals| {
+        totals.traded_volume = totals.traded_volume.saturating_sub(trade.credits);
+        totals.traded_value = totals
+            .traded_value
+            .saturating_sub(trade.credits as u128 * trade.price_per_credit as u128);
+        totals.trades = totals.trades.saturating_sub(1);
+    });
+
+    for interval in [CandleInterval::Hour, CandleInterval::Day] {
+        let length = interval.nanos();
+        let key = (length, trade.settled_at - trade.settled_at % length);
+        CANDLE_STORAGE.with(|s| {
+            let Some(candle) = s.borrow().get(&key) else {
+                return;
+            };
+            let candle = Candle {
+                volume: candle.volume.saturating_sub(trade.credits),
+                trades: candle.trades.saturating_sub(1),
+                ..candle
+            };
+            s.borrow_mut().insert(key, candle);
+        });
+    }
+}
+
+// get the market totals and the number of orders open for bids
+#[ic_cdk::query]
+pub(crate) fn get_market_stats() -> MarketStats {
+    let totals = MARKET_TOTALS_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .unwrap_or_default();
+    let active_orders = CREDIT_ORDER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, credit_order)| credit_order.status == OrderStatus::Open)
+            .count() as u64
+    });
+    MarketStats {
+        credits_minted: totals.credits_minted,
+        // credits minted before the totals were kept can be retired too
+        credits_outstanding: totals.credits_minted.saturating_sub(totals.credits_retired),
+        credits_retired: totals.credits_retired,
+        traded_volume: totals.traded_volume,
+        traded_value: totals.traded_value,
+        trades: totals.trades,
+        last_price: totals.last_price,
+        active_orders,
+    }
+}
+
+// get last, high, low and volume weighted price of the trades in the last window_seconds,
+// a window of zero covers all trades
+#[ic_cdk::query]
+pub(crate) fn get_price_stats(window_seconds: u64) -> PriceStats {
+    let since = match window_seconds {
+        0 => 0,
+        _ => ic_cdk::api::time().saturating_sub(window_seconds.saturating_mul(NANOS_PER_SECOND)),
+    };
+    let mut stats = PriceStats {
+        window_seconds,
+        ..Default::default()
+    };
+    let mut value: u128 = 0;
+    TRADE_STORAGE.with(|s| {
+        for (_, trade) in s.borrow().iter() {
+            if trade.settled_at < since || trade.reversed_at.is_some() {
+                continue;
+            }
+            let price = trade.price_per_credit;
+            stats.trades += 1;
+            stats.volume += trade.credits;
+            stats.last_price = Some(price);
+            stats.high = Some(stats.high.map_or(price, |high| high.max(price)));
+            stats.low = Some(stats.low.map_or(price, |low| low.min(price)));
+            value += trade.credits as u128 * price as u128;
+        }
+    });
+    if stats.volume > 0 {
+        stats.vwap = Some((value / stats.volume as u128) as u64);
+    }
+    stats
+}
+
+// get the candles of an interval between start and end for charting
+#[ic_cdk::query]
+fn get_candles(payload: CandlePayload) -> Result<Vec<Candle>, Error> {
+    let length = payload.interval.nanos();
+    if payload.end <= payload.start {
+        return Err(Error::InvalidPayload {
+            msg: "Candle end must be after start".to_string(),
+        });
+    }
+    if (payload.end - payload.start) / length > MAX_CANDLES {
+        return Err(Error::InvalidPayload {
+            msg: format!("Cannot return more than {} candles", MAX_CANDLES),
+        });
+    }
+    let first = payload.start - payload.start % length;
+    Ok(CANDLE_STORAGE.with(|s| {
+        s.borrow()
+            .range((length, first)..(length, payload.end))
+            .map(|(_, candle)| candle)
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reversed_trade_leaves_the_volume() {
+        let trade = |id, credits| Trade {
+            id,
+            credits,
+            price_per_credit: 10,
+            settled_at: 3 * CandleInterval::Hour.nanos() + 5,
+            ..Default::default()
+        };
+        record_trade_stats(&trade(1, 100));
+        record_trade_stats(&trade(2, 40));
+        reverse_trade_stats(&trade(2, 40));
+
+        let totals = MARKET_TOTALS_STORAGE.with(|s| s.borrow().get(&0)).unwrap();
+        assert_eq!(totals.traded_volume, 100);
+        assert_eq!(totals.traded_value, 1_000);
+        assert_eq!(totals.trades, 1);
+        for interval in [CandleInterval::Hour, CandleInterval::Day] {
+            let length = interval.nanos();
+            let start = trade(1, 0).settled_at / length * length;
+            let candle = CANDLE_STORAGE
+                .with(|s| s.borrow().get(&(length, start)))
+                .unwrap();
+            assert_eq!(candle.volume, 100);
+            assert_eq!(candle.trades, 1);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::{
+    emit_event, issue_receipt, next_id, notify, record_trade_stats, reverse_trade_stats,
+    AccountRef, CreditOrder, EventKind, Memory, ReceiptKind, MEMORY_MANAGER,
+};
+
+// credits delivered to a client at the settled price of a credit order
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Trade {
+    pub(crate) id: u64,
+    pub(crate) order_id: u64,
+    pub(crate) producer_id: u64,
+    pub(crate) client_id: u64,
+    pub(crate) credits: u64,
+    pub(crate) price_per_credit: u64,
+    // source of the credits declared on the order
+    pub(crate) facility_id: Option<u64>,
+    pub(crate) vintage: Option<u32>,
+    pub(crate) settled_at: u64,
+    // signed receipt of the settlement
+    pub(crate) receipt_id: u64,
+    // credits the taker fee kept from the delivery, None until trades recorded before it was
+    // kept are backfilled
+    pub(crate) client_fee: Option<u64>,
+    // when a dispute ruling returned the settled credits to the producer
+    pub(crate) reversed_at: Option<u64>,
+}
+
+impl Trade {
+    // the credits that reached the client balance
+    pub(crate) fn received(&self) -> u64 {
+        self.credits.saturating_sub(self.client_fee.unwrap_or(0))
+    }
+}
+
+impl Storable for Trade {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Trade {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    pub(crate) static TRADE_STORAGE: RefCell<StableBTreeMap<u64, Trade, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+}
+
+// record the settlement of credits from a credit order
+pub(crate) fn record_trade(
+    credit_order: &CreditOrder,
+    client_id: u64,
+    credits: u64,
+    client_fee: u64,
+) -> Trade {
+    let id = next_id();
+    let receipt = issue_receipt(
+        ReceiptKind::Settlement {
+            order_id: credit_order.id,
+            trade_id: id,
+        },
+        AccountRef::Client { id: client_id },
+        credits,
+        Some(credit_order.price_per_credit()),
+    );
+    let trade = Trade {
+        id,
+        order_id: credit_order.id,
+        producer_id: credit_order.producer_id,
+        client_id,
+        credits,
+        price_per_credit: credit_order.price_per_credit(),
+        facility_id: credit_order.facility_id,
+        vintage: credit_order.vintage,
+        settled_at: ic_cdk::api::time(),
+        receipt_id: receipt.id,
+        client_fee: Some(client_fee),
+        reversed_at: None,
+    };
+    TRADE_STORAGE.with(|s| s.borrow_mut().insert(id, trade.clone()));
+    record_trade_stats(&trade);
+    let message = format!(
+        "Credit order {} settled, {} credits at {} per credit",
+        trade.order_id, credits, trade.price_per_credit
+    );
+    notify(
+        AccountRef::Client { id: client_id },
+        "settlement",
+        trade.order_id,
+        message.clone(),
+    );
+    notify(
+        AccountRef::Producer {
+            id: trade.producer_id,
+        },
+        "settlement",
+        trade.order_id,
+        message,
+    );
+    emit_event(
+        EventKind::Settled,
+        Some(trade.order_id),
+        Some(AccountRef::Client { id: client_id }),
+        credits,
+        Some(trade.price_per_credit),
+    );
+    trade
+}
+
+// get all trades
+#[ic_cdk::query]
+fn get_trades() -> Vec<Trade> {
+    TRADE_STORAGE.with(|s| s.borrow().iter().map(|(_, trade)| trade).collect())
+}
+
+// mark the trades of a credit order reversed once its settlement is refunded to the producer,
+// taking them out of the traded volume
+pub(crate) fn reverse_trades(order_id: u64) {
+    let trades: Vec<Trade> = TRADE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, trade)| trade)
+            .filter(|trade| trade.order_id == order_id && trade.reversed_at.is_none())
+            .collect()
+    });
+    let now = ic_cdk::api::time();
+    for trade in trades {
+        reverse_trade_stats(&trade);
+        let trade = Trade {
+            reversed_at: Some(now),
+            ..trade
+        };
+        TRADE_STORAGE.with(|s| s.borrow_mut().insert(trade.id, trade));
+    }
+}
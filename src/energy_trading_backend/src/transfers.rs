@@ -0,0 +1,285 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+use validator::Validate;
+
+use crate::{
+    account_credits, authorize_admin, authorize_client, authorize_producer, collect_transfer_fee,
+    credit_account, debit_account, ensure_can_trade, ensure_transfer_fee_collectable, idempotent,
+    next_id, transfer_fee, validate_max_bytes, AccountRef, Error, Memory, MEMORY_MANAGER,
+};
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Transfer {
+    pub(crate) id: u64,
+    pub(crate) from: AccountRef,
+    pub(crate) to: AccountRef,
+    pub(crate) amount: u64,
+    // fee paid by the sender on top of the amount
+    pub(crate) fee: u64,
+    memo: Option<String>,
+    idempotency_key: Option<String>,
+    pub(crate) created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct TransferSettings {
+    // only allowlisted accounts can send or receive transfers
+    allowlist_only: bool,
+}
+
+impl Storable for Transfer {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for TransferSettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Transfer {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for TransferSettings {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    pub(crate) static TRANSFER_STORAGE: RefCell<StableBTreeMap<u64, Transfer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    // memory 13 held the transfer idempotency keys, transfers now use idempotent like other
+    // updates
+
+    static TRANSFER_SETTINGS_STORAGE: RefCell<StableBTreeMap<u64, TransferSettings, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    static TRANSFER_ALLOWLIST_STORAGE: RefCell<StableBTreeMap<AccountRef, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct TransferPayload {
+    from: AccountRef,
+    to: AccountRef,
+    // password of the sending client or producer
+    password: Option<String>,
+    amount: u64,
+    #[validate(length(max = 128))]
+    memo: Option<String>,
+    // checked in bytes like every idempotency key, so batched transfers fail their pre-check
+    #[validate(length(min = 1), custom = "validate_max_bytes::<64>")]
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct TransferSettingsPayload {
+    contract_password: String,
+    allowlist_only: bool,
+    idempotency_key: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct TransferAllowlistPayload {
+    contract_password: String,
+    account: AccountRef,
+    allowed: bool,
+    idempotency_key: Option<String>,
+}
+
+fn is_allowlisted(account: &AccountRef) -> bool {
+    TRANSFER_ALLOWLIST_STORAGE.with(|s| s.borrow().contains_key(account))
+}
+
+// validate and authenticate a transfer, also used to check batched transfers
+pub(crate) fn check_transfer(payload: &TransferPayload) -> Result<(), Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    if payload.amount == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "Transfer amount must be greater than zero".to_string(),
+        });
+    }
+    if payload.from == payload.to {
+        return Err(Error::InvalidPayload {
+            msg: "Cannot transfer credits to the same account".to_string(),
+        });
+    }
+
+    // the sender must prove ownership of the sending account
+    let password = payload.password.as_deref().unwrap_or_default();
+    match payload.from {
+        AccountRef::Client { id } => {
+            authorize_client(id, password)?;
+        }
+        AccountRef::Producer { id } => {
+            authorize_producer(id, password)?;
+        }
+    }
+
+    ensure_can_trade(payload.from)?;
+    ensure_can_trade(payload.to)?;
+    Ok(())
+}
+
+// transfer credits between any two accounts
+#[ic_cdk::update]
+pub(crate) fn transfer_credits(payload: TransferPayload) -> Result<Transfer, Error> {
+    idempotent(
+        "transfer_credits",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            check_transfer(&payload)?;
+
+            let settings = get_transfer_settings();
+            if settings.allowlist_only
+                && !(is_allowlisted(&payload.from) && is_allowlisted(&payload.to))
+            {
+                return Err(Error::Unauthorized {
+                    msg: "Both accounts must be allowlisted for transfers".to_string(),
+                });
+            }
+
+            // check both accounts before moving any credits
+            let balance = account_credits(payload.from)?;
+            account_credits(payload.to)?;
+            let fee = transfer_fee(payload.amount);
+            let total = payload
+                .amount
+                .checked_add(fee)
+                .ok_or(Error::InvalidPayload {
+                    msg: "Transfer amount overflow".to_string(),
+                })?;
+            if balance < total {
+                return Err(Error::InvalidPayload {
+                    msg: format!(
+                        "Insufficient credits, transfer requires {} including a fee of {}",
+                        total, fee
+                    ),
+                });
+            }
+
+            ensure_transfer_fee_collectable(fee)?;
+
+            debit_account(payload.from, total)?;
+            credit_account(payload.to, payload.amount)?;
+
+            let id = next_id();
+            collect_transfer_fee(id, fee)?;
+            let transfer = Transfer {
+                id,
+                from: payload.from,
+                to: payload.to,
+                amount: payload.amount,
+                fee,
+                memo: payload.memo,
+                idempotency_key: payload.idempotency_key,
+                created_at: ic_cdk::api::time(),
+            };
+            TRANSFER_STORAGE.with(|s| s.borrow_mut().insert(id, transfer.clone()));
+            Ok(transfer)
+        },
+    )
+}
+
+// get transfer by id
+#[ic_cdk::query]
+fn get_transfer(id: u64) -> Result<Transfer, Error> {
+    match TRANSFER_STORAGE.with(|s| s.borrow().get(&id)) {
+        Some(transfer) => Ok(transfer),
+        None => Err(Error::NotFound {
+            msg: format!("transfer with id: {} not found", id),
+        }),
+    }
+}
+
+// get the transfer history of an account
+#[ic_cdk::query]
+fn get_account_transfers(account: AccountRef) -> Vec<Transfer> {
+    TRANSFER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, transfer)| transfer)
+            .filter(|transfer| transfer.from == account || transfer.to == account)
+            .collect()
+    })
+}
+
+// get the transfer settings
+#[ic_cdk::query]
+fn get_transfer_settings() -> TransferSettings {
+    TRANSFER_SETTINGS_STORAGE
+        .with(|s| s.borrow().get(&0))
+        .unwrap_or_default()
+}
+
+// switch allowlist mode for regulated markets on or off
+#[ic_cdk::update]
+fn set_transfer_settings(payload: TransferSettingsPayload) -> Result<TransferSettings, Error> {
+    idempotent(
+        "set_transfer_settings",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_admin(&payload.contract_password)?;
+            let settings = TransferSettings {
+                allowlist_only: payload.allowlist_only,
+            };
+            TRANSFER_SETTINGS_STORAGE.with(|s| s.borrow_mut().insert(0, settings.clone()));
+            Ok(settings)
+        },
+    )
+}
+
+// add an account to or remove it from the transfer allowlist
+#[ic_cdk::update]
+fn update_transfer_allowlist(payload: TransferAllowlistPayload) -> Result<String, Error> {
+    idempotent(
+        "update_transfer_allowlist",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            authorize_admin(&payload.contract_password)?;
+            account_credits(payload.account)?;
+            TRANSFER_ALLOWLIST_STORAGE.with(|s| {
+                if payload.allowed {
+                    s.borrow_mut().insert(payload.account, ());
+                } else {
+                    s.borrow_mut().remove(&payload.account);
+                }
+            });
+            Ok("Transfer allowlist updated successfully".to_string())
+        },
+    )
+}
+
+// get all allowlisted accounts
+#[ic_cdk::query]
+fn get_transfer_allowlist() -> Vec<AccountRef> {
+    TRANSFER_ALLOWLIST_STORAGE.with(|s| s.borrow().iter().map(|(account, _)| account).collect())
+}
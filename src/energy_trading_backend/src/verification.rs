@@ -0,0 +1,236 @@
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+use validator::Validate;
+
+use crate::{
+    authorize_admin, certify_order, has_open_dispute, idempotent, next_id, AccountRef, Client,
+    CreditOrder, Error, Memory, OrderStatus, Producer, CLIENT_STORAGE, CREDIT_ORDER_STORAGE,
+    MEMORY_MANAGER, PRODUCER_STORAGE,
+};
+
+// KYC status of a client or producer, only verified accounts can trade
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum VerificationStatus {
+    #[default]
+    Unverified,
+    Pending,
+    Verified,
+    // open orders of a suspended account are frozen
+    Suspended,
+}
+
+// audit trail of verification status changes
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct VerificationRecord {
+    id: u64,
+    account: AccountRef,
+    status: VerificationStatus,
+    // reference to the evidence kept offline by the compliance team
+    evidence_reference: String,
+    updated_at: u64,
+}
+
+impl Storable for VerificationRecord {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for VerificationRecord {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static VERIFICATION_RECORD_STORAGE: RefCell<StableBTreeMap<u64, VerificationRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct VerificationPayload {
+    contract_password: String,
+    account: AccountRef,
+    status: VerificationStatus,
+    #[validate(length(min = 3, max = 256))]
+    evidence_reference: String,
+    idempotency_key: Option<String>,
+}
+
+// get the verification status of an account
+pub(crate) fn verification_status(account: AccountRef) -> Result<VerificationStatus, Error> {
+    match account {
+        AccountRef::Client { id } => match CLIENT_STORAGE.with(|s| s.borrow().get(&id)) {
+            Some(client) => Ok(client.verification),
+            None => Err(Error::NotFound {
+                msg: format!("client with id: {} not found", id),
+            }),
+        },
+        AccountRef::Producer { id } => match PRODUCER_STORAGE.with(|s| s.borrow().get(&id)) {
+            Some(producer) => Ok(producer.verification),
+            None => Err(Error::NotFound {
+                msg: format!("producer with id: {} not found", id),
+            }),
+        },
+    }
+}
+
+// refuse accounts that are not verified for trading
+pub(crate) fn ensure_verified(account: AccountRef) -> Result<(), Error> {
+    match verification_status(account)? {
+        VerificationStatus::Verified => Ok(()),
+        _ => Err(Error::Unauthorized {
+            msg: "Account is not verified for trading".to_string(),
+        }),
+    }
+}
+
+pub(crate) fn is_suspended(account: AccountRef) -> bool {
+    matches!(
+        verification_status(account),
+        Ok(VerificationStatus::Suspended)
+    )
+}
+
+pub(crate) fn order_involves(credit_order: &CreditOrder, account: AccountRef) -> bool {
+    match account {
+        AccountRef::Client { id } => credit_order.client_id == Some(id),
+        AccountRef::Producer { id } => credit_order.producer_id == id,
+    }
+}
+
+// freeze the open orders of a suspended account
+fn freeze_account_orders(account: AccountRef) {
+    CREDIT_ORDER_STORAGE.with(|s| {
+        let orders: Vec<CreditOrder> = s
+            .borrow()
+            .iter()
+            .map(|(_, credit_order)| credit_order)
+            .filter(|credit_order| {
+                credit_order.status == OrderStatus::Open && order_involves(credit_order, account)
+            })
+            .collect();
+        for credit_order in orders {
+            let order_id = credit_order.id;
+            s.borrow_mut().insert(
+                order_id,
+                CreditOrder {
+                    status: OrderStatus::Frozen,
+                    ..credit_order
+                },
+            );
+            certify_order(order_id);
+        }
+    });
+}
+
+// reopen orders frozen by a suspension once no party is suspended or disputing them
+fn release_account_orders(account: AccountRef) {
+    CREDIT_ORDER_STORAGE.with(|s| {
+        let orders: Vec<CreditOrder> = s
+            .borrow()
+            .iter()
+            .map(|(_, credit_order)| credit_order)
+            .filter(|credit_order| {
+                credit_order.status == OrderStatus::Frozen
+                    && !credit_order.paid
+                    && order_involves(credit_order, account)
+                    && !has_open_dispute(credit_order)
+                    && !is_suspended(AccountRef::Producer {
+                        id: credit_order.producer_id,
+                    })
+                    && !credit_order
+                        .client_id
+                        .is_some_and(|id| is_suspended(AccountRef::Client { id }))
+            })
+            .collect();
+        for credit_order in orders {
+            let order_id = credit_order.id;
+            s.borrow_mut().insert(
+                order_id,
+                CreditOrder {
+                    status: OrderStatus::Open,
+                    ..credit_order
+                },
+            );
+            certify_order(order_id);
+        }
+    });
+}
+
+// set the verification status of an account with a reference to the offline evidence
+#[ic_cdk::update]
+fn set_verification_status(payload: VerificationPayload) -> Result<VerificationRecord, Error> {
+    idempotent(
+        "set_verification_status",
+        payload.idempotency_key.clone(),
+        payload,
+        move |payload| {
+            if let Err(e) = payload.validate() {
+                return Err(Error::InvalidPayload { msg: e.to_string() });
+            }
+            authorize_admin(&payload.contract_password)?;
+            let previous = verification_status(payload.account)?;
+
+            match payload.account {
+                AccountRef::Client { id } => CLIENT_STORAGE.with(|s| {
+                    let client = s.borrow().get(&id).unwrap();
+                    s.borrow_mut().insert(
+                        id,
+                        Client {
+                            verification: payload.status,
+                            ..client
+                        },
+                    );
+                }),
+                AccountRef::Producer { id } => PRODUCER_STORAGE.with(|s| {
+                    let producer = s.borrow().get(&id).unwrap();
+                    s.borrow_mut().insert(
+                        id,
+                        Producer {
+                            verification: payload.status,
+                            ..producer
+                        },
+                    );
+                }),
+            }
+
+            if payload.status == VerificationStatus::Suspended {
+                freeze_account_orders(payload.account);
+            } else if previous == VerificationStatus::Suspended {
+                release_account_orders(payload.account);
+            }
+
+            let id = next_id();
+            let record = VerificationRecord {
+                id,
+                account: payload.account,
+                status: payload.status,
+                evidence_reference: payload.evidence_reference,
+                updated_at: ic_cdk::api::time(),
+            };
+            VERIFICATION_RECORD_STORAGE.with(|s| s.borrow_mut().insert(id, record.clone()));
+            Ok(record)
+        },
+    )
+}
+
+// get the verification history of an account
+#[ic_cdk::query]
+fn get_verification_history(account: AccountRef) -> Vec<VerificationRecord> {
+    VERIFICATION_RECORD_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, record)| record)
+            .filter(|record| record.account == account)
+            .collect()
+    })
+}